@@ -3,30 +3,73 @@ extern crate navigation;
 extern crate graceful;
 
 use super::video::*;
-use super::compass::*;
-use super::lidar::*;
-use super::gps::*;
 use super::motors::*;
-use super::Config;
-use super::switch::*;
+use super::gps::*;
+use super::hardware::*;
+use super::frame::Point;
+use super::perception::{ObstacleDetector, Obstacles, Sectors};
+use super::localization::Localization;
+use super::occupancy::{LocalMap, OccupancyGrid, Pose, OCCUPIED_PROBABILITY};
+use super::scan::Scan;
+use super::scan_matching::ScanOdometry;
+use super::tracking::{ObjectKind, Shape, TrackedObject, Tracker};
+use super::wmm;
 
 use chrono::UTC;
 use chrono::DateTime;
-use qik::*;
 use navigation::*;
 
 use std::sync::{Arc, Mutex};
 use std::thread;
 use std::time::Duration;
 
+/// the video overlay shows this many cells of the occupancy grid along each side
+const OVERLAY_MAP_SIZE: usize = 80;
+
+/// size in metres of each cell shown on the video overlay
+const OVERLAY_MAP_RESOLUTION: f64 = 0.1;
+
+/// a waypoint the vehicle has driven past only counts as reached if the vehicle is within
+/// this many times the waypoint radius of it
+const PASSED_WAYPOINT_MAX_DISTANCE: f64 = 5_f64;
+
+/// minimum ground speed (m/s) for the GPS course to be a useful cross-check on the compass
+const MIN_SPEED_FOR_GPS_COURSE: f32 = 1_f32;
+
 // NOTE: public fields are bad practice ... will fix later
 pub struct Settings {
     pub max_speed: i8,
     pub differential_drive_coefficient: f32,
-    pub waypoint_accuracy: (f64, f64), // lat, lon
+    /// distance in metres from a waypoint that counts as reaching it
+    pub waypoint_radius: f64,
     pub waypoints: Vec<Location>,
     pub obstacle_avoidance_distance: u32,
+    /// LIDAR sectors matching each of the ultrasonic sensors
+    pub sectors: Sectors,
+    /// ignore obstacles detected with less confidence than this (0 to 1)
+    pub min_obstacle_confidence: f32,
     pub usonic_sample_count: usize,
+    /// don't navigate unless the fix uses at least this many satellites
+    pub min_satellites: u8,
+    /// don't navigate if the horizontal dilution of precision is higher than this
+    pub max_hdop: f32,
+    /// discard the GPS fix if the receiver hasn't reported a position for this long
+    pub gps_timeout: Duration,
+    /// stop if the standard deviation of the estimated position grows beyond this many
+    /// metres, e.g. after dead reckoning without GPS for too long
+    pub max_position_sd: f64,
+    /// stop the vehicle if the LIDAR hasn't completed a scan for this long
+    pub lidar_timeout: Duration,
+    /// approximate wheel speed in m/s at full power, for estimating the speed without wheel
+    /// encoders
+    pub max_wheel_speed: f64,
+    /// drive at wheel velocities measured by the encoders rather than setting the motor power
+    pub encoders: bool,
+    /// record video of the run (requires a camera)
+    pub record_video: bool,
+    /// magnetic declination in degrees (positive east), or None to calculate it from the
+    /// first GPS fix using the World Magnetic Model
+    pub declination: Option<f32>,
 }
 
 /// the various actions the vehicle can be performing
@@ -37,6 +80,7 @@ pub enum Action {
     ReachedWaypoint { waypoint: usize },
     WaitingForGps,
     WaitingForCompass,
+    WaitingForLidar,
     AvoidingObstacleToLeft,
     AvoidingObstacleToRight,
     EmergencyStop,
@@ -47,38 +91,58 @@ pub enum Action {
 /// instrumentation data to display on the video stream
 #[derive(Clone,Debug)]
 pub struct State {
+    /// the latest GPS fix (lat, lon)
     loc: Option<(f64, f64)>,
+    /// filtered position in the local frame
+    position: Option<Point>,
+    /// standard deviation of the filtered position in metres
+    position_sd: Option<f64>,
+    /// how long ago the GPS fix was received
+    gps_age: Option<Duration>,
+    satellites: Option<u8>,
+    hdop: Option<f32>,
     bearing: Option<f32>,
+    /// GPS course over ground, when moving fast enough for it to be meaningful
+    gps_course: Option<f32>,
     /// Waypoint number and location (lat, lon)
     next_waypoint: Option<(usize, (f64,f64))>,
+    /// the next waypoint in the local frame
+    waypoint_position: Option<Point>,
     waypoint_bearing: Option<f32>,
     turn: Option<f32>,
     pub action: Action,
     speed: (Motion, Motion),
-    lidar: Vec<u32>,
-    distance_front: u32,
-    distance_front_left: u32,
-    distance_front_right: u32,
-    distance_side_left: u32,
-    distance_side_right: u32,
+    /// the occupancy grid around the vehicle
+    map: Option<LocalMap>,
+    /// distance to the nearest obstacle in each sector
+    obstacles: Obstacles,
+    /// objects tracked by the LIDAR, in the vehicle frame
+    objects: Vec<TrackedObject>,
+    /// magnetic declination applied to the compass
+    declination: Option<f32>,
 }
 
 impl State {
     fn new() -> Self {
         State {
             loc: None,
+            position: None,
+            position_sd: None,
+            gps_age: None,
+            satellites: None,
+            hdop: None,
             bearing: None,
+            gps_course: None,
             next_waypoint: None,
+            waypoint_position: None,
             waypoint_bearing: None,
             turn: None,
             action: Action::WaitingForStartCommand,
             speed: (Motion::Speed(0), Motion::Speed(0)),
-            lidar: vec![0_u32; 360],
-            distance_front: 0,
-            distance_front_left: 0,
-            distance_front_right: 0,
-            distance_side_left: 0,
-            distance_side_right: 0,
+            map: None,
+            obstacles: Obstacles::new(),
+            objects: vec![],
+            declination: None,
         }
     }
 
@@ -91,95 +155,105 @@ impl State {
 }
 
 /// group all the IO devices in a single strut to make it easier to pass them around
-struct IO<'a> {
-    gps: GPS,
-    imu: Compass,
-    motors: Motors<'a>,
-    lidar: Lidar
+pub struct IO<P, H, R, D, S, U, O> {
+    pub gps: P,
+    pub imu: H,
+    /// wheel odometry, if there is any
+    pub odometry: O,
+    pub motors: D,
+    pub lidar: R,
+    pub switch: S,
+    pub ultrasonic: U,
 }
 
 pub struct AVC {
-    conf: Config,
     settings: Settings,
+    detector: ObstacleDetector,
     shared_state: Arc<Mutex<Box<State>>>,
 }
 
 impl AVC {
-    pub fn new(conf: Config, settings: Settings) -> Self {
+    pub fn new(settings: Settings) -> Self {
         AVC {
-            conf: conf,
+            detector: ObstacleDetector::new(settings.sectors.clone()),
             settings: settings,
             shared_state: Arc::new(Mutex::new(Box::new(State::new()))),
         }
     }
 
-    pub fn run(&self) {
-
-        let mut qik = Qik::new(String::from(self.conf.qik_device), 18).unwrap();
-        qik.init().unwrap();
-
-        let switch = Switch::new(17);
-
-        let mut io = IO {
-            gps: GPS::new(self.conf.gps_device),
-            imu: Compass::new(self.conf.imu_device),
-            motors: Motors::new(&mut qik),
-            lidar: Lidar::new(String::from(self.conf.lidar_device))
-        };
-
-        io.gps.start_thread();
-//        io.imu.start_thread().unwrap();
-        switch.start_thread();
+    /// Run the course. Any threads required by the IO devices must already be running.
+    pub fn run<P, H, R, D, S, U, O>(&self, io: &mut IO<P, H, R, D, S, U, O>)
+        where P: PositionSource,
+              H: HeadingSource,
+              R: RangeScanner,
+              D: DriveActuator,
+              S: StartSwitch,
+              U: UltrasonicSensors,
+              O: OdometrySource
+    {
 
         // start the thread to write the video
         let video_state = self.shared_state.clone();
-        let video_thread = thread::spawn(move || {
-            let video = Video::new(0);
-            let start = UTC::now().timestamp();
-            let filename = format!("avc-{}.mp4", start);
-            println!("Writing video to {}", filename);
-            video.init(filename).unwrap();
-            let mut frame = 0;
-            loop {
-                frame += 1;
-
-                let now = UTC::now();
-                let elapsed = now.timestamp() - start;
-
-                video.capture();
-
-                {
-                    let s = video_state.lock().unwrap();
-                    // println!("{:?}", *s);
-
-                    // stop capturing video at end of race
-                    match s.action {
-                        Action::Aborted | Action::Finished => {
-                            println!("Aborting video writer thread");
-                            break;
-                        }
-                        _ => {}
-                    };
+        let video_thread = if self.settings.record_video {
+            Some(thread::spawn(move || {
+                let video = Video::new(0);
+                let start = UTC::now().timestamp();
+                let filename = format!("avc-{}.mp4", start);
+                println!("Writing video to {}", filename);
+                video.init(filename).unwrap();
+                let mut frame = 0;
+                loop {
+                    frame += 1;
+
+                    let now = UTC::now();
+                    let elapsed = now.timestamp() - start;
+
+                    video.capture();
+
+                    {
+                        let s = video_state.lock().unwrap();
+                        // println!("{:?}", *s);
+
+                        // stop capturing video at end of race
+                        match s.action {
+                            Action::Aborted | Action::Finished => {
+                                println!("Aborting video writer thread");
+                                break;
+                            }
+                            _ => {}
+                        };
 
-                    augment_video(&video, &s, now, elapsed, frame);
-                }
+                        augment_video(&video, &s, now, elapsed, frame);
+                    }
 
-                video.write();
-            }
+                    video.write();
+                }
 
-            println!("Closing video file");
-            video.close();
-            println!("Video thread terminated");
-        });
+                println!("Closing video file");
+                video.close();
+                println!("Video thread terminated");
+            }))
+        } else {
+            None
+        };
 
         let mut state = State::new();
+        let mut tracker = Tracker::new();
+        let mut grid = OccupancyGrid::new();
+        let mut localization = Localization::new(self.settings.max_wheel_speed);
+        let mut scan_odometry = ScanOdometry::new();
+
+        if let Some(d) = self.settings.declination {
+            println!("Using magnetic declination of {:.*} degrees", 2, d);
+            io.imu.set_declination(d);
+            state.declination = Some(d);
+        }
 
         // wait for start switch
         println!("Waiting for START switch...");
         loop {
-            match switch.get() {
-                Some(true) => break,
-                _ => {}
+            if let Some(true) = io.switch.state() {
+                break;
             }
             thread::sleep(Duration::from_millis(10));
         }
@@ -188,12 +262,17 @@ impl AVC {
 
         let nav_state = self.shared_state.clone();
         for (i, waypoint) in self.settings.waypoints.iter().enumerate() {
+            let previous = if i > 0 { Some(&self.settings.waypoints[i - 1]) } else { None };
             if !self.navigate_to_waypoint(i + 1,
-                                          &waypoint,
-                                          &mut io,
+                                          waypoint,
+                                          previous,
+                                          io,
                                           &mut state,
-                                          &nav_state,
-                                          &switch) {
+                                          &mut tracker,
+                                          &mut grid,
+                                          &mut localization,
+                                          &mut scan_odometry,
+                                          &nav_state) {
 
                 // set shared state to Aborted so the video thread finishes
                 let mut state = nav_state.lock().unwrap();
@@ -212,34 +291,54 @@ impl AVC {
         }
 
         // we'd better stop now
-        io.motors.set(Motion::Brake(127), Motion::Brake(127));
+        io.motors.drive(Motion::Brake(127), Motion::Brake(127));
 
         // wait for video writer to finish
-        println!("Waiting for video thread to terminate ...");
-        video_thread.join().unwrap();
+        if let Some(video_thread) = video_thread {
+            println!("Waiting for video thread to terminate ...");
+            video_thread.join().unwrap();
+        }
         println!("Finished!");
     }
 
-    fn navigate_to_waypoint(&self,
-                            wp_num: usize,
-                            wp: &Location,
-                            io: &mut IO,
-                            state: &mut State,
-                            nav_state: &Arc<Mutex<Box<State>>>,
-                            switch: &Switch)
-                            -> bool {
+    fn navigate_to_waypoint<P, H, R, D, S, U, O>(&self,
+                                           wp_num: usize,
+                                           wp: &Location,
+                                           previous: Option<&Location>,
+                                           io: &mut IO<P, H, R, D, S, U, O>,
+                                           state: &mut State,
+                                           tracker: &mut Tracker,
+                                           grid: &mut OccupancyGrid,
+                                           localization: &mut Localization,
+                                           scan_odometry: &mut ScanOdometry,
+                                           nav_state: &Arc<Mutex<Box<State>>>)
+                                           -> bool
+        where P: PositionSource,
+              H: HeadingSource,
+              R: RangeScanner,
+              D: DriveActuator,
+              S: StartSwitch,
+              U: UltrasonicSensors,
+              O: OdometrySource
+    {
 
         println!("navigate_to_waypoint({})", wp_num);
 
         // update next_waypoint
         state.next_waypoint = Some((wp_num, (wp.lat, wp.lon)));
 
+        // where this leg of the course starts, for telling when the vehicle has driven past the
+        // waypoint: the previous waypoint, or where the vehicle was for the first waypoint
+        let mut leg_start: Option<Point> = None;
+
+        // how far the vehicle was past the line through the waypoint on the previous loop
+        let mut last_beyond: Option<f64> = None;
+
         loop {
 
             // check for kill switch
-            match switch.get() {
-                Some(false) => return false,
-                _ => {}
+            if let Some(false) = io.switch.state() {
+                return false;
             }
 
             // update shared state so video can record latest data, and return if the
@@ -251,50 +350,153 @@ impl AVC {
             // give the CPU a breather and let some other threads run
             thread::sleep(Duration::from_millis(10));
 
-            match io.gps.get() {
+            // ignore the fix if it isn't good enough to navigate with
+            let fix = match io.gps.fix() {
+                Some(fix) => {
+                    state.gps_age = Some(fix.age());
+                    state.satellites = Some(fix.satellites);
+                    state.hdop = fix.hdop;
+                    if self.good_fix(&fix) { Some(fix) } else { None }
+                }
                 None => {
-                    state.loc = None;
-                    state.set_action(Action::WaitingForGps);
+                    state.gps_age = None;
+                    state.satellites = None;
+                    state.hdop = None;
+                    None
+                }
+            };
+
+            if let Some(ref fix) = fix {
+                if state.declination.is_none() {
+                    let d = calc_declination(fix);
+                    println!("Calculated magnetic declination of {:.*} degrees", 2, d);
+                    io.imu.set_declination(d);
+                    state.declination = Some(d);
+                }
+            }
+
+            // fuse everything we know about where we are and how we're moving
+            let heading = io.imu.heading();
+            let scan = self.fresh_scan(&io.lidar);
+            localization.predict();
+            if let Some(ref fix) = fix {
+                localization.update_gps(fix);
+            }
+            if let Some((b, measured)) = heading {
+                localization.update_heading(b, measured);
+            }
+            localization.update_odometry("wheels", io.odometry.odometry());
+            if let Some(ref scan) = scan {
+                scan_odometry.update(scan);
+                localization.update_odometry("lidar", scan_odometry.odometry());
+            }
+            localization.update_commanded(state.speed);
+
+            // keep navigating on the estimate through GPS dropouts until it becomes too uncertain
+            let estimate = match (localization.frame(), localization.estimate()) {
+                (Some(frame), Some(e)) => {
+                    state.position = Some(e.position);
+                    state.position_sd = Some(e.position_sd());
+                    if e.position_sd() <= self.settings.max_position_sd { Some((frame, e)) } else { None }
+                }
+                _ => {
+                    state.position = None;
+                    state.position_sd = None;
+                    None
+                }
+            };
+            state.loc = fix.as_ref().map(|f| (f.lat, f.lon));
+
+            match estimate {
+                None => {
+                    // there is a frame but no estimate until the compass has given a heading
+                    let waiting = if localization.frame().is_some() && state.position.is_none() {
+                        Action::WaitingForCompass
+                    } else {
+                        Action::WaitingForGps
+                    };
+                    state.waypoint_position = None;
+                    state.set_action(waiting);
                     let s = (Motion::Speed(0), Motion::Speed(0));
-                    io.motors.set(s.0, s.1);
+                    io.motors.drive(s.0, s.1);
                     state.speed = s;
                 }
-                Some(loc) => {
-                    state.loc = Some((loc.lat, loc.lon));
-                    if self.close_enough(&loc, &wp) {
+                Some((frame, estimate)) => {
+                    let wp_position = frame.to_point(wp);
+                    state.waypoint_position = Some(wp_position);
+
+                    let position = estimate.position;
+                    let from = *leg_start.get_or_insert_with(|| match previous {
+                        Some(p) => frame.to_point(p),
+                        None => position,
+                    });
+                    if self.reached(&position, &wp_position) {
+                        state.set_action(Action::ReachedWaypoint { waypoint: wp_num });
+                        return true;
+                    }
+                    let beyond = self.beyond(&from, &position, &wp_position);
+                    let passed = self.drove_past(last_beyond, beyond, &position, &wp_position);
+                    last_beyond = beyond;
+                    if passed {
+                        println!("Drove past waypoint {}", wp_num);
                         state.set_action(Action::ReachedWaypoint { waypoint: wp_num });
                         return true;
                     }
 
-                    match io.imu.get() {
+                    match heading {
                         None => {
                             state.bearing = None;
                             state.set_action(Action::WaitingForCompass);
                             let s = (Motion::Speed(0), Motion::Speed(0));
-                            io.motors.set(s.0, s.1);
+                            io.motors.drive(s.0, s.1);
                             state.speed = s;
                         }
-                        Some(b) => {
+                        Some((b, _)) => {
                             state.bearing = Some(b);
+                            let b = estimate.heading as f32;
 
-                            // simulate ultrasonic sensors with LIDAR data
-                            state.distance_front_left  = io.lidar.min(225, 315);
-                            state.distance_front       = io.lidar.min(315, 45);
-                            state.distance_front_right = io.lidar.min(45, 135);
-                            io.lidar.get(&mut state.lidar);
+                            // use the GPS course as a cross-check on the compass
+                            state.gps_course = match fix.as_ref().map(|f| (f.speed, f.course)) {
+                                Some((Some(speed), Some(course))) if speed >= MIN_SPEED_FOR_GPS_COURSE => Some(course),
+                                _ => None,
+                            };
 
-                            match self.check_obstacles(&state) {
+                            // it isn't safe to drive without the LIDAR
+                            let scan = match scan {
+                                Some(scan) => scan,
+                                None => {
+                                    state.set_action(Action::WaitingForLidar);
+                                    state.turn = None;
+                                    let s = (Motion::Speed(0), Motion::Speed(0));
+                                    io.motors.drive(s.0, s.1);
+                                    state.speed = s;
+                                    continue;
+                                }
+                            };
+
+                            tracker.update(&scan, Some(b), Some(estimate.speed as f32));
+                            state.objects = tracker.objects().to_vec();
+
+                            let pose = Pose { position: position, heading: b };
+                            grid.integrate(&scan, &pose);
+                            let map = grid.local_map(&pose, OVERLAY_MAP_SIZE, OVERLAY_MAP_RESOLUTION);
+
+                            state.obstacles = self.detector.detect(&io.lidar, &io.ultrasonic);
+                            self.detector.include_map(&mut state.obstacles, &map);
+                            state.map = Some(map);
+
+                            match self.check_obstacles(state) {
                                 Some(avoid) => {
                                     match avoid {
                                         Action::AvoidingObstacleToLeft => {
                                             state.set_action(avoid);
                                             state.turn = None;
-                                            state.speed = (Motion::Speed(self.settings.max_speed), Motion::Speed(0));
+                                            state.speed = (self.motion(self.settings.max_speed), self.motion(0));
                                         },
                                         Action::AvoidingObstacleToRight => {
                                             state.set_action(avoid);
                                             state.turn = None;
-                                            state.speed = (Motion::Speed(0), Motion::Speed(self.settings.max_speed));
+                                            state.speed = (self.motion(0), self.motion(self.settings.max_speed));
                                         },
                                         Action::EmergencyStop => {
                                             state.set_action(avoid);
@@ -310,7 +512,7 @@ impl AVC {
                                     // continue with navigation towards waypoint
                                     state.set_action(Action::Navigating { waypoint: wp_num });
 
-                                    let wp_bearing = loc.calc_bearing_to(&wp) as f32;
+                                    let wp_bearing = position.bearing_to(&wp_position) as f32;
 
                                     let turn = calc_bearing_diff(b, wp_bearing);
                                     let mut left_speed = self.settings.max_speed;
@@ -328,13 +530,13 @@ impl AVC {
 
                                     state.waypoint_bearing = Some(wp_bearing);
                                     state.turn = Some(turn);
-                                    state.speed = (Motion::Speed(left_speed),
-                                                   Motion::Speed(right_speed));
+                                    state.speed = (self.motion(left_speed),
+                                                   self.motion(right_speed));
                                 }
                             }
 
                             // set motor speeds
-                            io.motors.set(state.speed.0, state.speed.1);
+                            io.motors.drive(state.speed.0, state.speed.1);
                         }
                     }
                 }
@@ -346,27 +548,39 @@ impl AVC {
     fn check_obstacles(&self, state: &State) -> Option<Action> {
 
         let min_d = self.settings.obstacle_avoidance_distance;
+        let min_c = self.settings.min_obstacle_confidence;
+
+        let ff = state.obstacles.front.blocked(min_d, min_c);
 
-        let ff = state.distance_front;
+        // is there an obstacle on each side?
+        let left  = state.obstacles.front_left.blocked(min_d, min_c);
+        let right = state.obstacles.front_right.blocked(min_d, min_c);
 
-        // find the closest obstacle on each side
-        let left  = state.distance_front_left;
-        let right = state.distance_front_right;
+        // is there anything beside the vehicle that it could turn into?
+        let side_left  = state.obstacles.side_left.blocked(min_d, min_c);
+        let side_right = state.obstacles.side_right.blocked(min_d, min_c);
 
         // determine avoidance action
-        if ff < min_d {
-            if left < min_d {
-                if right < min_d {
+        if ff {
+            if left {
+                if right {
                     Some(Action::EmergencyStop)
                 } else {
                     Some(Action::AvoidingObstacleToLeft)
                 }
-            } else if right < min_d {
-                if left < min_d {
+            } else if right {
+                if left {
                     Some(Action::EmergencyStop)
                 } else {
                     Some(Action::AvoidingObstacleToRight)
                 }
+            } else if side_left != side_right {
+                // turn away from the side that is blocked
+                if side_left {
+                    Some(Action::AvoidingObstacleToLeft)
+                } else {
+                    Some(Action::AvoidingObstacleToRight)
+                }
             } else {
                 // turn in direction we were navigating to
                 match state.turn {
@@ -380,9 +594,9 @@ impl AVC {
                     None => Some(Action::AvoidingObstacleToLeft),
                 }
             }
-        } else if left < min_d {
+        } else if left {
             Some(Action::AvoidingObstacleToLeft)
-        } else if right < min_d {
+        } else if right {
             Some(Action::AvoidingObstacleToRight)
         } else {
             None
@@ -392,20 +606,75 @@ impl AVC {
     /// replace the shared state ... using a block here to limit the scope of the mutex
     fn update_shared_state(&self, state: &State, nav_state: &Arc<Mutex<Box<State>>>) -> bool {
         let mut x = nav_state.lock().unwrap();
-        match x.action {
-            Action::Aborted => {
-                println!("Aborting navigation");
-                return false;
-            }
-            _ => {}
+        if x.action == Action::Aborted {
+            println!("Aborting navigation");
+            return false;
         };
-        *x = Box::new(state.clone());
+        **x = state.clone();
         true
     }
 
-    fn close_enough(&self, a: &Location, b: &Location) -> bool {
-        (a.lat - b.lat).abs() < self.settings.waypoint_accuracy.0
-            && (a.lon - b.lon).abs() < self.settings.waypoint_accuracy.1
+    /// is the fix good enough to navigate with?
+    fn good_fix(&self, fix: &GpsFix) -> bool {
+        fix.age() < self.settings.gps_timeout
+            && fix.fix_type != FixType::NoFix
+            && fix.satellites >= self.settings.min_satellites
+            && fix.hdop.is_none_or(|hdop| hdop <= self.settings.max_hdop)
+    }
+
+    /// the latest LIDAR scan, or None if it hasn't completed a scan recently
+    fn fresh_scan<R: RangeScanner>(&self, lidar: &R) -> Option<Scan> {
+        lidar.scan().and_then(|scan| {
+            if scan.finished.elapsed() < self.settings.lidar_timeout { Some(scan) } else { None }
+        })
+    }
+
+    /// is the vehicle within the waypoint radius?
+    fn reached(&self, position: &Point, wp: &Point) -> bool {
+        position.distance_to(wp) <= self.settings.waypoint_radius
+    }
+
+    /// How far in metres the vehicle is past the line through the waypoint perpendicular to
+    /// the leg from `from`, negative before it. Crossing this line counts as reaching the
+    /// waypoint, which stops the vehicle circling back to a waypoint it narrowly missed.
+    /// None if the leg is too short to have a meaningful direction.
+    fn beyond(&self, from: &Point, position: &Point, wp: &Point) -> Option<f64> {
+        let leg = (wp.east - from.east, wp.north - from.north);
+        let length = (leg.0 * leg.0 + leg.1 * leg.1).sqrt();
+        if length < self.settings.waypoint_radius {
+            return None;
+        }
+        Some(((position.east - wp.east) * leg.0 + (position.north - wp.north) * leg.1) / length)
+    }
+
+    /// has the vehicle just crossed the line through the waypoint (see `beyond`), from the near
+    /// side to the far side and close enough to it for that to count as reaching it?
+    fn drove_past(&self,
+                  before: Option<f64>,
+                  after: Option<f64>,
+                  position: &Point,
+                  wp: &Point) -> bool {
+        match (before, after) {
+            (Some(before), Some(after)) => {
+                before <= 0_f64 && after > 0_f64 && self.near_enough_to_pass(position, wp)
+            }
+            _ => false,
+        }
+    }
+
+    /// is the vehicle close enough to the waypoint for driving past it to count?
+    fn near_enough_to_pass(&self, position: &Point, wp: &Point) -> bool {
+        position.distance_to(wp) <= self.settings.waypoint_radius * PASSED_WAYPOINT_MAX_DISTANCE
+    }
+
+    /// the motor command for a speed from -127 to 127, as a wheel velocity when the encoders
+    /// can hold it
+    fn motion(&self, speed: i8) -> Motion {
+        if self.settings.encoders {
+            Motion::Velocity((speed as f64 / 127_f64 * self.settings.max_wheel_speed) as f32)
+        } else {
+            Motion::Speed(speed)
+        }
     }
 
 }
@@ -423,6 +692,21 @@ fn calc_bearing_diff(current_bearing: f32, wp_bearing: f32) -> f32 {
 
 
 
+/// Calculate the magnetic declination at the fix using the receiver's date if it has one
+fn calc_declination(fix: &GpsFix) -> f32 {
+    let date = fix.date.unwrap_or(UTC::now().date().naive_utc());
+    let year = wmm::decimal_year(date);
+    if !wmm::is_valid(year) {
+        let (first, last) = wmm::valid_years();
+        println!("WARNING: the magnetic model is only valid from {} to {}, so the calculated \
+                  declination may be wrong. Set `declination` in the course file.", first, last);
+    }
+    wmm::declination(fix.lat,
+                     fix.lon,
+                     fix.altitude.unwrap_or(0_f32) as f64,
+                     year) as f32
+}
+
 /// Calculate motor speed based on angle of turn.
 fn calculate_motor_speed(settings: &Settings, angle: f32) -> i8 {
     let mut temp = angle * settings.differential_drive_coefficient;
@@ -453,32 +737,52 @@ fn augment_video(video: &Video, s: &State, now: DateTime<UTC>, elapsed: i64, fra
     video.draw_text(x1,
                     y,
                     match s.loc {
-                        None => format!("GPS: N/A"),
+                        None => "GPS: N/A".to_string(),
                         Some((lat, lon)) => format!("GPS: {:.*}, {:.*}", 6, lat, 6, lon),
                     },
                     &c);
+
+    // Line 1 (still) - fix quality and age
+    video.draw_text(x1 + 220,
+                    y,
+                    match (s.satellites, s.hdop) {
+                        (Some(n), Some(hdop)) => format!("{} / {:.*}", n, 1, hdop),
+                        (Some(n), None) => format!("{} / ?", n),
+                        _ => "- / -".to_string(),
+                    },
+                    &c);
+    video.draw_text(x1 + 300,
+                    y,
+                    match s.gps_age {
+                        None => "Age: N/A".to_string(),
+                        Some(age) => format!("Age: {:.*}s", 1,
+                                             age.as_secs() as f32 + age.subsec_nanos() as f32 / 1e9),
+                    },
+                    &c);
     y += line_height;
 
     // Line 2 - next waypoint number
     video.draw_text(x1,
                     y,
                     match s.next_waypoint {
-                        None => format!("WP ?: N/A"),
+                        None => "WP ?: N/A".to_string(),
                         Some((n, (lat, lon))) => format!("WP {}: {:.*}, {:.*}", n, 6, lat, 6, lon),
                     },
                     &c);
     y += line_height;
 
 
-    // Line 3 - difference in GPS co-ordinates (are we there yet?)
+    // Line 3 - metres east and north from the estimated position to the waypoint (are we there yet?)
     video.draw_text(x1,
                     y,
-                    if s.loc.is_some() && s.next_waypoint.is_some() {
-                        let gps = s.loc.unwrap();
-                        let wp = s.next_waypoint.unwrap().1;
-                        format!("DIFF: {:.*}, {:.*}", 6, wp.0-gps.0, 6, wp.1-gps.1)
+                    if let (Some(pos), Some(wp)) = (s.position, s.waypoint_position) {
+                        let (de, dn) = (wp.east - pos.east, wp.north - pos.north);
+                        match s.position_sd {
+                            Some(sd) => format!("DIFF: {:.*}m E, {:.*}m N (+/- {:.*}m)", 1, de, 1, dn, 1, sd),
+                            None => format!("DIFF: {:.*}m E, {:.*}m N", 1, de, 1, dn),
+                        }
                     } else {
-                        format!("DIFF: N/A")
+                        "DIFF: N/A".to_string()
                     },
                     &c);
     y += line_height;
@@ -487,17 +791,26 @@ fn augment_video(video: &Video, s: &State, now: DateTime<UTC>, elapsed: i64, fra
     video.draw_text(x1,
                     y,
                     match s.bearing {
-                        None => format!("Compass: N/A"),
+                        None => "Compass: N/A".to_string(),
                         Some(b) => format!("Compass: {:.*}", 1, b),
                     },
                     &c);
+
+    // Line 4 (still) - GPS course over ground
+    video.draw_text(x1 + 150,
+                    y,
+                    match s.gps_course {
+                        None => "COG: N/A".to_string(),
+                        Some(b) => format!("COG: {:.*}", 1, b),
+                    },
+                    &c);
     y += line_height;
 
     // Line 5 - what is bearing for next WP?
     video.draw_text(x1,
                     y,
                     match s.waypoint_bearing {
-                        None => format!("WP: N/A"),
+                        None => "WP: N/A".to_string(),
                         Some(b) => format!("WP: {:.*}", 1, b),
                     },
                     &c);
@@ -506,7 +819,7 @@ fn augment_video(video: &Video, s: &State, now: DateTime<UTC>, elapsed: i64, fra
     video.draw_text(x1 + 100,
                     y,
                     match s.turn {
-                        None => format!("Turn: N/A"),
+                        None => "Turn: N/A".to_string(),
                         Some(b) => format!("Turn: {:.*}", 1, b),
                     },
                     &c);
@@ -517,7 +830,7 @@ fn augment_video(video: &Video, s: &State, now: DateTime<UTC>, elapsed: i64, fra
     // Date
     video.draw_text(x2,
                     y,
-                    format!("UTC: {}", now.format("%Y-%m-%d %H:%M:%S").to_string()),
+                    format!("UTC: {}", now.format("%Y-%m-%d %H:%M:%S")),
                     &c);
     y += line_height;
 
@@ -540,17 +853,19 @@ fn augment_video(video: &Video, s: &State, now: DateTime<UTC>, elapsed: i64, fra
     // ultrasonic sensors
     video.draw_text(x2,
                     y,
-                    format!("FL={}, FF={}, FR={}",
-                            s.distance_front_left,
-                            s.distance_front,
-                            s.distance_front_right),
+                    format!("SL={}, FL={}, FF={}, FR={}, SR={}",
+                            s.obstacles.side_left.distance,
+                            s.obstacles.front_left.distance,
+                            s.obstacles.front.distance,
+                            s.obstacles.front_right.distance,
+                            s.obstacles.side_right.distance),
                     &c);
     y += line_height;
 
     // action
     video.draw_text(x2, y, format!("{:?}", s.action), &c);
 
-    // draw raw LIDAR data points
+    // draw the occupied cells of the occupancy grid
     let blue = Color::new(40, 40, 200, 24); // r, g, b, alpha
     let red = Color::new(200, 40, 40, 24); // r, g, b, alpha
 
@@ -561,30 +876,211 @@ fn augment_video(video: &Video, s: &State, now: DateTime<UTC>, elapsed: i64, fra
 
     video.fill_rect((cx-3) as u32, (cy-3) as u32, 7, 7, &green);
 
-    for i in 0..360 {
+    if let Some(ref map) = s.map {
+        // the grid is drawn at the same scale as the objects (1 pixel = 2 cm)
+        let cell_size = (map.resolution * 50_f64) as u32;
+        for row in 0..map.size {
+            for col in 0..map.size {
+                if map.get(row, col) > OCCUPIED_PROBABILITY {
+                    let (x, y) = map.position(row, col);
+                    let px = cx + (x * 50_f64) as i32 - cell_size as i32 / 2;
+                    let py = cy - (y * 50_f64) as i32 - cell_size as i32 / 2;
+
+                    // use color coding for distance
+                    if x * x + y * y < 1_f64 {
+                        video.fill_rect(px as u32, py as u32, cell_size, cell_size, &red);
+                    } else {
+                        video.fill_rect(px as u32, py as u32, cell_size, cell_size, &blue);
+                    }
+                }
+            }
+        }
+    }
+
+    // draw the tracked objects, color coded by what they are thought to be
+    let white = Color::new(220, 220, 220, 24); // r, g, b, alpha
+    let orange = Color::new(240, 140, 0, 24); // r, g, b, alpha
+    let yellow = Color::new(220, 220, 0, 24); // r, g, b, alpha
+
+    // convert cm in the vehicle frame to screen co-ordinates, at the same scale as the points
+    let to_screen = |p: (f32, f32)| -> Option<(u32, u32)> {
+        let x = cx + (p.0 / 2_f32) as i32;
+        let y = cy - (p.1 / 2_f32) as i32;
+        if x > 0 && y > 0 { Some((x as u32, y as u32)) } else { None }
+    };
+
+    for o in s.objects.iter().filter(|o| o.missed == 0) {
+        let color = match o.kind {
+            ObjectKind::Wall => &white,
+            ObjectKind::Barrel => &orange,
+            ObjectKind::Vehicle => &red,
+            ObjectKind::Unknown => &yellow,
+        };
+        let outline: Vec<(f32, f32)> = match o.shape {
+            Shape::Line { start, end } => (0..21).map(|i| {
+                let t = i as f32 / 20_f32;
+                (start.0 + t * (end.0 - start.0), start.1 + t * (end.1 - start.1))
+            }).collect(),
+            Shape::Circle { center, radius } => (0..24).map(|i| {
+                let a = (i as f32 * 15_f32).to_radians();
+                (center.0 + radius * a.sin(), center.1 + radius * a.cos())
+            }).collect(),
+        };
+        for (x, y) in outline.into_iter().filter_map(&to_screen) {
+            video.fill_rect(x, y, 2, 2, color);
+        }
+        if let Some((x, y)) = to_screen(o.center) {
+            video.draw_text(x + 5, y, format!("{}", o.id), color);
+        }
+    }
 
-        // don't show behind the sensor since it is the LCD panel and only
-        // show points that are closer than 400cm
-        if (i<135 || i>225) && s.lidar[i] < 400 {
+}
 
-            // now scale distance down to fit into the area on the screen
-            let distance = s.lidar[i] as f64 / 2_f64;
 
-            let angle_radians = (i as f64).to_radians();
-            let ox = (distance * angle_radians.sin()) as i32;
-            let oy = (distance * angle_radians.cos()) as i32;
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use super::super::perception::fuse;
+
+    fn settings() -> Settings {
+        Settings {
+            max_speed: 127,
+            differential_drive_coefficient: 2_f32,
+            waypoint_radius: 2.5,
+            waypoints: vec![],
+            obstacle_avoidance_distance: 150,
+            sectors: Sectors::new(),
+            min_obstacle_confidence: 0.5,
+            usonic_sample_count: 4,
+            min_satellites: 4,
+            max_hdop: 5_f32,
+            gps_timeout: Duration::from_secs(2),
+            max_position_sd: 5_f64,
+            lidar_timeout: Duration::from_secs(1),
+            max_wheel_speed: 1_f64,
+            encoders: false,
+            record_video: false,
+            declination: None,
+        }
+    }
 
-            let x = (cx + ox) as u32;
-            let y = (cy - oy) as u32;
+    /// a state with obstacles at the given distances (cm) in the side left, front left,
+    /// front, front right and side right sectors, seen by both sensors
+    fn state(distances: [u32; 5], turn: Option<f32>) -> State {
+        let mut s = State::new();
+        let r = |d: u32| fuse(Some(d), Some(d));
+        s.obstacles = Obstacles {
+            side_left: r(distances[0]),
+            front_left: r(distances[1]),
+            front: r(distances[2]),
+            front_right: r(distances[3]),
+            side_right: r(distances[4]),
+        };
+        s.turn = turn;
+        s
+    }
 
-            // use color coding for distance
-            if s.lidar[i] < 100 {
-                video.fill_rect(x-1, y-1, 3, 3, &red);
-            } else {
-                video.fill_rect(x-1, y-1, 3, 3, &blue);
+    #[test]
+    fn avoids_obstacles_ahead() {
+        let avc = AVC::new(settings());
+        assert_eq!(avc.check_obstacles(&state([500; 5], None)), None);
+        assert_eq!(avc.check_obstacles(&state([500, 100, 500, 500, 500], None)),
+                   Some(Action::AvoidingObstacleToLeft));
+        assert_eq!(avc.check_obstacles(&state([500, 500, 500, 100, 500], None)),
+                   Some(Action::AvoidingObstacleToRight));
+        assert_eq!(avc.check_obstacles(&state([500, 100, 100, 100, 500], None)),
+                   Some(Action::EmergencyStop));
+        // something beside the vehicle doesn't matter while the way ahead is clear
+        assert_eq!(avc.check_obstacles(&state([100, 500, 500, 500, 100], None)), None);
+    }
+
+    #[test]
+    fn turns_away_from_a_blocked_side() {
+        let avc = AVC::new(settings());
+        // navigating to the right, but there is something on the right
+        assert_eq!(avc.check_obstacles(&state([500, 500, 100, 500, 100], Some(10_f32))),
+                   Some(Action::AvoidingObstacleToRight));
+        assert_eq!(avc.check_obstacles(&state([100, 500, 100, 500, 500], Some(-10_f32))),
+                   Some(Action::AvoidingObstacleToLeft));
+        // with both or neither side blocked, turn towards the waypoint
+        assert_eq!(avc.check_obstacles(&state([100, 500, 100, 500, 100], Some(-10_f32))),
+                   Some(Action::AvoidingObstacleToRight));
+        assert_eq!(avc.check_obstacles(&state([500, 500, 100, 500, 500], Some(10_f32))),
+                   Some(Action::AvoidingObstacleToLeft));
+    }
+
+    /// drive a leg from (0, 0) past a waypoint 20m north, `offset` metres to the east of it, a
+    /// metre at a time. Returns whether the waypoint counted as reached.
+    fn drive_past(avc: &AVC, offset: f64) -> bool {
+        let (from, wp) = (Point::new(0_f64, 0_f64), Point::new(0_f64, 20_f64));
+        let mut last_beyond = None;
+        for i in 0..30 {
+            let position = Point::new(offset, i as f64);
+            if avc.reached(&position, &wp) {
+                return true;
             }
+            let beyond = avc.beyond(&from, &position, &wp);
+            if avc.drove_past(last_beyond, beyond, &position, &wp) {
+                return true;
+            }
+            last_beyond = beyond;
         }
+        false
     }
 
-}
+    #[test]
+    fn reaches_a_waypoint_within_the_radius() {
+        let avc = AVC::new(settings());
+        assert!(avc.reached(&Point::new(1.5, 2_f64), &Point::new(0_f64, 0_f64)));
+        assert!(!avc.reached(&Point::new(1.5, 2.1), &Point::new(0_f64, 0_f64)));
+        assert!(drive_past(&avc, 2_f64));
+    }
 
+    #[test]
+    fn measures_how_far_past_the_waypoint() {
+        let avc = AVC::new(settings());
+        let (from, wp) = (Point::new(0_f64, 0_f64), Point::new(20_f64, 0_f64));
+        assert_eq!(avc.beyond(&from, &Point::new(15_f64, 3_f64), &wp), Some(-5_f64));
+        assert_eq!(avc.beyond(&from, &Point::new(20_f64, -8_f64), &wp), Some(0_f64));
+        assert_eq!(avc.beyond(&from, &Point::new(22_f64, 8_f64), &wp), Some(2_f64));
+    }
+
+    #[test]
+    fn has_no_direction_for_a_short_leg() {
+        let avc = AVC::new(settings());
+        let from = Point::new(0_f64, 0_f64);
+        assert_eq!(avc.beyond(&from, &Point::new(5_f64, 5_f64), &Point::new(1_f64, 2_f64)), None);
+        assert!(avc.beyond(&from, &Point::new(5_f64, 5_f64), &Point::new(0_f64, 2.5)).is_some());
+    }
+
+    #[test]
+    fn arrives_by_driving_past_nearby() {
+        let avc = AVC::new(settings());
+        let limit = settings().waypoint_radius * PASSED_WAYPOINT_MAX_DISTANCE;
+        assert!(avc.near_enough_to_pass(&Point::new(limit, 0_f64), &Point::new(0_f64, 0_f64)));
+        let outside = Point::new(limit + 0.1, 0_f64);
+        assert!(!avc.near_enough_to_pass(&outside, &Point::new(0_f64, 0_f64)));
+
+        // crossing the line outside the radius but within the limit
+        assert!(drive_past(&avc, 5_f64));
+        assert!(drive_past(&avc, -(limit - 0.5)));
+        // crossing too far away doesn't count
+        assert!(!drive_past(&avc, limit + 0.5));
+    }
+
+    #[test]
+    fn only_counts_crossing_from_the_near_side() {
+        let avc = AVC::new(settings());
+        let wp = Point::new(0_f64, 0_f64);
+        let position = Point::new(3_f64, 0.5);
+        assert!(avc.drove_past(Some(-0.5), Some(0.5), &position, &wp));
+        assert!(avc.drove_past(Some(0_f64), Some(0.5), &position, &wp));
+        // starting the leg already past the line, or coming back to it
+        assert!(!avc.drove_past(None, Some(0.5), &position, &wp));
+        assert!(!avc.drove_past(Some(0.5), Some(1_f64), &position, &wp));
+        assert!(!avc.drove_past(Some(0.5), Some(-0.5), &position, &wp));
+        // a leg too short to have a line
+        assert!(!avc.drove_past(None, None, &position, &wp));
+    }
+}
@@ -0,0 +1,248 @@
+use yaml_rust::{Yaml, YamlLoader};
+
+use super::yaml_f64;
+
+use std::fs::File;
+use std::io::{Read, Write};
+
+/// Hard-iron and soft-iron correction for the magnetometer. Readings are corrected by
+/// subtracting the offset and then multiplying the x and y components by the soft-iron matrix.
+#[derive(Debug, Clone)]
+pub struct CompassCalibration {
+    /// hard-iron offset in micro-teslas
+    pub offset: (f32, f32, f32),
+    /// soft-iron correction in the horizontal plane
+    pub soft_iron: [[f32; 2]; 2],
+}
+
+impl CompassCalibration {
+    /// no correction
+    pub fn identity() -> Self {
+        CompassCalibration {
+            offset: (0_f32, 0_f32, 0_f32),
+            soft_iron: [[1_f32, 0_f32], [0_f32, 1_f32]],
+        }
+    }
+
+    pub fn apply(&self, x: f32, y: f32, z: f32) -> (f32, f32, f32) {
+        let (x, y, z) = (x - self.offset.0, y - self.offset.1, z - self.offset.2);
+        let m = &self.soft_iron;
+        (m[0][0] * x + m[0][1] * y, m[1][0] * x + m[1][1] * y, z)
+    }
+
+    /// Fit the calibration to readings taken while the vehicle is turned through at least one
+    /// full circle on level ground. The readings trace an offset, rotated ellipse in the x/y
+    /// plane which is mapped back onto a circle centered on the origin.
+    pub fn fit(samples: &[(f32, f32, f32)]) -> Result<Self, String> {
+        if samples.len() < 10 {
+            return Err(format!("Not enough samples to calibrate ({})", samples.len()));
+        }
+
+        // least squares fit of the conic a.x^2 + b.xy + c.y^2 + d.x + e.y = 1
+        let mut ata = [[0_f64; 5]; 5];
+        let mut atb = [0_f64; 5];
+        for &(x, y, _) in samples {
+            let (x, y) = (x as f64, y as f64);
+            let row = [x * x, x * y, y * y, x, y];
+            for i in 0..5 {
+                for j in 0..5 {
+                    ata[i][j] += row[i] * row[j];
+                }
+                atb[i] += row[i];
+            }
+        }
+        let p = solve5(ata, atb)?;
+        let (a, b, c, d, e) = (p[0], p[1], p[2], p[3], p[4]);
+
+        if b * b - 4_f64 * a * c >= 0_f64 {
+            return Err(String::from("Samples do not form an ellipse; turn a full circle"));
+        }
+
+        // center of the ellipse is the hard-iron offset
+        let det = 4_f64 * a * c - b * b;
+        let x0 = (b * e - 2_f64 * c * d) / det;
+        let y0 = (b * d - 2_f64 * a * e) / det;
+
+        // shape of the ellipse relative to its center: (p - c)' M (p - c) = k
+        let k = 1_f64 + a * x0 * x0 + b * x0 * y0 + c * y0 * y0;
+        let (m00, m01, m11) = (a / k, b / 2_f64 / k, c / k);
+
+        // soft-iron correction is the square root of M, scaled so the corrected readings keep
+        // the same average magnitude
+        let theta = 0.5 * (2_f64 * m01).atan2(m00 - m11);
+        let (s, co) = (theta.sin(), theta.cos());
+        let l1 = m00 * co * co + 2_f64 * m01 * s * co + m11 * s * s;
+        let l2 = m00 * s * s - 2_f64 * m01 * s * co + m11 * co * co;
+        if l1 <= 0_f64 || l2 <= 0_f64 {
+            return Err(String::from("Samples do not form an ellipse; turn a full circle"));
+        }
+        let radius = 1_f64 / (l1 * l2).sqrt().sqrt();
+        let (r1, r2) = (l1.sqrt() * radius, l2.sqrt() * radius);
+        let soft_iron = [[(r1 * co * co + r2 * s * s) as f32, ((r1 - r2) * s * co) as f32],
+                         [((r1 - r2) * s * co) as f32, (r1 * s * s + r2 * co * co) as f32]];
+
+        // the z offset can't be determined from readings taken on level ground
+        Ok(CompassCalibration {
+            offset: (x0 as f32, y0 as f32, 0_f32),
+            soft_iron: soft_iron,
+        })
+    }
+
+    pub fn load(filename: &str) -> Result<Self, String> {
+        let mut input = String::new();
+        let mut file = File::open(filename).map_err(|e| format!("{}: {}", filename, e))?;
+        file.read_to_string(&mut input).map_err(|e| format!("{}: {}", filename, e))?;
+        let docs = YamlLoader::load_from_str(&input).map_err(|e| format!("{}: {:?}", filename, e))?;
+
+        let doc = match docs.first() {
+            Some(doc) => doc,
+            None => return Err(format!("{}: empty calibration file", filename)),
+        };
+        let get = |key: &str| -> Result<Vec<f32>, String> {
+            let v = match doc.as_hash().and_then(|h| h.get(&Yaml::String(String::from(key)))) {
+                Some(v) => v,
+                None => return Err(format!("{}: missing {}", filename, key)),
+            };
+            let v = v.as_vec().ok_or(format!("{}: {} must be a list", filename, key))?;
+            let mut values = vec![];
+            for y in v {
+                match *y {
+                    Yaml::Array(ref a) => values.extend(a.iter().filter_map(yaml_f64).map(|n| n as f32)),
+                    _ => values.extend(yaml_f64(y).map(|n| n as f32)),
+                }
+            }
+            Ok(values)
+        };
+
+        let offset = get("offset")?;
+        let soft_iron = get("soft_iron")?;
+        if offset.len() != 3 || soft_iron.len() != 4 {
+            return Err(format!("{}: offset must have 3 values and soft_iron 2x2", filename));
+        }
+
+        Ok(CompassCalibration {
+            offset: (offset[0], offset[1], offset[2]),
+            soft_iron: [[soft_iron[0], soft_iron[1]], [soft_iron[2], soft_iron[3]]],
+        })
+    }
+
+    pub fn save(&self, filename: &str) -> Result<(), String> {
+        let s = format!("# compass calibration\noffset: [{}, {}, {}]\nsoft_iron:\n  - [{}, {}]\n  - [{}, {}]\n",
+                        self.offset.0, self.offset.1, self.offset.2,
+                        self.soft_iron[0][0], self.soft_iron[0][1],
+                        self.soft_iron[1][0], self.soft_iron[1][1]);
+        let mut file = File::create(filename).map_err(|e| format!("{}: {}", filename, e))?;
+        file.write_all(s.as_bytes()).map_err(|e| format!("{}: {}", filename, e))
+    }
+}
+
+/// solve a 5x5 linear system using gaussian elimination with partial pivoting
+fn solve5(mut a: [[f64; 5]; 5], mut b: [f64; 5]) -> Result<[f64; 5], String> {
+    for col in 0..5 {
+        let mut pivot = col;
+        for row in col + 1..5 {
+            if a[row][col].abs() > a[pivot][col].abs() {
+                pivot = row;
+            }
+        }
+        if a[pivot][col].abs() < 1e-12 {
+            return Err(String::from("Samples do not form an ellipse; turn a full circle"));
+        }
+        a.swap(col, pivot);
+        b.swap(col, pivot);
+        for row in col + 1..5 {
+            let f = a[row][col] / a[col][col];
+            for k in col..5 {
+                a[row][k] -= f * a[col][k];
+            }
+            b[row] -= f * b[col];
+        }
+    }
+    let mut x = [0_f64; 5];
+    for row in (0..5).rev() {
+        let mut sum = b[row];
+        for k in row + 1..5 {
+            sum -= a[row][k] * x[k];
+        }
+        x[row] = sum / a[row][row];
+    }
+    Ok(x)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::env;
+    use std::f64::consts::PI;
+    use std::fs;
+
+    /// readings around an ellipse with the given center and semi-axes, rotated by `angle`
+    /// degrees
+    fn ellipse(center: (f64, f64), axes: (f64, f64), angle: f64) -> Vec<(f32, f32, f32)> {
+        let (s, c) = angle.to_radians().sin_cos();
+        (0..72).map(|i| {
+            let t = i as f64 * 5_f64 * PI / 180_f64;
+            let (x, y) = (axes.0 * t.cos(), axes.1 * t.sin());
+            ((center.0 + x * c - y * s) as f32, (center.1 + x * s + y * c) as f32, 42_f32)
+        }).collect()
+    }
+
+    fn near(a: f32, b: f64, tolerance: f64) -> bool {
+        (a as f64 - b).abs() < tolerance
+    }
+
+    #[test]
+    fn fits_offset_and_scale() {
+        let c = CompassCalibration::fit(&ellipse((10_f64, -5_f64), (30_f64, 20_f64), 0_f64)).unwrap();
+        assert!(near(c.offset.0, 10_f64, 1e-3) && near(c.offset.1, -5_f64, 1e-3));
+        assert_eq!(c.offset.2, 0_f32);
+        // each axis is scaled to the geometric mean of the two, sqrt(600)
+        let r = 600_f64.sqrt();
+        assert!(near(c.soft_iron[0][0], r / 30_f64, 1e-4), "{:?}", c);
+        assert!(near(c.soft_iron[1][1], r / 20_f64, 1e-4), "{:?}", c);
+        assert!(near(c.soft_iron[0][1], 0_f64, 1e-4) && near(c.soft_iron[1][0], 0_f64, 1e-4));
+    }
+
+    #[test]
+    fn maps_a_rotated_ellipse_onto_a_circle() {
+        let samples = ellipse((-12_f64, 7_f64), (40_f64, 25_f64), 30_f64);
+        let c = CompassCalibration::fit(&samples).unwrap();
+        assert!(near(c.offset.0, -12_f64, 1e-3) && near(c.offset.1, 7_f64, 1e-3));
+        let r = 1000_f64.sqrt();
+        for &(x, y, z) in &samples {
+            let (x, y, _) = c.apply(x, y, z);
+            let d = ((x * x + y * y) as f64).sqrt();
+            assert!((d - r).abs() < 1e-3, "radius {} at ({}, {})", d, x, y);
+        }
+    }
+
+    #[test]
+    fn rejects_samples_that_are_not_an_ellipse() {
+        assert!(CompassCalibration::fit(&ellipse((0_f64, 0_f64), (30_f64, 20_f64), 0_f64)[..5]).is_err());
+        let line: Vec<(f32, f32, f32)> = (0..20).map(|i| (i as f32, 2_f32 * i as f32, 0_f32)).collect();
+        assert!(CompassCalibration::fit(&line).is_err());
+    }
+
+    #[test]
+    fn saves_and_loads() {
+        let dir = env::temp_dir();
+        let filename = dir.join(format!("avc-calibration-{}.yaml", ::std::process::id()));
+        let filename = filename.to_str().unwrap();
+
+        let c = CompassCalibration::fit(&ellipse((10_f64, -5_f64), (30_f64, 20_f64), 30_f64)).unwrap();
+        c.save(filename).unwrap();
+        let loaded = CompassCalibration::load(filename).unwrap();
+        assert_eq!(loaded.offset, c.offset);
+        assert_eq!(loaded.soft_iron, c.soft_iron);
+
+        for contents in ["", "# compass calibration\n"].iter() {
+            fs::write(filename, contents).unwrap();
+            let e = CompassCalibration::load(filename).err().unwrap();
+            assert!(e.ends_with("empty calibration file"), "{}", e);
+        }
+        fs::write(filename, "offset: [1, 2, 3]\n").unwrap();
+        assert!(CompassCalibration::load(filename).is_err());
+        fs::remove_file(filename).unwrap();
+    }
+}
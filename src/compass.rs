@@ -1,54 +1,295 @@
 extern crate hmc5883l;
 use self::hmc5883l::*;
 
-use std::time::Duration;
+use std::collections::VecDeque;
+use std::time::{Duration, Instant};
 use std::sync::{Arc, Mutex};
 use std::thread;
 use std::f32::consts::PI;
-use std::f32;
 
-use std::io::prelude::*;
+use super::calibration::CompassCalibration;
+use super::hardware::{keep_connected, HeadingSource};
+use super::imu::{self, ADXL345, ADXL345_ADDRESS};
+use super::maths::normalize_degrees;
+
+/// I2C address of the HMC5883L
+const HMC5883L_ADDRESS: u16 = 0x1E;
+
+/// reopen the device after this many failed readings in a row
+const MAX_CONSECUTIVE_FAILURES: u32 = 10;
+
+/// how long to wait before retrying a failed read, to give the bus time to recover
+const RETRY_DELAY_MS: u64 = 5;
+
+/// a magnetometer reading and the accelerometer reading taken with it, if there was one
+type Sample = ((f32, f32, f32), Option<(f32, f32, f32)>);
+
+/// How to sample the compass
+#[derive(Debug, Clone)]
+pub struct CompassConfig {
+    /// how often to read the magnetometer, at least 1 Hz
+    pub rate_hz: u32,
+    /// number of readings to average, at least 1
+    pub window: usize,
+    /// how many times to retry a failed read before giving up on a sample
+    pub max_retries: u32,
+    /// the heading is discarded if there hasn't been a good reading for this long
+    pub stale_timeout: Duration,
+}
+
+impl CompassConfig {
+    pub fn new() -> Self {
+        CompassConfig {
+            rate_hz: 20,
+            window: 4,
+            max_retries: 2,
+            stale_timeout: Duration::from_millis(500),
+        }
+    }
+}
+
+/// Status of the I2C bus
+#[derive(Debug, Clone)]
+pub struct CompassHealth {
+    /// is the magnetometer currently open?
+    pub connected: bool,
+    /// number of successful readings
+    pub readings: u64,
+    /// number of failed I2C reads, including ones that succeeded on a retry
+    pub errors: u64,
+    /// number of times the device has been reopened
+    pub reconnects: u64,
+}
+
+/// The latest averaged reading
+#[derive(Debug, Clone)]
+pub struct CompassReading {
+    /// heading in degrees (0 = north, clockwise), including the declination
+    pub heading: f32,
+    /// uncalibrated magnetic field in micro-teslas
+    pub raw: (f32, f32, f32),
+    /// pitch and roll in degrees, if there is an accelerometer
+    pub attitude: Option<(f32, f32)>,
+    /// when the last sample in the window was read
+    pub updated: Instant,
+}
+
+/// state shared between the sampling thread and the compass handle
+struct Shared {
+    /// reading with the magnetic (not true) heading
+    reading: Option<CompassReading>,
+    health: CompassHealth,
+}
 
 pub struct Compass {
-    mag: HMC5883L
+    filename: &'static str,
+    calibration: CompassCalibration,
+    config: CompassConfig,
+    /// magnetic declination in degrees, positive east
+    declination: f32,
+    shared: Arc<Mutex<Shared>>,
 }
 
 impl Compass {
 
-    pub fn new(f: &'static str) -> Self {
+    pub fn new(f: &'static str, calibration: CompassCalibration, config: CompassConfig) -> Self {
         Compass {
-            mag: HMC5883L::new(f, 0x1E).unwrap()
+            filename: f,
+            calibration: calibration,
+            config: config,
+            declination: 0_f32,
+            shared: Arc::new(Mutex::new(Shared {
+                reading: None,
+                health: CompassHealth {
+                    connected: false,
+                    readings: 0,
+                    errors: 0,
+                    reconnects: 0,
+                },
+            })),
         }
     }
 
-    pub fn get(&mut self) -> Option<f32> {
+    /// set the magnetic declination in degrees (positive east) so that `get` returns the true
+    /// heading rather than the magnetic heading
+    pub fn set_declination(&mut self, degrees: f32) {
+        self.declination = degrees;
+    }
 
-        let gauss_lsb_xy = 1100.0;
-        let gauss_lsb_z  =  980.0;
+    /// start the thread that samples the magnetometer (and accelerometer, if there is one)
+    pub fn start_thread(&self) {
 
-        // You need to determine the correct magnetic declination for your location for accurate
-        // readings. Find yours at http://www.magnetic-declination.com/
-        let declination_angle = 0.22; // in radians, not degrees
+        let f = self.filename;
+        let calibration = self.calibration.clone();
+        let config = self.config.clone();
+        let shared = self.shared.clone();
 
-         // read raw values
-        let (x, y, z) = self.mag.read().unwrap();
+        thread::spawn(move || {
 
-        // convert to micro-teslas
-        let (x, y, _) = (x/gauss_lsb_xy*100.0, y/gauss_lsb_xy*100.0, z/gauss_lsb_z*100.0);
+            let interval = Duration::from_millis(1000 / config.rate_hz as u64);
+            let reconnecting = || shared.lock().unwrap().health.reconnects += 1;
+            keep_connected(reconnecting, || {
+                let mut mag = match HMC5883L::new(f, HMC5883L_ADDRESS) {
+                    Ok(m) => m,
+                    Err(e) => {
+                        println!("Failed to open compass: {}", e);
+                        return;
+                    }
+                };
 
-        let mut heading = y.atan2(x) + declination_angle;
+                let mut accel = match ADXL345::new(f, ADXL345_ADDRESS) {
+                    Ok(a) => Some(a),
+                    Err(e) => {
+                        println!("WARNING: no accelerometer, compass will not be tilt compensated ({})", e);
+                        None
+                    }
+                };
 
-        if heading < 0.0 {
-            heading += 2.0 * PI;
-        }
+                {
+                    let mut s = shared.lock().unwrap();
+                    s.health.connected = true;
+                }
+
+                let mut window: VecDeque<Sample> = VecDeque::new();
+                let mut failures = 0;
+
+                while failures < MAX_CONSECUTIVE_FAILURES {
+                    thread::sleep(interval);
+
+                    let mut errors = 0;
+                    let mut sample = None;
+                    while sample.is_none() && errors <= config.max_retries {
+                        match mag.read() {
+                            Ok(m) => sample = Some(m),
+                            Err(e) => {
+                                println!("Failed to read compass: {}", e);
+                                errors += 1;
+                                if errors <= config.max_retries {
+                                    thread::sleep(Duration::from_millis(RETRY_DELAY_MS));
+                                }
+                            }
+                        }
+                    }
+
+                    let a = match accel {
+                        Some(ref mut a) => match a.read() {
+                            Ok(reading) => Some(reading),
+                            Err(e) => {
+                                println!("Failed to read accelerometer: {}", e);
+                                errors += 1;
+                                None
+                            }
+                        },
+                        None => None,
+                    };
+
+                    let mut s = shared.lock().unwrap();
+                    s.health.errors += errors as u64;
 
-        if heading > 2.0 * PI {
-            heading -= 2.0 * PI;
+                    let m = match sample {
+                        Some(m) => m,
+                        None => {
+                            failures += 1;
+                            continue;
+                        }
+                    };
+                    failures = 0;
+                    s.health.readings += 1;
+
+                    window.push_back((to_micro_teslas(m), a));
+                    while window.len() > config.window {
+                        window.pop_front();
+                    }
+
+                    let raw = mean(window.iter().map(|w| w.0));
+                    let accel_mean = if window.iter().all(|w| w.1.is_some()) {
+                        Some(mean(window.iter().map(|w| w.1.unwrap())))
+                    } else {
+                        None
+                    };
+
+                    s.reading = Some(CompassReading {
+                        heading: calc_heading(&calibration, raw, accel_mean),
+                        raw: raw,
+                        attitude: accel_mean.map(imu::pitch_roll),
+                        updated: Instant::now(),
+                    });
+                }
+
+                println!("Giving up on compass after {} failed readings, reopening", failures);
+                let mut s = shared.lock().unwrap();
+                s.health.connected = false;
+            });
+        });
+    }
+
+    /// the latest reading, or None if there hasn't been a good reading recently. Never blocks
+    /// on the I2C bus.
+    pub fn reading(&self) -> Option<CompassReading> {
+        let s = self.shared.lock().unwrap();
+        match s.reading {
+            Some(ref r) if r.updated.elapsed() < self.config.stale_timeout => {
+                let mut r = r.clone();
+                r.heading = normalize_degrees(r.heading + self.declination);
+                Some(r)
+            }
+            _ => None,
         }
+    }
+
+    pub fn get(&self) -> Option<f32> {
+        self.reading().map(|r| r.heading)
+    }
+
+    pub fn health(&self) -> CompassHealth {
+        let s = self.shared.lock().unwrap();
+        s.health.clone()
+    }
+}
 
-        // Convert radians to degrees for readability.
-        heading = heading * 180.0 / PI;
+/// convert a raw HMC5883L reading (at the default gain) to micro-teslas
+fn to_micro_teslas(m: (f32, f32, f32)) -> (f32, f32, f32) {
+    let gauss_lsb_xy = 1100.0;
+    let gauss_lsb_z  =  980.0;
+    (m.0/gauss_lsb_xy*100.0, m.1/gauss_lsb_xy*100.0, m.2/gauss_lsb_z*100.0)
+}
+
+fn mean<I: Iterator<Item = (f32, f32, f32)>>(values: I) -> (f32, f32, f32) {
+    let mut sum = (0_f32, 0_f32, 0_f32);
+    let mut n = 0;
+    for v in values {
+        sum = (sum.0 + v.0, sum.1 + v.1, sum.2 + v.2);
+        n += 1;
+    }
+    let n = n as f32;
+    (sum.0 / n, sum.1 / n, sum.2 / n)
+}
+
+/// magnetic heading in degrees from a raw magnetometer reading
+fn calc_heading(calibration: &CompassCalibration,
+                raw: (f32, f32, f32),
+                accel: Option<(f32, f32, f32)>) -> f32 {
+
+    // correct for hard-iron and soft-iron distortion
+    let (x, y, z) = calibration.apply(raw.0, raw.1, raw.2);
+
+    // compensate for pitch and roll, falling back to assuming the vehicle is level
+    let tilt_compensated = accel.and_then(|a| imu::tilt_compensated_heading((x, y, z), a));
+    let heading = match tilt_compensated {
+        Some(h) => h,
+        None => y.atan2(x),
+    };
+
+    // Convert radians to degrees for readability.
+    normalize_degrees(heading * 180.0 / PI)
+}
+
+impl HeadingSource for Compass {
+    fn heading(&mut self) -> Option<(f32, Instant)> {
+        self.reading().map(|r| (r.heading, r.updated))
+    }
 
-        Some(heading)
+    fn set_declination(&mut self, degrees: f32) {
+        Compass::set_declination(self, degrees)
     }
 }
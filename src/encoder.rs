@@ -0,0 +1,225 @@
+use sysfs_gpio::{Direction, Edge, Pin, PinPoller};
+
+use std::f64::consts::PI;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use super::hardware::OdometrySource;
+use super::odometry::Odometry;
+
+/// wait this long for an edge before checking the wheel has stopped
+const POLL_TIMEOUT_MS: isize = 20;
+
+/// the wheel velocity is measured over this interval
+const VELOCITY_INTERVAL_MS: u64 = 50;
+
+/// how often the wheel odometry is updated
+const ODOMETRY_INTERVAL_MS: u64 = 20;
+
+/// readings are discarded if an encoder hasn't been polled for this long
+const STALE_TIMEOUT_MS: u64 = 500;
+
+/// assumed wheel slip, as a fraction of the distance travelled, for the odometry uncertainty
+const SLIP_FRACTION: f64 = 0.05;
+
+/// How the encoders are connected and the size of the wheels
+#[derive(Debug, Clone)]
+pub struct EncoderConfig {
+    /// GPIO pins (A, B) for each wheel. Swap A and B if a wheel counts backwards.
+    pub left: (u64, u64),
+    pub right: (u64, u64),
+    /// edges on channel A per revolution of the wheel
+    pub ticks_per_rev: u32,
+    /// metres
+    pub wheel_diameter: f64,
+    /// distance between the centers of the left and right wheels in metres
+    pub track_width: f64,
+}
+
+impl EncoderConfig {
+    pub fn new() -> Self {
+        EncoderConfig {
+            left: (22, 23),
+            right: (24, 25),
+            ticks_per_rev: 360,
+            wheel_diameter: 0.12,
+            track_width: 0.25,
+        }
+    }
+
+    /// distance in metres that the wheel moves per tick
+    pub fn metres_per_tick(&self) -> f64 {
+        PI * self.wheel_diameter / self.ticks_per_rev as f64
+    }
+}
+
+/// The state of one wheel's encoder
+#[derive(Debug, Clone)]
+pub struct WheelState {
+    /// total ticks, positive forwards
+    pub ticks: i64,
+    /// metres per second, positive forwards
+    pub velocity: f64,
+    /// when the encoder was last polled
+    pub updated: Instant,
+}
+
+/// Quadrature encoders on the left and right wheels. Each encoder is read on its own thread by
+/// waiting for GPIO edge events on channel A and reading channel B to tell which way the
+/// wheel is turning. The ticks are also integrated into wheel odometry.
+#[derive(Clone)]
+pub struct WheelEncoders {
+    config: EncoderConfig,
+    left: Arc<Mutex<WheelState>>,
+    right: Arc<Mutex<WheelState>>,
+    odometry: Arc<Mutex<Odometry>>,
+}
+
+impl WheelEncoders {
+    pub fn new(config: EncoderConfig) -> Self {
+        let wheel = || Arc::new(Mutex::new(WheelState {
+            ticks: 0,
+            velocity: 0_f64,
+            updated: Instant::now(),
+        }));
+        WheelEncoders {
+            config: config,
+            left: wheel(),
+            right: wheel(),
+            odometry: Arc::new(Mutex::new(Odometry::new())),
+        }
+    }
+
+    /// start the threads that read the encoders and integrate the odometry
+    pub fn start_thread(&self) {
+        let metres_per_tick = self.config.metres_per_tick();
+        start_encoder_thread("left", self.config.left, metres_per_tick, self.left.clone());
+        start_encoder_thread("right", self.config.right, metres_per_tick, self.right.clone());
+
+        let left = self.left.clone();
+        let right = self.right.clone();
+        let odometry = self.odometry.clone();
+        let track_width = self.config.track_width;
+
+        thread::spawn(move || {
+            let mut last = (0_i64, 0_i64);
+            loop {
+                thread::sleep(Duration::from_millis(ODOMETRY_INTERVAL_MS));
+                let ticks = (left.lock().unwrap().ticks, right.lock().unwrap().ticks);
+                let dl = (ticks.0 - last.0) as f64 * metres_per_tick;
+                let dr = (ticks.1 - last.1) as f64 * metres_per_tick;
+                last = ticks;
+
+                // the vehicle turns clockwise when the left wheel moves further than the right
+                let forward = (dl + dr) / 2_f64;
+                let turn = ((dl - dr) / track_width).to_degrees();
+
+                let mut o = odometry.lock().unwrap();
+                o.add(0_f64, forward, turn);
+                o.position_sd = forward.abs() * SLIP_FRACTION;
+                o.heading_sd = (dl.abs() + dr.abs()) * SLIP_FRACTION / track_width * 180_f64 / PI;
+            }
+        });
+    }
+
+    /// the latest (left, right) wheel velocities in m/s, or None if the encoders aren't
+    /// being read
+    pub fn velocities(&self) -> Option<(f64, f64)> {
+        match (self.get(&self.left), self.get(&self.right)) {
+            (Some(l), Some(r)) => Some((l.velocity, r.velocity)),
+            _ => None,
+        }
+    }
+
+    /// the total (left, right) ticks, or None if the encoders aren't being read
+    pub fn ticks(&self) -> Option<(i64, i64)> {
+        match (self.get(&self.left), self.get(&self.right)) {
+            (Some(l), Some(r)) => Some((l.ticks, r.ticks)),
+            _ => None,
+        }
+    }
+
+    fn get(&self, wheel: &Arc<Mutex<WheelState>>) -> Option<WheelState> {
+        let w = wheel.lock().unwrap();
+        if w.updated.elapsed() < Duration::from_millis(STALE_TIMEOUT_MS) {
+            Some(w.clone())
+        } else {
+            None
+        }
+    }
+}
+
+impl OdometrySource for WheelEncoders {
+    fn odometry(&self) -> Option<Odometry> {
+        self.ticks().map(|_| self.odometry.lock().unwrap().clone())
+    }
+}
+
+fn start_encoder_thread(name: &'static str,
+                        pins: (u64, u64),
+                        metres_per_tick: f64,
+                        state: Arc<Mutex<WheelState>>) {
+
+    let a = Pin::new(pins.0);
+    let b = Pin::new(pins.1);
+
+    thread::spawn(move || {
+        loop {
+            let mut poller = match open(&a, &b) {
+                Ok(p) => p,
+                Err(e) => {
+                    println!("Failed to open {} encoder: {}", name, e);
+                    thread::sleep(Duration::from_millis(1000));
+                    continue;
+                }
+            };
+
+            let mut ticks = state.lock().unwrap().ticks;
+            let mut last_sample = (ticks, Instant::now());
+
+            loop {
+                match poller.poll(POLL_TIMEOUT_MS) {
+                    Ok(Some(value_a)) => match b.get_value() {
+                        // A leads B when turning forwards, so they differ just after an edge on A
+                        Ok(value_b) => ticks += if value_a != value_b { 1 } else { -1 },
+                        Err(e) => {
+                            println!("Failed to read {} encoder: {}", name, e);
+                            break;
+                        }
+                    },
+                    Ok(None) => {}
+                    Err(e) => {
+                        println!("Failed to read {} encoder: {}", name, e);
+                        break;
+                    }
+                }
+
+                let now = Instant::now();
+                let mut s = state.lock().unwrap();
+                s.ticks = ticks;
+                s.updated = now;
+                let elapsed = now.duration_since(last_sample.1);
+                if elapsed >= Duration::from_millis(VELOCITY_INTERVAL_MS) {
+                    let secs = elapsed.as_secs() as f64 + elapsed.subsec_nanos() as f64 / 1_000_000_000_f64;
+                    s.velocity = (ticks - last_sample.0) as f64 * metres_per_tick / secs;
+                    last_sample = (ticks, now);
+                }
+            }
+
+            thread::sleep(Duration::from_millis(1000));
+        }
+    });
+}
+
+/// export the pins and start watching for edges on channel A
+fn open(a: &Pin, b: &Pin) -> ::sysfs_gpio::Result<PinPoller> {
+    for pin in [a, b].iter() {
+        if !pin.is_exported() {
+            pin.export()?;
+        }
+        pin.set_direction(Direction::In)?;
+    }
+    a.set_edge(Edge::BothEdges)?;
+    a.get_poller()
+}
@@ -0,0 +1,139 @@
+extern crate navigation;
+
+use navigation::Location;
+
+/// WGS84 semi-major axis in metres and first eccentricity squared
+const EQUATORIAL_RADIUS: f64 = 6_378_137_f64;
+const ECCENTRICITY_SQUARED: f64 = 0.006_694_379_990_14;
+
+/// A position in metres east and north of the origin of a LocalFrame
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Point {
+    pub east: f64,
+    pub north: f64,
+}
+
+impl Point {
+    pub fn new(east: f64, north: f64) -> Self {
+        Point { east: east, north: north }
+    }
+
+    /// distance in metres
+    pub fn distance_to(&self, other: &Point) -> f64 {
+        let (de, dn) = (other.east - self.east, other.north - self.north);
+        (de * de + dn * dn).sqrt()
+    }
+
+    /// bearing in degrees clockwise from north, from 0 to 360
+    pub fn bearing_to(&self, other: &Point) -> f64 {
+        let b = (other.east - self.east).atan2(other.north - self.north).to_degrees();
+        if b < 0_f64 { b + 360_f64 } else { b }
+    }
+}
+
+/// A flat local tangent plane with its origin at a fixed location, so that positions can be
+/// worked with in metres east and north rather than degrees. The scale in each direction is
+/// taken from the WGS84 ellipsoid at the origin, which is accurate to well under a metre over
+/// the few hundred metres of a course.
+#[derive(Debug, Clone, Copy)]
+pub struct LocalFrame {
+    lat: f64,
+    lon: f64,
+    metres_per_degree_lat: f64,
+    metres_per_degree_lon: f64,
+}
+
+impl LocalFrame {
+    pub fn new(origin: &Location) -> Self {
+        let phi = origin.lat.to_radians();
+        let w = 1_f64 - ECCENTRICITY_SQUARED * phi.sin() * phi.sin();
+        // radii of curvature along the meridian and the prime vertical
+        let meridian = EQUATORIAL_RADIUS * (1_f64 - ECCENTRICITY_SQUARED) / w.powf(1.5);
+        let normal = EQUATORIAL_RADIUS / w.sqrt();
+        LocalFrame {
+            lat: origin.lat,
+            lon: origin.lon,
+            metres_per_degree_lat: meridian.to_radians(),
+            metres_per_degree_lon: (normal * phi.cos()).to_radians(),
+        }
+    }
+
+    pub fn to_point(self, loc: &Location) -> Point {
+        Point {
+            east: (loc.lon - self.lon) * self.metres_per_degree_lon,
+            north: (loc.lat - self.lat) * self.metres_per_degree_lat,
+        }
+    }
+
+    pub fn to_location(self, p: &Point) -> Location {
+        Location::new(self.lat + p.north / self.metres_per_degree_lat,
+                      self.lon + p.east / self.metres_per_degree_lon)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// mean radius of the earth in metres
+    const MEAN_RADIUS: f64 = 6_371_008.8;
+
+    const ORIGIN: (f64, f64) = (39.950380, -105.060512);
+
+    /// great circle distance in metres between two locations, using the haversine formula
+    fn haversine_distance(a: &Location, b: &Location) -> f64 {
+        let (phi1, phi2) = (a.lat.to_radians(), b.lat.to_radians());
+        let dphi = phi2 - phi1;
+        let dlambda = (b.lon - a.lon).to_radians();
+        let h = (dphi / 2_f64).sin().powi(2)
+            + phi1.cos() * phi2.cos() * (dlambda / 2_f64).sin().powi(2);
+        2_f64 * MEAN_RADIUS * h.sqrt().min(1_f64).asin()
+    }
+
+    #[test]
+    fn converts_locations_to_points_and_back() {
+        let frame = LocalFrame::new(&Location::new(ORIGIN.0, ORIGIN.1));
+        let p = frame.to_point(&Location::new(ORIGIN.0, ORIGIN.1));
+        assert_eq!((p.east, p.north), (0_f64, 0_f64));
+
+        for &(east, north) in [(100_f64, 0_f64), (0_f64, -250_f64), (-321.5, 123.25)].iter() {
+            let loc = frame.to_location(&Point::new(east, north));
+            let p = frame.to_point(&loc);
+            assert!((p.east - east).abs() < 1e-6 && (p.north - north).abs() < 1e-6, "{:?}", p);
+        }
+    }
+
+    #[test]
+    fn scales_each_direction_from_the_ellipsoid() {
+        // a degree of latitude is about 111 km, a degree of longitude shrinks with cos(lat)
+        let frame = LocalFrame::new(&Location::new(ORIGIN.0, ORIGIN.1));
+        let p = frame.to_point(&Location::new(ORIGIN.0 + 0.001, ORIGIN.1 + 0.001));
+        assert!((p.north - 111.034).abs() < 0.01, "{:?}", p);
+        assert!((p.east - 85.456).abs() < 0.01, "{:?}", p);
+    }
+
+    #[test]
+    fn agrees_with_the_great_circle_distance() {
+        let origin = Location::new(ORIGIN.0, ORIGIN.1);
+        let frame = LocalFrame::new(&origin);
+        for &(east, north) in [(300_f64, 0_f64), (0_f64, 300_f64), (-200_f64, 150_f64)].iter() {
+            let loc = frame.to_location(&Point::new(east, north));
+            let flat = Point::new(0_f64, 0_f64).distance_to(&Point::new(east, north));
+            let d = haversine_distance(&origin, &loc);
+            // the haversine uses a sphere, so only agrees to within its 0.5% error
+            assert!((flat - d).abs() < flat * 0.005, "{} {}", flat, d);
+        }
+    }
+
+    #[test]
+    fn measures_distance_and_bearing() {
+        let a = Point::new(10_f64, 20_f64);
+        assert_eq!(a.distance_to(&Point::new(13_f64, 24_f64)), 5_f64);
+        assert_eq!(a.bearing_to(&Point::new(10_f64, 30_f64)), 0_f64);
+        assert_eq!(a.bearing_to(&Point::new(20_f64, 20_f64)), 90_f64);
+        assert_eq!(a.bearing_to(&Point::new(10_f64, 10_f64)), 180_f64);
+        assert_eq!(a.bearing_to(&Point::new(0_f64, 20_f64)), 270_f64);
+        assert!((a.bearing_to(&Point::new(0_f64, 30_f64)) - 315_f64).abs() < 1e-9);
+        assert!((a.bearing_to(&Point::new(11_f64, 10_f64)) - 174.289).abs() < 1e-3);
+    }
+}
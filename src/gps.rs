@@ -2,105 +2,449 @@ extern crate serial;
 use std::io::prelude::*;
 use self::serial::prelude::*;
 
+use chrono::{DateTime, NaiveDate, NaiveDateTime, NaiveTime, UTC};
 use navigation::*;
 
+use super::hardware::{keep_connected, PositionSource};
+use super::gpsd;
+use super::gpsd::GpsdParser;
+use super::nmea::NmeaParser;
+use super::ubx;
+use super::ubx::{DynamicModel, FrameReader, UbxParser};
+
+use std::io;
+use std::net::TcpStream;
 use std::sync::{Arc, Mutex};
 use std::thread;
-use std::time::Duration;
-use std::iter::FromIterator;
+use std::time::{Duration, Instant};
+
+/// how long to wait for a u-blox receiver to acknowledge each CFG message
+const UBX_ACK_TIMEOUT_MS: u64 = 1000;
+
+/// how many reads in a row may time out before reconnecting to the receiver
+const MAX_READ_TIMEOUTS: u32 = 3;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum FixType {
+    NoFix,
+    Fix2D,
+    Fix3D,
+}
+
+/// Everything the receiver told us about the current fix
+#[derive(Debug, Clone)]
+pub struct GpsFix {
+    /// UTC time of the fix
+    pub time: Option<NaiveTime>,
+    /// UTC date of the fix
+    pub date: Option<NaiveDate>,
+    pub lat: f64,
+    pub lon: f64,
+    pub fix_type: FixType,
+    /// number of satellites used in the fix
+    pub satellites: u8,
+    /// number of satellites in view
+    pub satellites_in_view: u8,
+    /// horizontal dilution of precision
+    pub hdop: Option<f32>,
+    /// altitude above mean sea level in metres
+    pub altitude: Option<f32>,
+    /// speed over ground in m/s
+    pub speed: Option<f32>,
+    /// course over ground in degrees
+    pub course: Option<f32>,
+    /// when we received the fix from the receiver
+    pub received: Instant,
+}
+
+impl GpsFix {
+    pub fn new(lat: f64, lon: f64) -> Self {
+        GpsFix {
+            time: None,
+            date: None,
+            lat: lat,
+            lon: lon,
+            fix_type: FixType::NoFix,
+            satellites: 0,
+            satellites_in_view: 0,
+            hdop: None,
+            altitude: None,
+            speed: None,
+            course: None,
+            received: Instant::now(),
+        }
+    }
+
+    pub fn location(&self) -> Location {
+        Location::new(self.lat, self.lon)
+    }
+
+    /// the receiver's UTC date and time for the fix, if it has told us both
+    pub fn utc(&self) -> Option<DateTime<UTC>> {
+        match (self.date, self.time) {
+            (Some(d), Some(t)) => Some(DateTime::from_utc(NaiveDateTime::new(d, t), UTC)),
+            _ => None,
+        }
+    }
+
+    /// how long ago we received the fix
+    pub fn age(&self) -> Duration {
+        self.received.elapsed()
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum GpsProtocol {
+    Nmea,
+    /// u-blox binary protocol
+    Ubx,
+    /// JSON reports from a gpsd server rather than reading the device directly
+    Gpsd,
+}
+
+/// How to talk to the receiver
+#[derive(Debug, Clone)]
+pub struct GpsConfig {
+    pub protocol: GpsProtocol,
+    pub baud_rate: usize,
+    /// the fix is discarded if the receiver has not reported a position for this long
+    pub stale_timeout: Duration,
+    /// measurement rate in Hz to configure, from 1 to 25 (UBX only)
+    pub rate_hz: Option<u32>,
+    /// dynamic model to configure (UBX only)
+    pub dynamic_model: Option<DynamicModel>,
+    /// host:port of the gpsd server (gpsd only)
+    pub gpsd_address: String,
+}
+
+impl GpsConfig {
+    pub fn new() -> Self {
+        GpsConfig {
+            protocol: GpsProtocol::Nmea,
+            baud_rate: 57600,
+            stale_timeout: Duration::from_secs(2),
+            rate_hz: None,
+            dynamic_model: None,
+            gpsd_address: String::from("127.0.0.1:2947"),
+        }
+    }
+}
+
+/// Status of the connection to the receiver
+#[derive(Debug, Clone)]
+pub struct GpsHealth {
+    /// is the serial port currently open?
+    pub connected: bool,
+    /// when the last complete sentence was received
+    pub last_sentence: Option<Instant>,
+    /// number of sentences received
+    pub sentences: u64,
+    /// number of sentences that could not be parsed
+    pub parse_errors: u64,
+    /// number of times the serial port has been reopened
+    pub reconnects: u64,
+}
+
+/// state shared between the reader thread and the GPS handle
+struct Shared {
+    fix: Option<GpsFix>,
+    health: GpsHealth,
+}
+
+/// turns the bytes from the receiver into fixes
+enum Decoder {
+    Nmea { parser: NmeaParser, buf: Vec<char> },
+    Ubx { reader: FrameReader, parser: UbxParser },
+    Gpsd { parser: GpsdParser, buf: Vec<u8> },
+}
+
+impl Decoder {
+    fn new(protocol: GpsProtocol) -> Self {
+        match protocol {
+            GpsProtocol::Nmea => Decoder::Nmea { parser: NmeaParser::new(), buf: vec![] },
+            GpsProtocol::Ubx => Decoder::Ubx { reader: FrameReader::new(), parser: UbxParser::new() },
+            GpsProtocol::Gpsd => Decoder::Gpsd { parser: GpsdParser::new(), buf: vec![] },
+        }
+    }
+
+    /// Add a byte to the stream. Returns the result of parsing a message once a complete
+    /// sentence or frame has been read.
+    fn push(&mut self, b: u8) -> Option<Result<Option<GpsFix>, String>> {
+        match *self {
+            Decoder::Nmea { ref mut parser, ref mut buf } => {
+                let ch = b as char;
+                if ch == '\n' {
+                    let sentence : String = buf.iter().cloned().collect();
+                    // println!("NMEA: {}", sentence);
+                    buf.clear();
+                    Some(parser.parse_line(&sentence))
+                } else {
+                    buf.push(ch);
+                    None
+                }
+            }
+            Decoder::Ubx { ref mut reader, ref mut parser } => {
+                reader.push(b).map(|frame| frame.and_then(|f| parser.parse_frame(&f)))
+            }
+            Decoder::Gpsd { ref mut parser, ref mut buf } => {
+                if b == b'\n' {
+                    let line = String::from_utf8_lossy(buf).into_owned();
+                    buf.clear();
+                    Some(parser.parse_line(&line))
+                } else {
+                    buf.push(b);
+                    None
+                }
+            }
+        }
+    }
+
+    /// discard any partial message, e.g. after reconnecting
+    fn reset(&mut self) {
+        match *self {
+            Decoder::Nmea { ref mut buf, .. } => buf.clear(),
+            Decoder::Ubx { ref mut reader, .. } => *reader = FrameReader::new(),
+            Decoder::Gpsd { ref mut buf, .. } => buf.clear(),
+        }
+    }
+}
 
 pub struct GPS {
     filename: &'static str,
-    location: Arc<Mutex<Option<Location>>>,
+    config: GpsConfig,
+    shared: Arc<Mutex<Shared>>,
 }
 
 impl GPS {
-    pub fn new(f: &'static str) -> Self {
+    pub fn new(f: &'static str, config: GpsConfig) -> Self {
         GPS {
             filename: f,
-            location: Arc::new(Mutex::new(None)),
+            config: config,
+            shared: Arc::new(Mutex::new(Shared {
+                fix: None,
+                health: GpsHealth {
+                    connected: false,
+                    last_sentence: None,
+                    sentences: 0,
+                    parse_errors: 0,
+                    reconnects: 0,
+                },
+            })),
         }
     }
 
     pub fn start_thread(&self) {
 
-        let f = self.filename.clone();
-        let gps_location = self.location.clone();
+        let f = self.filename;
+        let config = self.config.clone();
+        let shared = self.shared.clone();
 
-        let mut port = serial::open(f).unwrap();
+        // start thread to read from the receiver, reconnecting whenever it fails
+        let _ = thread::spawn(move || {
 
-        port.reconfigure(&|settings| {
-                settings.set_baud_rate(serial::Baud57600).unwrap();
-                settings.set_char_size(serial::Bits8);
-                settings.set_parity(serial::ParityNone);
-                settings.set_stop_bits(serial::Stop1);
-                settings.set_flow_control(serial::FlowNone);
-                Ok(())
-            })
-            .unwrap();
+            let mut decoder = Decoder::new(config.protocol);
+            let reconnecting = || shared.lock().unwrap().health.reconnects += 1;
+            keep_connected(reconnecting, || {
+                let mut port = match connect(f, &config) {
+                    Ok(p) => p,
+                    Err(e) => {
+                        println!("Failed to connect to GPS: {}", e);
+                        return;
+                    }
+                };
 
-        port.set_timeout(Duration::from_millis(5000)).unwrap();
+                {
+                    let mut s = shared.lock().unwrap();
+                    s.health.connected = true;
+                }
 
-        // start thread to read from serial port
-        let _ = thread::spawn(move || {
+                decoder.reset();
+                let mut read_buf = vec![0_u8; 1024];
+                let mut timeouts = 0;
+                loop {
+                    let n = match port.read(&mut read_buf[..]) {
+                        Ok(0) => {
+                            println!("GPS connection closed");
+                            break;
+                        }
+                        Ok(n) => n,
+                        Err(ref e) if e.kind() == io::ErrorKind::TimedOut
+                                   || e.kind() == io::ErrorKind::WouldBlock => {
+                            timeouts += 1;
+                            println!("Timed out reading from GPS ({} in a row)", timeouts);
+                            if timeouts >= MAX_READ_TIMEOUTS {
+                                break;
+                            }
+                            continue;
+                        }
+                        Err(e) => {
+                            println!("Failed to read from GPS: {}", e);
+                            break;
+                        }
+                    };
+                    timeouts = 0;
+                    for i in 0..n {
+                        if let Some(result) = decoder.push(read_buf[i]) {
+                            let mut s = shared.lock().unwrap();
+                            s.health.last_sentence = Some(Instant::now());
+                            s.health.sentences += 1;
 
-            let mut buf: Vec<char> = vec![];
-            let mut read_buf = vec![0_u8; 1024];
-            let mut last_lat = 0.0;
-            let mut last_lon = 0.0;
-            loop {
-                let n = port.read(&mut read_buf[..]).unwrap();
-                for i in 0..n {
-                    let ch = read_buf[i] as char;
-                    if ch == '\n' {
-                        let sentence : String = buf.iter().cloned().collect();
-                        // println!("NMEA: {}", sentence);
-
-                        let parts: Vec<&str> = sentence.split(",").collect();
-
-                        match parts[0] {
-                            "$GPGLL" => {
-
-                                let lat = parts[1];     // ddmm.mmmm
-                                let lat_ns = parts[2];  // N or S
-                                let lon = parts[3];     // ddmm.mmmm
-                                let lon_ew = parts[4];  // E or W
-                                let _ = parts[5];    // hhmmss.sss
-                                let av = parts[6];  // A=valid, V=not valid
-                                // println!("{} {}, {} {}", lat, lat_ns, lon, lon_ew);
-
-                                if av == "A" {
-                                    match Location::parse_nmea(lat, lat_ns, lon, lon_ew) {
-                                        Ok(x) => {
-                                            if (last_lat-x.lat).abs() > 0.0000001
-                                            || (last_lon-x.lon).abs() > 0.0000001 {
-                                                let mut loc = gps_location.lock().unwrap();
-                                                *loc = Some(Location::new(x.lat, x.lon));
-                                                last_lat = x.lat;
-                                                last_lon = x.lon;
-                                            }
-                                        },
-                                        Err(e) => println!("Failed to parse GPS: {}", e)
-                                    }
+                            match result {
+                                Ok(Some(x)) => s.fix = Some(x),
+                                Ok(None) => {},
+                                Err(e) => {
+                                    s.health.parse_errors += 1;
+                                    println!("Failed to parse GPS: {}", e)
                                 }
                             }
-                            _ => {}
                         }
-
-                        buf.clear();
-                    } else {
-                        buf.push(ch);
                     }
                 }
-            }
+
+                let mut s = shared.lock().unwrap();
+                s.health.connected = false;
+            });
         });
     }
 
     pub fn get(&self) -> Option<Location> {
-        let loc = self.location.lock().unwrap();
-        match *loc {
-            Some(Location { lat, lon }) => Some(Location::new(lat,lon)),
-            None => None
+        self.get_fix().map(|f| f.location())
+    }
+
+    /// the latest fix, or None if there is no fix or it is older than the stale timeout
+    pub fn get_fix(&self) -> Option<GpsFix> {
+        let s = self.shared.lock().unwrap();
+        match s.fix {
+            Some(ref fix) if fix.age() < self.config.stale_timeout => Some(fix.clone()),
+            _ => None,
         }
     }
+
+    /// how long ago the latest fix was received, even if it is stale
+    pub fn fix_age(&self) -> Option<Duration> {
+        let s = self.shared.lock().unwrap();
+        s.fix.as_ref().map(|fix| fix.age())
+    }
+
+    pub fn health(&self) -> GpsHealth {
+        let s = self.shared.lock().unwrap();
+        s.health.clone()
+    }
+}
+
+/// Open the serial port (configuring a u-blox receiver if necessary) or connect to gpsd
+fn connect(f: &str, config: &GpsConfig) -> io::Result<Box<dyn Read>> {
+    match config.protocol {
+        GpsProtocol::Gpsd => {
+            let mut stream = TcpStream::connect(&config.gpsd_address[..])?;
+            stream.set_read_timeout(Some(Duration::from_millis(5000)))?;
+            stream.write_all(gpsd::WATCH.as_bytes())?;
+            Ok(Box::new(stream))
+        }
+        GpsProtocol::Nmea | GpsProtocol::Ubx => {
+            let mut port = open_port(f, config.baud_rate)?;
+            if config.protocol == GpsProtocol::Ubx {
+                configure_ubx(&mut port, config)?;
+            }
+            Ok(Box::new(port))
+        }
+    }
+}
+
+fn open_port(f: &str, baud_rate: usize) -> serial::Result<serial::SystemPort> {
+    let mut port = serial::open(f)?;
+
+    port.reconfigure(&|settings| {
+            settings.set_baud_rate(serial::BaudRate::from_speed(baud_rate))?;
+            settings.set_char_size(serial::Bits8);
+            settings.set_parity(serial::ParityNone);
+            settings.set_stop_bits(serial::Stop1);
+            settings.set_flow_control(serial::FlowNone);
+            Ok(())
+        })?;
+
+    port.set_timeout(Duration::from_millis(5000))?;
+
+    Ok(port)
+}
+
+/// Send CFG messages to a u-blox receiver to set the measurement rate and dynamic model, and
+/// to switch from NMEA output to NAV-PVT and NAV-DOP messages
+fn configure_ubx(port: &mut serial::SystemPort, config: &GpsConfig) -> io::Result<()> {
+    let mut frames = vec![];
+    if let Some(rate_hz) = config.rate_hz {
+        frames.push(ubx::cfg_rate(rate_hz));
+    }
+    if let Some(model) = config.dynamic_model {
+        frames.push(ubx::cfg_nav5(model));
+    }
+    frames.push(ubx::cfg_msg(ubx::CLASS_NAV, ubx::NAV_PVT, 1));
+    frames.push(ubx::cfg_msg(ubx::CLASS_NAV, ubx::NAV_DOP, 1));
+    // GGA, GLL, GSA, GSV, RMC, VTG
+    for id in 0..6 {
+        frames.push(ubx::cfg_msg(ubx::CLASS_NMEA, id, 0));
+    }
+    port.set_timeout(Duration::from_millis(UBX_ACK_TIMEOUT_MS))?;
+    for frame in frames {
+        port.write_all(&frame.encode())?;
+        port.flush()?;
+        match wait_for_ack(port, &frame) {
+            Some(true) => {}
+            Some(false) => println!("GPS rejected UBX message {:02X}/{:02X}", frame.class, frame.id),
+            None => println!("GPS did not acknowledge UBX message {:02X}/{:02X}", frame.class, frame.id),
+        }
+    }
+    port.set_timeout(Duration::from_millis(5000))?;
+    Ok(())
+}
+
+/// Read from the receiver until it acknowledges the frame that was sent. Returns whether it
+/// accepted the frame, or None if it didn't answer in time.
+fn wait_for_ack<R: Read>(port: &mut R, sent: &ubx::Frame) -> Option<bool> {
+    let started = Instant::now();
+    let mut reader = FrameReader::new();
+    let mut buf = [0_u8; 256];
+    while started.elapsed() < Duration::from_millis(UBX_ACK_TIMEOUT_MS) {
+        let n = match port.read(&mut buf) {
+            Ok(0) | Err(_) => return None,
+            Ok(n) => n,
+        };
+        for &b in buf[..n].iter() {
+            if let Some(Ok(frame)) = reader.push(b) {
+                if let Some(accepted) = frame.acknowledges(sent) {
+                    return Some(accepted);
+                }
+            }
+        }
+    }
+    None
+}
+
+impl PositionSource for GPS {
+    fn fix(&self) -> Option<GpsFix> {
+        self.get_fix()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::io::Cursor;
+
+    #[test]
+    fn waits_for_the_matching_ack() {
+        let sent = ubx::cfg_rate(5);
+        let mut bytes = b"$GPGGA,,,*7A\r\n".to_vec();
+        // an ACK for an earlier message, then the one we're waiting for
+        bytes.extend(ubx::Frame::new(ubx::CLASS_ACK, ubx::ACK_ACK, vec![ubx::CLASS_CFG, ubx::CFG_MSG]).encode());
+        bytes.extend(ubx::Frame::new(ubx::CLASS_ACK, ubx::ACK_NAK, vec![ubx::CLASS_CFG, ubx::CFG_RATE]).encode());
+        assert_eq!(wait_for_ack(&mut Cursor::new(bytes), &sent), Some(false));
+
+        let ack = ubx::Frame::new(ubx::CLASS_ACK, ubx::ACK_ACK, vec![ubx::CLASS_CFG, ubx::CFG_RATE]).encode();
+        assert_eq!(wait_for_ack(&mut Cursor::new(ack), &sent), Some(true));
+
+        // the receiver went quiet
+        assert_eq!(wait_for_ack(&mut Cursor::new(b"$GPGGA,,,*7A\r\n".to_vec()), &sent), None);
+    }
 }
@@ -0,0 +1,403 @@
+use chrono::{NaiveDate, NaiveTime};
+
+use super::gps::{FixType, GpsFix};
+
+use std::iter::Peekable;
+use std::str::Chars;
+
+/// the command that asks gpsd to stream JSON reports
+pub const WATCH: &str = "?WATCH={\"enable\":true,\"json\":true};\n";
+
+/// Just enough JSON to read gpsd reports
+#[derive(Debug, Clone, PartialEq)]
+pub enum Json {
+    Null,
+    Bool(bool),
+    Number(f64),
+    String(String),
+    Array(Vec<Json>),
+    Object(Vec<(String, Json)>),
+}
+
+impl Json {
+    pub fn parse(s: &str) -> Result<Json, String> {
+        let mut chars = s.chars().peekable();
+        let value = parse_value(&mut chars)?;
+        skip_whitespace(&mut chars);
+        match chars.next() {
+            None => Ok(value),
+            Some(c) => Err(format!("Unexpected '{}' after JSON value", c)),
+        }
+    }
+
+    pub fn get(&self, key: &str) -> Option<&Json> {
+        match *self {
+            Json::Object(ref fields) => fields.iter().find(|f| f.0 == key).map(|f| &f.1),
+            _ => None,
+        }
+    }
+
+    pub fn as_f64(&self) -> Option<f64> {
+        match *self {
+            Json::Number(n) => Some(n),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(&self) -> Option<&str> {
+        match *self {
+            Json::String(ref s) => Some(s),
+            _ => None,
+        }
+    }
+
+    pub fn as_bool(&self) -> Option<bool> {
+        match *self {
+            Json::Bool(b) => Some(b),
+            _ => None,
+        }
+    }
+
+    pub fn as_vec(&self) -> Option<&Vec<Json>> {
+        match *self {
+            Json::Array(ref v) => Some(v),
+            _ => None,
+        }
+    }
+}
+
+fn skip_whitespace(chars: &mut Peekable<Chars>) {
+    while let Some(&c) = chars.peek() {
+        if !c.is_whitespace() {
+            break;
+        }
+        chars.next();
+    }
+}
+
+fn expect(chars: &mut Peekable<Chars>, expected: char) -> Result<(), String> {
+    skip_whitespace(chars);
+    match chars.next() {
+        Some(c) if c == expected => Ok(()),
+        Some(c) => Err(format!("Expected '{}' but found '{}'", expected, c)),
+        None => Err(format!("Expected '{}' but found end of input", expected)),
+    }
+}
+
+fn parse_value(chars: &mut Peekable<Chars>) -> Result<Json, String> {
+    skip_whitespace(chars);
+    match chars.peek().cloned() {
+        Some('{') => {
+            chars.next();
+            let mut fields = vec![];
+            skip_whitespace(chars);
+            if chars.peek() == Some(&'}') {
+                chars.next();
+                return Ok(Json::Object(fields));
+            }
+            loop {
+                skip_whitespace(chars);
+                let key = parse_string(chars)?;
+                expect(chars, ':')?;
+                fields.push((key, parse_value(chars)?));
+                skip_whitespace(chars);
+                match chars.next() {
+                    Some(',') => {}
+                    Some('}') => return Ok(Json::Object(fields)),
+                    _ => return Err(String::from("Expected ',' or '}' in object")),
+                }
+            }
+        }
+        Some('[') => {
+            chars.next();
+            let mut values = vec![];
+            skip_whitespace(chars);
+            if chars.peek() == Some(&']') {
+                chars.next();
+                return Ok(Json::Array(values));
+            }
+            loop {
+                values.push(parse_value(chars)?);
+                skip_whitespace(chars);
+                match chars.next() {
+                    Some(',') => {}
+                    Some(']') => return Ok(Json::Array(values)),
+                    _ => return Err(String::from("Expected ',' or ']' in array")),
+                }
+            }
+        }
+        Some('"') => Ok(Json::String(parse_string(chars)?)),
+        Some(c) if c == '-' || c.is_ascii_digit() => {
+            let mut s = String::new();
+            while let Some(&c) = chars.peek() {
+                if c.is_ascii_digit() || c == '-' || c == '+' || c == '.' || c == 'e' || c == 'E' {
+                    s.push(c);
+                    chars.next();
+                } else {
+                    break;
+                }
+            }
+            s.parse::<f64>().map(Json::Number).map_err(|_| format!("Invalid number: {}", s))
+        }
+        Some(_) => {
+            let mut s = String::new();
+            while let Some(&c) = chars.peek() {
+                if !c.is_alphabetic() {
+                    break;
+                }
+                s.push(c);
+                chars.next();
+            }
+            match s.as_ref() {
+                "true" => Ok(Json::Bool(true)),
+                "false" => Ok(Json::Bool(false)),
+                "null" => Ok(Json::Null),
+                _ => Err(format!("Invalid JSON value: {}", s)),
+            }
+        }
+        None => Err(String::from("Unexpected end of input")),
+    }
+}
+
+fn parse_string(chars: &mut Peekable<Chars>) -> Result<String, String> {
+    expect(chars, '"')?;
+    let mut s = String::new();
+    loop {
+        match chars.next() {
+            Some('"') => return Ok(s),
+            Some('\\') => {
+                match chars.next() {
+                    Some('n') => s.push('\n'),
+                    Some('t') => s.push('\t'),
+                    Some('r') => s.push('\r'),
+                    Some('u') => {
+                        let hex: String = chars.by_ref().take(4).collect();
+                        match u32::from_str_radix(&hex, 16).ok().and_then(::std::char::from_u32) {
+                            Some(c) => s.push(c),
+                            None => return Err(format!("Invalid unicode escape: {}", hex)),
+                        }
+                    }
+                    Some(c) => s.push(c),
+                    None => return Err(String::from("Unterminated string")),
+                }
+            }
+            Some(c) => s.push(c),
+            None => return Err(String::from("Unterminated string")),
+        }
+    }
+}
+
+/// Combines gpsd TPV (position) and SKY (satellite) reports into a single fix
+pub struct GpsdParser {
+    satellites: u8,
+    satellites_in_view: u8,
+    hdop: Option<f32>,
+}
+
+impl GpsdParser {
+    pub fn new() -> Self {
+        GpsdParser {
+            satellites: 0,
+            satellites_in_view: 0,
+            hdop: None,
+        }
+    }
+
+    /// Parse a report. Returns the updated fix if the report contained a valid position.
+    pub fn parse_line(&mut self, line: &str) -> Result<Option<GpsFix>, String> {
+        let report = Json::parse(line)?;
+        match report.get("class").and_then(|c| c.as_str()) {
+            Some("TPV") => Ok(self.parse_tpv(&report)),
+            Some("SKY") => {
+                self.parse_sky(&report);
+                Ok(None)
+            }
+            Some(_) => Ok(None),
+            None => Err(format!("gpsd report has no class: {}", line)),
+        }
+    }
+
+    fn parse_tpv(&self, report: &Json) -> Option<GpsFix> {
+        let fix_type = match report.get("mode").and_then(|m| m.as_f64()).map(|m| m as i64) {
+            Some(2) => FixType::Fix2D,
+            Some(3) => FixType::Fix3D,
+            _ => return None,
+        };
+        let (lat, lon) = match (report.get("lat").and_then(|n| n.as_f64()),
+                                report.get("lon").and_then(|n| n.as_f64())) {
+            (Some(lat), Some(lon)) => (lat, lon),
+            _ => return None,
+        };
+
+        let f32_field = |key: &str| report.get(key).and_then(|n| n.as_f64()).map(|n| n as f32);
+
+        let mut fix = GpsFix::new(lat, lon);
+        fix.fix_type = fix_type;
+        fix.satellites = self.satellites;
+        fix.satellites_in_view = self.satellites_in_view;
+        fix.hdop = self.hdop;
+        fix.altitude = f32_field("altMSL").or(f32_field("alt"));
+        fix.speed = f32_field("speed");
+        fix.course = f32_field("track");
+        if let Some((date, time)) = report.get("time").and_then(|t| t.as_str()).and_then(parse_time) {
+            fix.date = Some(date);
+            fix.time = Some(time);
+        }
+        Some(fix)
+    }
+
+    fn parse_sky(&mut self, report: &Json) {
+        if let Some(hdop) = report.get("hdop").and_then(|n| n.as_f64()) {
+            self.hdop = Some(hdop as f32);
+        }
+        if let Some(satellites) = report.get("satellites").and_then(|s| s.as_vec()) {
+            self.satellites_in_view = satellites.len() as u8;
+            self.satellites = satellites.iter()
+                .filter(|s| s.get("used").and_then(|u| u.as_bool()) == Some(true))
+                .count() as u8;
+        }
+    }
+}
+
+/// parse an ISO 8601 time such as 2017-04-22T18:42:08.000Z
+fn parse_time(s: &str) -> Option<(NaiveDate, NaiveTime)> {
+    let parts: Vec<&str> = s.trim_end_matches('Z').split('T').collect();
+    if parts.len() != 2 {
+        return None;
+    }
+    let d: Vec<&str> = parts[0].split('-').collect();
+    let t: Vec<&str> = parts[1].split(':').collect();
+    if d.len() != 3 || t.len() != 3 {
+        return None;
+    }
+    let date = match (d[0].parse::<i32>(), d[1].parse::<u32>(), d[2].parse::<u32>()) {
+        (Ok(y), Ok(m), Ok(d)) => NaiveDate::from_ymd_opt(y, m, d),
+        _ => None,
+    };
+    let time = match (t[0].parse::<u32>(), t[1].parse::<u32>(), t[2].parse::<f64>()) {
+        (Ok(h), Ok(m), Ok(sec)) => {
+            let milli = ((sec - sec.floor()) * 1000_f64).round() as u32;
+            NaiveTime::from_hms_milli_opt(h, m, sec.floor() as u32, milli)
+        }
+        _ => None,
+    };
+    match (date, time) {
+        (Some(d), Some(t)) => Some((d, t)),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::gps::{GpsConfig, GpsProtocol, GPS};
+
+    use std::io::{BufRead, BufReader, Write};
+    use std::net::TcpListener;
+    use std::thread;
+    use std::time::{Duration, Instant};
+
+    const VERSION: &str = "{\"class\":\"VERSION\",\"release\":\"3.17\",\"rev\":\"3.17\",\"proto_major\":3,\"proto_minor\":12}";
+    const SKY: &str = "{\"class\":\"SKY\",\"device\":\"/dev/ttyUSB0\",\"hdop\":0.9,\"satellites\":[{\"PRN\":5,\"el\":45,\"az\":90,\"ss\":40,\"used\":true},{\"PRN\":7,\"el\":10,\"az\":200,\"ss\":20,\"used\":false},{\"PRN\":13,\"el\":60,\"az\":300,\"ss\":42,\"used\":true}]}";
+    const TPV: &str = "{\"class\":\"TPV\",\"device\":\"/dev/ttyUSB0\",\"mode\":3,\"time\":\"2017-04-22T18:42:08.500Z\",\"lat\":39.950380,\"lon\":-105.060512,\"altMSL\":1650.2,\"speed\":1.25,\"track\":271.5}";
+    const TPV_2D: &str = "{\"class\":\"TPV\",\"mode\":2,\"lat\":39.950400,\"lon\":-105.060500}";
+    const TPV_NO_FIX: &str = "{\"class\":\"TPV\",\"mode\":1}";
+
+    #[test]
+    fn parses_reports() {
+        let mut parser = GpsdParser::new();
+        assert!(parser.parse_line(VERSION).unwrap().is_none());
+        assert!(parser.parse_line(SKY).unwrap().is_none());
+
+        let fix = parser.parse_line(TPV).unwrap().unwrap();
+        assert_eq!(fix.lat, 39.950380);
+        assert_eq!(fix.lon, -105.060512);
+        assert_eq!(fix.fix_type, FixType::Fix3D);
+        assert_eq!(fix.hdop, Some(0.9));
+        assert_eq!(fix.satellites, 2);
+        assert_eq!(fix.satellites_in_view, 3);
+        assert_eq!(fix.altitude, Some(1650.2));
+        assert_eq!(fix.speed, Some(1.25));
+        assert_eq!(fix.course, Some(271.5));
+        assert_eq!(fix.date, NaiveDate::from_ymd_opt(2017, 4, 22));
+        assert_eq!(fix.time, NaiveTime::from_hms_milli_opt(18, 42, 8, 500));
+
+        assert_eq!(parser.parse_line(TPV_2D).unwrap().unwrap().fix_type, FixType::Fix2D);
+        assert!(parser.parse_line(TPV_NO_FIX).unwrap().is_none());
+        assert!(parser.parse_line("{\"lat\":1}").is_err());
+        assert!(parser.parse_line("{\"class\":\"TPV\"").is_err());
+    }
+
+    #[test]
+    fn parses_json() {
+        let json = Json::parse(" {\"a\": [1, -2.5e1, true, null], \"b\": \"x\\\"y\\u0041\"} ").unwrap();
+        assert_eq!(json.get("a"), Some(&Json::Array(vec![Json::Number(1_f64),
+                                                          Json::Number(-25_f64),
+                                                          Json::Bool(true),
+                                                          Json::Null])));
+        assert_eq!(json.get("b").and_then(|b| b.as_str()), Some("x\"yA"));
+        assert!(Json::parse("{\"a\": 1} x").is_err());
+        assert!(Json::parse("[1, 2").is_err());
+    }
+
+    /// replay canned reports to each client that connects, dropping the first connection
+    /// after one fix so that the client has to reconnect
+    fn serve(listener: TcpListener, lats: Vec<f64>) {
+        thread::spawn(move || {
+            for lat in lats {
+                let (stream, _) = listener.accept().unwrap();
+                let mut reader = BufReader::new(stream.try_clone().unwrap());
+                let mut watch = String::new();
+                reader.read_line(&mut watch).unwrap();
+                assert_eq!(watch, WATCH);
+
+                let mut stream = stream;
+                let tpv = TPV.replace("39.950380", &format!("{:.6}", lat));
+                for line in [VERSION, SKY, &tpv[..]].iter() {
+                    stream.write_all(line.as_bytes()).unwrap();
+                    stream.write_all(b"\n").unwrap();
+                }
+                thread::sleep(Duration::from_millis(200));
+            }
+        });
+    }
+
+    #[test]
+    fn reads_from_server_and_reconnects() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut config = GpsConfig::new();
+        config.protocol = GpsProtocol::Gpsd;
+        config.gpsd_address = listener.local_addr().unwrap().to_string();
+        config.stale_timeout = Duration::from_secs(10);
+        serve(listener, vec![39.950380, 39.951000]);
+
+        let gps = GPS::new("", config);
+        gps.start_thread();
+
+        let wait_for = |lat: f64| {
+            let start = Instant::now();
+            while start.elapsed() < Duration::from_secs(5) {
+                if let Some(fix) = gps.get_fix() {
+                    if fix.lat == lat {
+                        return fix;
+                    }
+                }
+                thread::sleep(Duration::from_millis(10));
+            }
+            panic!("no fix at {} from the server", lat);
+        };
+
+        let fix = wait_for(39.950380);
+        assert_eq!(fix.lon, -105.060512);
+        assert_eq!(fix.fix_type, FixType::Fix3D);
+        assert_eq!(fix.hdop, Some(0.9));
+        assert_eq!(fix.speed, Some(1.25));
+        assert_eq!(fix.course, Some(271.5));
+
+        // the server drops the connection, so the second fix only arrives after reconnecting
+        wait_for(39.951000);
+        let health = gps.health();
+        assert!(health.reconnects >= 1);
+        assert_eq!(health.parse_errors, 0);
+    }
+}
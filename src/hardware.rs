@@ -0,0 +1,85 @@
+use std::thread;
+use std::time::{Duration, Instant};
+
+use super::gps::GpsFix;
+use super::motors::Motion;
+use super::odometry::Odometry;
+use super::scan::Scan;
+use super::ultrasonic::UltrasonicRanges;
+
+/// how long to wait before reopening a device that failed
+const RECONNECT_DELAY_MS: u64 = 1000;
+
+/// Provides the current GPS fix, or None if there is no fix
+pub trait PositionSource {
+    fn fix(&self) -> Option<GpsFix>;
+}
+
+/// Provides the current heading of the vehicle in degrees (0 = north, clockwise) and when it
+/// was measured
+pub trait HeadingSource {
+    fn heading(&mut self) -> Option<(f32, Instant)>;
+    /// set the magnetic declination in degrees (positive east). Sources that already
+    /// report true heading can ignore this.
+    fn set_declination(&mut self, _degrees: f32) {}
+}
+
+/// Provides distances (in cm) to the nearest object for each degree around the vehicle
+/// where 0 is straight ahead and angles increase clockwise
+pub trait RangeScanner {
+    /// minimum distance between the start angle (inclusive) and end angle (exclusive),
+    /// wrapping around through zero if start > end
+    fn min_distance(&self, start: usize, end: usize) -> u32;
+    /// the latest complete scan at full resolution
+    fn scan(&self) -> Option<Scan>;
+}
+
+/// Ultrasonic sensors around the front of the vehicle
+pub trait UltrasonicSensors {
+    /// latest distances, or None if the sensors aren't working
+    fn ranges(&self) -> Option<UltrasonicRanges>;
+}
+
+/// Measures the vehicle's movement relative to where it started, e.g. by matching LIDAR scans
+/// or counting wheel encoder ticks
+pub trait OdometrySource {
+    /// the total movement so far, or None if the source isn't working
+    fn odometry(&self) -> Option<Odometry>;
+}
+
+/// A vehicle without a particular odometry source
+impl<T: OdometrySource> OdometrySource for Option<T> {
+    fn odometry(&self) -> Option<Odometry> {
+        self.as_ref().and_then(|o| o.odometry())
+    }
+}
+
+/// Controls the left and right drive motors
+pub trait DriveActuator {
+    fn drive(&mut self, left: Motion, right: Motion);
+}
+
+/// The start/stop switch. Some(true) means start, Some(false) means stop and None
+/// means the state is not known yet
+pub trait StartSwitch {
+    fn state(&self) -> Option<bool>;
+}
+
+/// Run a device thread's session over and over so that the device is reopened whenever it
+/// can't be opened or fails, returning from `session`. Waits a second between sessions and
+/// calls `reconnecting` before each one after the first, e.g. to count the reconnects. Never
+/// returns.
+pub fn keep_connected<R, S>(mut reconnecting: R, mut session: S)
+    where R: FnMut(),
+          S: FnMut()
+{
+    let mut first_attempt = true;
+    loop {
+        if !first_attempt {
+            thread::sleep(Duration::from_millis(RECONNECT_DELAY_MS));
+            reconnecting();
+        }
+        first_attempt = false;
+        session();
+    }
+}
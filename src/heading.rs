@@ -0,0 +1,363 @@
+use super::compass::Compass;
+use super::hardware::HeadingSource;
+use super::imu::{ITG3200, ITG3200_ADDRESS};
+use super::maths::normalize_degrees;
+
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// how often the filter runs if no rate is configured
+pub const DEFAULT_RATE_HZ: u32 = 50;
+
+/// standard deviation of the gyro rate noise in degrees/s
+const GYRO_NOISE: f32 = 0.5;
+
+/// standard deviation of the random walk of the gyro bias in degrees/s per second
+const GYRO_BIAS_DRIFT: f32 = 0.05;
+
+/// standard deviation of the heading change per second without a gyro, in degrees
+const NO_GYRO_PROCESS_NOISE: f32 = 45.0;
+
+/// standard deviation of the magnetometer heading in degrees
+const COMPASS_NOISE: f32 = 5.0;
+
+/// compass readings further than this many standard deviations from the estimate are
+/// treated as magnetic disturbances and ignored
+const COMPASS_GATE: f32 = 4.0;
+
+/// accept the compass anyway after this many readings in a row have been ignored, since the
+/// estimate is more likely to be wrong than the compass
+const MAX_REJECTED_READINGS: u32 = 25;
+
+/// number of gyro readings averaged at startup to estimate the initial bias
+const GYRO_BIAS_SAMPLES: u32 = 100;
+
+/// the startup bias is rejected if the readings vary by more than this (standard deviation
+/// in degrees/s), which means the vehicle was moving, or if fewer than half could be read
+const MAX_GYRO_BIAS_SD: f32 = 1.0;
+
+/// the estimate is discarded if the filter hasn't run for this long
+const STALE_TIMEOUT_MS: u64 = 500;
+
+/// The fused heading along with how fast it is changing and how much we trust it
+#[derive(Debug, Clone)]
+pub struct HeadingEstimate {
+    /// true heading in degrees (0 = north, clockwise)
+    pub heading: f32,
+    /// rate of turn in degrees/s (clockwise positive), if there is a gyro
+    pub yaw_rate: Option<f32>,
+    /// standard deviation of the heading in degrees
+    pub uncertainty: f32,
+    /// estimated gyro bias in degrees/s
+    pub gyro_bias: f32,
+    /// pitch and roll in degrees, if there is an accelerometer
+    pub attitude: Option<(f32, f32)>,
+    /// when the estimate was last updated
+    pub updated: Instant,
+}
+
+/// Kalman filter over the magnetic heading and the gyro bias. The gyro drives the prediction
+/// and the compass corrects it.
+struct KalmanFilter {
+    /// magnetic heading in degrees
+    heading: f32,
+    /// gyro bias in degrees/s
+    bias: f32,
+    /// covariance
+    p: [[f32; 2]; 2],
+}
+
+impl KalmanFilter {
+    fn new(heading: f32, bias: f32) -> Self {
+        KalmanFilter {
+            heading: heading,
+            bias: bias,
+            p: [[COMPASS_NOISE * COMPASS_NOISE, 0_f32], [0_f32, 1_f32]],
+        }
+    }
+
+    /// advance the estimate by dt seconds using the gyro rate, if there is one
+    fn predict(&mut self, rate: Option<f32>, dt: f32) {
+        let p = self.p;
+        match rate {
+            Some(rate) => {
+                self.heading = normalize_degrees(self.heading + (rate - self.bias) * dt);
+                // F = [[1, -dt], [0, 1]]
+                let q0 = GYRO_NOISE * GYRO_NOISE * dt * dt;
+                let q1 = GYRO_BIAS_DRIFT * GYRO_BIAS_DRIFT * dt;
+                self.p = [[p[0][0] - dt * (p[0][1] + p[1][0]) + dt * dt * p[1][1] + q0,
+                           p[0][1] - dt * p[1][1]],
+                          [p[1][0] - dt * p[1][1],
+                           p[1][1] + q1]];
+            }
+            None => {
+                self.p[0][0] += NO_GYRO_PROCESS_NOISE * NO_GYRO_PROCESS_NOISE * dt;
+            }
+        }
+    }
+
+    /// correct the estimate with a compass heading. Returns false if the reading was
+    /// rejected as an outlier.
+    fn update(&mut self, heading: f32, force: bool) -> bool {
+        let p = self.p;
+        let innovation = bearing_diff(self.heading, heading);
+        let s = p[0][0] + COMPASS_NOISE * COMPASS_NOISE;
+        if !force && innovation.abs() > COMPASS_GATE * s.sqrt() {
+            return false;
+        }
+        let k = (p[0][0] / s, p[1][0] / s);
+        self.heading = normalize_degrees(self.heading + k.0 * innovation);
+        self.bias += k.1 * innovation;
+        self.p = [[(1_f32 - k.0) * p[0][0], (1_f32 - k.0) * p[0][1]],
+                  [p[1][0] - k.1 * p[0][0], p[1][1] - k.1 * p[0][1]]];
+        true
+    }
+}
+
+struct Shared {
+    /// estimate of the magnetic heading
+    estimate: Option<HeadingEstimate>,
+    /// magnetic declination in degrees, positive east
+    declination: f32,
+}
+
+/// Fuses the gyro and compass into a heading estimate on a background thread
+pub struct HeadingEstimator {
+    gyro_device: &'static str,
+    compass: Option<Compass>,
+    rate_hz: u32,
+    shared: Arc<Mutex<Shared>>,
+}
+
+impl HeadingEstimator {
+    /// The gyro is expected on the same bus as the compass. If it can't be found the
+    /// estimate is just a filtered compass heading.
+    pub fn new(gyro_device: &'static str, compass: Compass, rate_hz: u32) -> Self {
+        HeadingEstimator {
+            gyro_device: gyro_device,
+            compass: Some(compass),
+            rate_hz: rate_hz,
+            shared: Arc::new(Mutex::new(Shared {
+                estimate: None,
+                declination: 0_f32,
+            })),
+        }
+    }
+
+    /// start the compass sampling thread and the filter thread
+    pub fn start_thread(&mut self) {
+
+        let compass = self.compass.take().expect("heading estimator already started");
+        compass.start_thread();
+        let f = self.gyro_device;
+        let interval = Duration::from_millis(1000 / self.rate_hz.max(1) as u64);
+        let shared = self.shared.clone();
+
+        thread::spawn(move || {
+
+            let mut gyro = match ITG3200::new(f, ITG3200_ADDRESS) {
+                Ok(g) => Some(g),
+                Err(e) => {
+                    println!("WARNING: no gyro, heading will not be gyro stabilized ({})", e);
+                    None
+                }
+            };
+
+            // assume the vehicle is stationary at startup, otherwise leave it to the filter
+            // to estimate the bias from the compass
+            let initial_bias = match gyro {
+                Some(ref mut g) => {
+                    println!("Calibrating gyro bias from {} readings, keep the vehicle still",
+                             GYRO_BIAS_SAMPLES);
+                    let mut samples = vec![];
+                    for _ in 0..GYRO_BIAS_SAMPLES {
+                        match g.yaw_rate() {
+                            Ok(rate) => samples.push(rate),
+                            Err(e) => println!("Failed to read gyro: {}", e),
+                        }
+                        thread::sleep(interval);
+                    }
+                    match gyro_bias(&samples) {
+                        Ok(bias) => {
+                            println!("Initial gyro bias: {:.*} degrees/s", 3, bias);
+                            bias
+                        }
+                        Err(e) => {
+                            println!("WARNING: rejected gyro bias calibration, starting from zero \
+                                      ({})", e);
+                            0_f32
+                        }
+                    }
+                }
+                None => 0_f32,
+            };
+
+            let mut filter: Option<KalmanFilter> = None;
+            let mut rejected = 0;
+            let mut last_compass_update = None;
+            let mut last_step = Instant::now();
+
+            loop {
+                thread::sleep(interval);
+
+                let now = Instant::now();
+                let elapsed = now.duration_since(last_step);
+                let dt = elapsed.as_secs() as f32 + elapsed.subsec_nanos() as f32 / 1_000_000_000_f32;
+                last_step = now;
+
+                let rate = match gyro {
+                    Some(ref mut g) => match g.yaw_rate() {
+                        Ok(rate) => Some(rate),
+                        Err(e) => {
+                            println!("Failed to read gyro: {}", e);
+                            None
+                        }
+                    },
+                    None => None,
+                };
+
+                // only use each compass reading once
+                let reading = compass.reading();
+                let magnetic = match reading {
+                    Some(ref r) if Some(r.updated) != last_compass_update => {
+                        last_compass_update = Some(r.updated);
+                        Some(r.heading)
+                    }
+                    _ => None,
+                };
+
+                match filter {
+                    Some(ref mut kf) => {
+                        kf.predict(rate, dt);
+                        if let Some(h) = magnetic {
+                            if kf.update(h, rejected >= MAX_REJECTED_READINGS) {
+                                rejected = 0;
+                            } else {
+                                rejected += 1;
+                            }
+                        }
+                    }
+                    None => {
+                        // wait for the first compass reading to initialize the filter
+                        match magnetic {
+                            Some(h) => filter = Some(KalmanFilter::new(h, initial_bias)),
+                            None => continue,
+                        }
+                    }
+                }
+                let kf = filter.as_ref().unwrap();
+
+                let mut s = shared.lock().unwrap();
+                s.estimate = Some(HeadingEstimate {
+                    heading: kf.heading,
+                    yaw_rate: rate.map(|r| r - kf.bias),
+                    uncertainty: kf.p[0][0].sqrt(),
+                    gyro_bias: kf.bias,
+                    attitude: reading.and_then(|r| r.attitude),
+                    updated: now,
+                });
+            }
+        });
+    }
+
+    /// the latest estimate (of true heading), or None if the filter isn't running
+    pub fn get(&self) -> Option<HeadingEstimate> {
+        let s = self.shared.lock().unwrap();
+        match s.estimate {
+            Some(ref e) if e.updated.elapsed() < Duration::from_millis(STALE_TIMEOUT_MS) => {
+                let mut e = e.clone();
+                e.heading = normalize_degrees(e.heading + s.declination);
+                Some(e)
+            }
+            _ => None,
+        }
+    }
+}
+
+impl HeadingSource for HeadingEstimator {
+    fn heading(&mut self) -> Option<(f32, Instant)> {
+        self.get().map(|e| (e.heading, e.updated))
+    }
+
+    fn set_declination(&mut self, degrees: f32) {
+        let mut s = self.shared.lock().unwrap();
+        s.declination = degrees;
+    }
+}
+
+/// The mean of the gyro rates read while the vehicle is stationary, or an error if there
+/// are too few of them or they vary too much to trust
+fn gyro_bias(samples: &[f32]) -> Result<f32, String> {
+    if samples.len() * 2 < GYRO_BIAS_SAMPLES as usize {
+        return Err(format!("only {} of {} readings succeeded", samples.len(), GYRO_BIAS_SAMPLES));
+    }
+    let n = samples.len() as f32;
+    let mean = samples.iter().sum::<f32>() / n;
+    let sd = (samples.iter().map(|r| (r - mean) * (r - mean)).sum::<f32>() / n).sqrt();
+    if sd > MAX_GYRO_BIAS_SD {
+        return Err(format!("readings vary by {:.*} degrees/s, was the vehicle moving?", 2, sd));
+    }
+    Ok(mean)
+}
+
+/// signed difference from heading a to heading b in the range -180..180 degrees
+fn bearing_diff(a: f32, b: f32) -> f32 {
+    let d = normalize_degrees(b - a);
+    if d > 180_f32 { d - 360_f32 } else { d }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn averages_the_gyro_bias() {
+        let samples: Vec<f32> = (0..GYRO_BIAS_SAMPLES)
+            .map(|i| 0.3 + (i % 5) as f32 * 0.1)
+            .collect();
+        assert!((gyro_bias(&samples).unwrap() - 0.5).abs() < 1e-4);
+    }
+
+    #[test]
+    fn rejects_a_moving_or_missing_gyro() {
+        let turning: Vec<f32> = (0..GYRO_BIAS_SAMPLES).map(|i| i as f32 * 0.2).collect();
+        assert!(gyro_bias(&turning).is_err());
+        let few = vec![0.5_f32; GYRO_BIAS_SAMPLES as usize / 2 - 1];
+        assert!(gyro_bias(&few).is_err());
+        assert!(gyro_bias(&[]).is_err());
+    }
+
+    #[test]
+    fn estimates_the_gyro_bias_from_the_compass() {
+        // stationary, pointing east, with a gyro that reads 2 degrees/s too fast
+        let mut kf = KalmanFilter::new(90_f32, 0_f32);
+        let dt = 0.02;
+        for i in 0..(120_f32 / dt) as u32 {
+            kf.predict(Some(2_f32), dt);
+            // the compass is read every 5th step
+            if i % 5 == 0 {
+                kf.update(90_f32, false);
+            }
+        }
+        assert!((kf.bias - 2_f32).abs() < 0.1, "{}", kf.bias);
+        assert!(bearing_diff(kf.heading, 90_f32).abs() < 1_f32, "{}", kf.heading);
+    }
+
+    #[test]
+    fn follows_a_constant_turn() {
+        // turning clockwise at 10 degrees/s through north, with a biased gyro
+        let mut kf = KalmanFilter::new(300_f32, 0_f32);
+        let dt = 0.02;
+        let mut heading = 300_f32;
+        for i in 0..(120_f32 / dt) as u32 {
+            heading = normalize_degrees(heading + 10_f32 * dt);
+            kf.predict(Some(10_f32 + 1.5), dt);
+            if i % 5 == 0 {
+                kf.update(heading, false);
+            }
+        }
+        assert!((kf.bias - 1.5).abs() < 0.1, "{}", kf.bias);
+        assert!(bearing_diff(kf.heading, heading).abs() < 1_f32, "{} {}", kf.heading, heading);
+    }
+}
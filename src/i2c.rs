@@ -0,0 +1,35 @@
+extern crate libc;
+
+use std::fs::{File, OpenOptions};
+use std::io;
+use std::io::prelude::*;
+use std::os::unix::io::AsRawFd;
+
+/// ioctl to select the slave address (from linux/i2c-dev.h)
+const I2C_SLAVE: libc::c_ulong = 0x0703;
+
+/// A device on a Linux I2C bus such as /dev/i2c-1, accessed through the i2c-dev interface
+pub struct I2CDevice {
+    file: File,
+}
+
+impl I2CDevice {
+    pub fn open(f: &str, address: u16) -> io::Result<Self> {
+        let file = OpenOptions::new().read(true).write(true).open(f)?;
+        let ret = unsafe { libc::ioctl(file.as_raw_fd(), I2C_SLAVE, address as libc::c_ulong) };
+        if ret < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(I2CDevice { file: file })
+    }
+
+    pub fn write_register(&mut self, register: u8, value: u8) -> io::Result<()> {
+        self.file.write_all(&[register, value])
+    }
+
+    /// read consecutive registers starting at the given register
+    pub fn read_registers(&mut self, register: u8, buf: &mut [u8]) -> io::Result<()> {
+        self.file.write_all(&[register])?;
+        self.file.read_exact(buf)
+    }
+}
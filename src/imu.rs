@@ -0,0 +1,119 @@
+use super::i2c::I2CDevice;
+
+use std::io;
+
+/// default I2C address of the ADXL345 (ALT ADDRESS pin low)
+pub const ADXL345_ADDRESS: u16 = 0x53;
+
+const ADXL345_POWER_CTL: u8 = 0x2D;
+const ADXL345_DATA_FORMAT: u8 = 0x31;
+const ADXL345_DATAX0: u8 = 0x32;
+
+/// scale in full resolution mode (g per LSB)
+const ADXL345_SCALE: f32 = 0.0039;
+
+/// default I2C address of the ITG-3200 (AD0 pin low, 0x69 if it is high)
+pub const ITG3200_ADDRESS: u16 = 0x68;
+
+const ITG3200_DLPF_FS: u8 = 0x16;
+const ITG3200_GYRO_XOUT_H: u8 = 0x1D;
+const ITG3200_PWR_MGM: u8 = 0x3E;
+
+/// LSB per degree/second
+const ITG3200_SCALE: f32 = 14.375;
+
+/// ignore the accelerometer when the vehicle is accelerating hard or bouncing, since it no
+/// longer measures just gravity
+const MIN_GRAVITY: f32 = 0.7;
+const MAX_GRAVITY: f32 = 1.3;
+
+/// ADXL345 3-axis accelerometer
+pub struct ADXL345 {
+    dev: I2CDevice,
+}
+
+impl ADXL345 {
+    pub fn new(f: &str, address: u16) -> io::Result<Self> {
+        let mut dev = I2CDevice::open(f, address)?;
+        // full resolution, +/- 2g
+        dev.write_register(ADXL345_DATA_FORMAT, 0x08)?;
+        // start measuring
+        dev.write_register(ADXL345_POWER_CTL, 0x08)?;
+        Ok(ADXL345 { dev: dev })
+    }
+
+    /// read the acceleration in g
+    pub fn read(&mut self) -> io::Result<(f32, f32, f32)> {
+        let mut b = [0_u8; 6];
+        self.dev.read_registers(ADXL345_DATAX0, &mut b)?;
+        let x = (b[0] as u16 | (b[1] as u16) << 8) as i16;
+        let y = (b[2] as u16 | (b[3] as u16) << 8) as i16;
+        let z = (b[4] as u16 | (b[5] as u16) << 8) as i16;
+        Ok((x as f32 * ADXL345_SCALE, y as f32 * ADXL345_SCALE, z as f32 * ADXL345_SCALE))
+    }
+}
+
+/// ITG-3200 3-axis gyro
+pub struct ITG3200 {
+    dev: I2CDevice,
+}
+
+impl ITG3200 {
+    pub fn new(f: &str, address: u16) -> io::Result<Self> {
+        let mut dev = I2CDevice::open(f, address)?;
+        // use the x gyro as the clock reference
+        dev.write_register(ITG3200_PWR_MGM, 0x01)?;
+        // full scale range (+/- 2000 degrees/s) with a 42 Hz low pass filter
+        dev.write_register(ITG3200_DLPF_FS, 0x18 | 0x03)?;
+        Ok(ITG3200 { dev: dev })
+    }
+
+    /// read the rotation rate in degrees/s about each axis (counter clockwise positive)
+    pub fn read(&mut self) -> io::Result<(f32, f32, f32)> {
+        let mut b = [0_u8; 6];
+        self.dev.read_registers(ITG3200_GYRO_XOUT_H, &mut b)?;
+        let x = ((b[0] as u16) << 8 | b[1] as u16) as i16;
+        let y = ((b[2] as u16) << 8 | b[3] as u16) as i16;
+        let z = ((b[4] as u16) << 8 | b[5] as u16) as i16;
+        Ok((x as f32 / ITG3200_SCALE, y as f32 / ITG3200_SCALE, z as f32 / ITG3200_SCALE))
+    }
+
+    /// rate of change of heading in degrees/s (clockwise positive), assuming the z axis
+    /// points up
+    pub fn yaw_rate(&mut self) -> io::Result<f32> {
+        self.read().map(|(_, _, z)| -z)
+    }
+}
+
+/// Pitch (nose up positive) and roll (right side down positive) in degrees from an
+/// accelerometer reading taken while the vehicle isn't accelerating
+pub fn pitch_roll(accel: (f32, f32, f32)) -> (f32, f32) {
+    let (x, y, z) = accel;
+    let pitch = x.atan2((y * y + z * z).sqrt());
+    let roll = y.atan2(z);
+    (pitch.to_degrees(), roll.to_degrees())
+}
+
+/// Heading in radians (0 = magnetic north, clockwise) of the x axis, using the accelerometer
+/// to find which way is down so that the heading doesn't change when the vehicle tilts.
+/// Both readings must use the same axes as the compass: x forward, y left and z up. Returns
+/// None if the accelerometer isn't just measuring gravity.
+pub fn tilt_compensated_heading(mag: (f32, f32, f32), accel: (f32, f32, f32)) -> Option<f32> {
+    let g = (accel.0 * accel.0 + accel.1 * accel.1 + accel.2 * accel.2).sqrt();
+    if !(MIN_GRAVITY..=MAX_GRAVITY).contains(&g) {
+        return None;
+    }
+
+    // the accelerometer measures the reaction to gravity, which points up
+    let down = (-accel.0 / g, -accel.1 / g, -accel.2 / g);
+    // east is perpendicular to both down and the magnetic field, and north to east and down
+    let east = cross(down, mag);
+    let north = cross(east, down);
+    Some(east.0.atan2(north.0))
+}
+
+fn cross(a: (f32, f32, f32), b: (f32, f32, f32)) -> (f32, f32, f32) {
+    (a.1 * b.2 - a.2 * b.1,
+     a.2 * b.0 - a.0 * b.2,
+     a.0 * b.1 - a.1 * b.0)
+}
@@ -1,72 +1,250 @@
+use std::io;
+use std::io::prelude::*;
 use std::sync::{Arc, Mutex};
 use std::thread;
+use std::time::{Duration, Instant};
 
 extern crate libsweep;
 use self::libsweep::*;
 
+extern crate serial;
+use self::serial::prelude::*;
+
+use super::hardware::{keep_connected, RangeScanner};
+use super::perception::Sector;
+use super::scan::{Scan, ScanPoint};
+
+/// which way the sensor spins when viewed from above
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Rotation {
+    Clockwise,
+    CounterClockwise,
+}
+
+/// restart scanning after this many failed scans in a row
+const MAX_CONSECUTIVE_ERRORS: u32 = 3;
+
+/// weight given to the latest scan when smoothing the scan rate
+const SCAN_RATE_SMOOTHING: f32 = 0.2;
+
+/// how long to wait for the sensor to respond to a command sent over the serial port
+const COMMAND_TIMEOUT_MS: u64 = 1000;
+
+/// How the sensor is mounted on the vehicle and how it should scan
+#[derive(Debug, Clone)]
+pub struct LidarConfig {
+    /// angle in degrees, clockwise from straight ahead, that the sensor's zero angle points to
+    pub offset: usize,
+    pub rotation: Rotation,
+    /// vehicle angles to ignore, e.g. because the sensor can see part of the vehicle
+    pub mask: Option<Sector>,
+    /// rotation speed in Hz (1 to 10), or None to leave the sensor's setting alone
+    pub motor_speed: Option<i32>,
+    /// samples per second (500, 750 or 1000), or None to leave the sensor's setting alone
+    pub sample_rate: Option<i32>,
+}
+
+impl LidarConfig {
+    pub fn new() -> Self {
+        LidarConfig {
+            offset: 0,
+            rotation: Rotation::CounterClockwise,
+            mask: Some(Sector::new(135, 226)),
+            motor_speed: None,
+            sample_rate: None,
+        }
+    }
+
+    /// convert a sensor angle in degrees to a vehicle angle (0 = straight ahead, clockwise)
+    pub fn vehicle_angle(&self, angle: f32) -> f32 {
+        let angle = match self.rotation {
+            Rotation::Clockwise => self.offset as f32 + angle,
+            Rotation::CounterClockwise => self.offset as f32 - angle,
+        };
+        let angle = angle % 360_f32;
+        if angle < 0_f32 { angle + 360_f32 } else { angle }
+    }
+}
+
+/// Status of the sensor
+#[derive(Debug, Clone)]
+pub struct LidarHealth {
+    /// is the sensor currently scanning?
+    pub scanning: bool,
+    /// number of complete scans received
+    pub scans: u64,
+    /// number of failed scans
+    pub errors: u64,
+    /// number of times scanning has been restarted after errors
+    pub restarts: u64,
+    /// smoothed number of scans per second
+    pub scan_rate: f32,
+    /// when the last complete scan was received
+    pub last_scan: Option<Instant>,
+}
+
+/// state shared between the scanning thread and the LIDAR handle
+struct Shared {
+    /// nearest distance in each 1 degree bin, 0 until the first scan so nothing looks clear
+    points: [u32; 360],
+    scan: Option<Scan>,
+    health: LidarHealth,
+}
+
 pub struct Lidar {
-    points: Arc<Mutex<[u32; 360]>>
+    shared: Arc<Mutex<Shared>>,
 }
 
 impl Lidar {
 
-    pub fn new(port: String) -> Self {
+    pub fn new(port: String, config: LidarConfig) -> Self {
 
-        let lidar = Lidar { points: Arc::new(Mutex::new([0; 360])) };
+        let lidar = Lidar {
+            shared: Arc::new(Mutex::new(Shared {
+                points: [0; 360],
+                scan: None,
+                health: LidarHealth {
+                    scanning: false,
+                    scans: 0,
+                    errors: 0,
+                    restarts: 0,
+                    scan_rate: 0_f32,
+                    last_scan: None,
+                },
+            })),
+        };
 
-        let points_clone = lidar.points.clone();
+        let shared = lidar.shared.clone();
 
         let _ = thread::spawn(move || {
-            let sweep = Sweep::new(port).unwrap();
-            sweep.start_scanning().unwrap();
-            loop {
-                match sweep.scan() {
-                    Ok(ref samples) if samples.len() > 0 => {
-
-                        // get a lock on the points array
-                        let mut points  = points_clone.lock().unwrap();
-
-                        // reset points
-                        let max_distance = 1000;
-                        for i in 0..360 {
-                            points[i] = max_distance;
-                        }
 
-                        // this is one complete scan
-                        let mut first = (samples.first().unwrap().angle / 1000) as usize;
-                        let mut last = (samples.last().unwrap().angle / 1000) as usize;
-                        println!("LIDAR: {}..{} degrees has {} samples", first, last, samples.len());
-
-                        // store the points (but only the good ones)
-                        for i in 0..samples.len() {
-                            let sample = &samples[i];
-                            if sample.signal_strength > 100 && sample.distance > 1 {
-                                let mut angle = sample.angle / 1000;
-                                angle = 360 - angle; // it spins counter clockwise!
-                                if angle >= 0 && angle < 360 {
-                                    points[angle as usize] = sample.distance as u32;
+            let reconnecting = || shared.lock().unwrap().health.restarts += 1;
+            keep_connected(reconnecting, || {
+                // libsweep can't set the sample rate, so it is set over the serial port before
+                // libsweep opens it
+                if let Some(hz) = config.sample_rate {
+                    if let Err(e) = set_sample_rate(&port, hz) {
+                        println!("Failed to set LIDAR sample rate to {} Hz: {}", hz, e);
+                    }
+                }
+
+                let sweep = match Sweep::new(port.clone()) {
+                    Ok(sweep) => sweep,
+                    Err(e) => {
+                        println!("Failed to open LIDAR: {}", e);
+                        return;
+                    }
+                };
+
+                // the sensor must not be scanning while it is configured
+                if let Some(hz) = config.motor_speed {
+                    if let Err(e) = sweep.set_motor_speed(hz) {
+                        println!("Failed to set LIDAR motor speed to {} Hz: {}", hz, e);
+                    }
+                }
+                if let (Ok(speed), Ok(rate)) = (sweep.get_motor_speed(), sweep.get_sample_rate()) {
+                    println!("LIDAR motor speed {} Hz, sample rate {} Hz", speed, rate);
+                }
+
+                if let Err(e) = sweep.start_scanning() {
+                    println!("Failed to start LIDAR scanning: {}", e);
+                    return;
+                }
+
+                {
+                    let mut s = shared.lock().unwrap();
+                    s.health.scanning = true;
+                }
+
+                let mut last_scan = Instant::now();
+                let mut errors = 0;
+
+                while errors < MAX_CONSECUTIVE_ERRORS {
+                    match sweep.scan() {
+                        Ok(ref samples) if !samples.is_empty() => {
+                            errors = 0;
+
+                            // the samples aren't timestamped so assume they were measured at
+                            // a constant rate since the previous scan
+                            let received = Instant::now();
+                            let duration = received.duration_since(last_scan);
+                            last_scan = received;
+
+                            let n = samples.len() as u32;
+                            let mut points = Vec::with_capacity(samples.len());
+                            for (i, sample) in samples.iter().enumerate() {
+                                let angle = config.vehicle_angle(sample.angle as f32 / 1000_f32);
+                                if config.mask.as_ref().is_some_and(|m| m.contains(angle)) {
+                                    continue;
                                 }
+                                points.push(ScanPoint {
+                                    angle: angle,
+                                    distance: sample.distance as u32,
+                                    signal_strength: sample.signal_strength as u8,
+                                    timestamp: received - duration * (n - 1 - i as u32) / n,
+                                });
                             }
+                            let scan = Scan::new(points, received - duration, received);
+
+                            let mut s = shared.lock().unwrap();
+                            scan.resample(&mut s.points);
+                            s.scan = Some(scan);
+
+                            // the first scan after starting includes the time taken to start
+                            if s.health.last_scan.is_some() {
+                                let secs = duration.as_secs() as f32 + duration.subsec_nanos() as f32 / 1_000_000_000_f32;
+                                let rate = 1_f32 / secs;
+                                s.health.scan_rate = if s.health.scan_rate == 0_f32 {
+                                    rate
+                                } else {
+                                    s.health.scan_rate + SCAN_RATE_SMOOTHING * (rate - s.health.scan_rate)
+                                };
+                            }
+                            s.health.scans += 1;
+                            s.health.last_scan = Some(received);
+                        },
+                        Ok(_) => {
+                            errors += 1;
+                            let mut s = shared.lock().unwrap();
+                            s.health.errors += 1;
+                            println!("LIDAR scan had no samples");
+                        }
+                        Err(e) => {
+                            errors += 1;
+                            let mut s = shared.lock().unwrap();
+                            s.health.errors += 1;
+                            println!("LIDAR scan failed: {}", e);
                         }
+                    }
+                }
 
-                    },
-                    _ => println!("scan failed")
+                println!("Restarting LIDAR after {} failed scans", errors);
+                if let Err(e) = sweep.stop_scanning() {
+                    println!("Failed to stop LIDAR scanning: {}", e);
                 }
-            }
+                let mut s = shared.lock().unwrap();
+                s.health.scanning = false;
+                s.health.scan_rate = 0_f32;
+            });
         });
 
         lidar
     }
 
-    pub fn get(&self, buffer: &mut Vec<u32>) {
-        let points  = self.points.lock().unwrap();
-        for i in 0..360 {
-            buffer[i] = points[i]
-        }
+    pub fn health(&self) -> LidarHealth {
+        let s = self.shared.lock().unwrap();
+        s.health.clone()
+    }
+
+    /// the latest complete scan at full resolution
+    pub fn get_scan(&self) -> Option<Scan> {
+        let s = self.shared.lock().unwrap();
+        s.scan.clone()
     }
 
     pub fn min(&self, start: usize, end: usize) -> u32 {
-        let points  = self.points.lock().unwrap();
+        let s = self.shared.lock().unwrap();
+        let points = &s.points;
         let mut min = points[start];
         if start < end {
             for i in start..end {
@@ -93,4 +271,114 @@ impl Lidar {
 
 }
 
+/// Set the sample rate with the Sweep's `LR` command, stopping it scanning first since the
+/// rate can't be changed while it is scanning
+fn set_sample_rate(port: &str, hz: i32) -> io::Result<()> {
+    let code = match sample_rate_code(hz) {
+        Some(code) => code,
+        None => return Err(io::Error::new(io::ErrorKind::InvalidInput, "use 500, 750 or 1000 Hz")),
+    };
+
+    let mut serial = serial::open(port)?;
+    serial.reconfigure(&|settings| {
+            settings.set_baud_rate(serial::Baud115200)?;
+            settings.set_char_size(serial::Bits8);
+            settings.set_parity(serial::ParityNone);
+            settings.set_stop_bits(serial::Stop1);
+            settings.set_flow_control(serial::FlowNone);
+            Ok(())
+        })?;
+
+    // stop scanning and throw away any samples that were already on their way
+    serial.write_all(b"DX\n")?;
+    serial.set_timeout(Duration::from_millis(100))?;
+    let mut buf = [0_u8; 256];
+    loop {
+        match serial.read(&mut buf) {
+            Ok(0) => break,
+            Ok(_) => {}
+            Err(ref e) if e.kind() == io::ErrorKind::TimedOut
+                       || e.kind() == io::ErrorKind::WouldBlock => break,
+            Err(e) => return Err(e),
+        }
+    }
+
+    serial.set_timeout(Duration::from_millis(COMMAND_TIMEOUT_MS))?;
+    let command = format!("LR{}\n", code);
+    serial.write_all(command.as_bytes())?;
+    serial.flush()?;
+    let mut response = [0_u8; 9];
+    serial.read_exact(&mut response)?;
+    check_response(command.as_bytes(), &response)
+}
+
+/// the Sweep's parameter for each of the sample rates it supports
+fn sample_rate_code(hz: i32) -> Option<&'static str> {
+    match hz {
+        500 => Some("01"),
+        750 => Some("02"),
+        1000 => Some("03"),
+        _ => None,
+    }
+}
 
+/// The response to a command with a parameter echoes the command, followed by a two character
+/// status ("00" for success), a checksum of the status and a line feed
+fn check_response(command: &[u8], response: &[u8]) -> io::Result<()> {
+    let n = command.len();
+    if response.len() != n + 4 || &response[..n] != command || response[n + 3] != b'\n' {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "unexpected response"));
+    }
+    let status = &response[n..n + 2];
+    let sum = ((status[0] as u16 + status[1] as u16) & 0x3F) as u8 + 0x30;
+    if response[n + 2] != sum {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "bad checksum in response"));
+    }
+    if status != b"00" {
+        let status = String::from_utf8_lossy(status);
+        return Err(io::Error::other(format!("sensor returned status {}", status)));
+    }
+    Ok(())
+}
+
+impl RangeScanner for Lidar {
+    fn min_distance(&self, start: usize, end: usize) -> u32 {
+        self.min(start, end)
+    }
+
+    fn scan(&self) -> Option<Scan> {
+        self.get_scan()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn checks_the_response_to_a_command() {
+        assert_eq!(sample_rate_code(750), Some("02"));
+        assert_eq!(sample_rate_code(800), None);
+
+        assert!(check_response(b"LR02\n", b"LR02\n00P\n").is_ok());
+        // invalid parameter
+        let e = check_response(b"LR02\n", b"LR02\n11R\n").unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::Other);
+        // bad checksum, or the response to a different command
+        let e = check_response(b"LR02\n", b"LR02\n00Q\n").unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::InvalidData);
+        let e = check_response(b"LR02\n", b"LR03\n00P\n").unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn converts_sensor_angles_to_vehicle_angles() {
+        let mut config = LidarConfig::new();
+        config.offset = 90;
+        assert_eq!(config.vehicle_angle(10_f32), 80_f32);
+        assert_eq!(config.vehicle_angle(100_f32), 350_f32);
+        config.rotation = Rotation::Clockwise;
+        assert_eq!(config.vehicle_angle(10_f32), 100_f32);
+        assert_eq!(config.vehicle_angle(300_f32), 30_f32);
+    }
+}
@@ -0,0 +1,484 @@
+use std::collections::HashMap;
+use std::f64::consts::PI;
+use std::time::{Duration, Instant};
+
+use super::frame::{LocalFrame, Point};
+use super::gps::GpsFix;
+use super::maths::normalize_radians;
+use super::motors::Motion;
+use super::odometry::Odometry;
+
+/// indices into the state vector
+const EAST: usize = 0;
+const NORTH: usize = 1;
+const HEADING: usize = 2;
+const SPEED: usize = 3;
+const YAW_RATE: usize = 4;
+
+/// standard deviation of a GPS position in metres at an HDOP of 1
+const GPS_UERE: f64 = 2.5;
+
+/// HDOP assumed when the receiver doesn't report one
+const DEFAULT_HDOP: f64 = 2_f64;
+
+/// standard deviation of the GPS speed over ground in m/s
+const GPS_SPEED_SD: f64 = 0.3;
+
+/// standard deviation of the compass heading in degrees
+const COMPASS_SD: f64 = 3_f64;
+
+/// standard deviation in m/s of the speed estimated from the motor commands, which is only
+/// used when there is no odometry
+const COMMANDED_SPEED_SD: f64 = 1_f64;
+
+/// the motor commands are used if no odometry has measured the speed for this long
+const ODOMETRY_TIMEOUT_MS: u64 = 500;
+
+/// odometry is never assumed to be more accurate than this (m/s and degrees/s)
+const MIN_SPEED_SD: f64 = 0.02;
+const MIN_YAW_RATE_SD: f64 = 1_f64;
+
+/// how quickly the speed (m/s/s) and yaw rate (degrees/s/s) are expected to change
+const ACCELERATION_SD: f64 = 2_f64;
+const ANGULAR_ACCELERATION_SD: f64 = 90_f64;
+
+/// GPS positions further than this many standard deviations from the estimate are rejected
+const GPS_GATE: f64 = 4_f64;
+
+/// after this many GPS positions in a row are rejected the estimate is assumed to be wrong and
+/// is reset to the fix
+const MAX_REJECTED_FIXES: usize = 5;
+
+/// The filtered position, heading and motion of the vehicle
+#[derive(Debug, Clone)]
+pub struct Estimate {
+    /// position in the local frame
+    pub position: Point,
+    /// degrees clockwise from north
+    pub heading: f64,
+    /// m/s, positive forwards
+    pub speed: f64,
+    /// covariance of (east, north, heading, speed, yaw rate) in metres, radians and seconds
+    pub covariance: [[f64; 5]; 5],
+}
+
+impl Estimate {
+    /// standard deviation of the position in metres, averaged over east and north
+    pub fn position_sd(&self) -> f64 {
+        ((self.covariance[EAST][EAST] + self.covariance[NORTH][NORTH]) / 2_f64).sqrt()
+    }
+}
+
+/// Extended Kalman filter over the vehicle's position (in a local frame anchored at the first
+/// GPS fix), heading, speed and yaw rate. The state is predicted forwards with a constant speed and
+/// yaw rate model and corrected by GPS fixes (weighted by HDOP), the compass and any odometry
+/// sources, falling back to the motor commands for the speed when there is no odometry.
+pub struct Localization {
+    /// wheel speed in m/s at full power, for estimating the speed from the motor commands
+    max_wheel_speed: f64,
+    /// anchored at the first GPS fix
+    frame: Option<LocalFrame>,
+    heading_known: bool,
+    x: [f64; 5],
+    p: [[f64; 5]; 5],
+    last_predict: Option<Instant>,
+    /// when the last GPS fix that was used was received
+    last_fix: Option<Instant>,
+    rejected_fixes: usize,
+    /// when the last compass heading that was used was measured
+    last_heading: Option<Instant>,
+    /// the motor commands that the speed was last corrected with
+    last_commanded: Option<(Motion, Motion)>,
+    /// the previous reading from each odometry source
+    last_odometry: HashMap<&'static str, Odometry>,
+    /// when odometry last measured the speed
+    last_odometry_speed: Option<Instant>,
+}
+
+impl Localization {
+    pub fn new(max_wheel_speed: f64) -> Self {
+        Localization {
+            max_wheel_speed: max_wheel_speed,
+            frame: None,
+            heading_known: false,
+            x: [0_f64; 5],
+            p: [[0_f64; 5]; 5],
+            last_predict: None,
+            last_fix: None,
+            rejected_fixes: 0,
+            last_heading: None,
+            last_commanded: None,
+            last_odometry: HashMap::new(),
+            last_odometry_speed: None,
+        }
+    }
+
+    /// the frame that positions are estimated in, or None until there has been a GPS fix
+    pub fn frame(&self) -> Option<LocalFrame> {
+        self.frame
+    }
+
+    /// the current estimate, or None until there has been a GPS fix and a compass heading
+    pub fn estimate(&self) -> Option<Estimate> {
+        if self.frame.is_none() || !self.heading_known {
+            return None;
+        }
+        Some(Estimate {
+            position: Point::new(self.x[EAST], self.x[NORTH]),
+            heading: self.x[HEADING].to_degrees(),
+            speed: self.x[SPEED],
+            covariance: self.p,
+        })
+    }
+
+    /// Move the estimate forwards to the current time, assuming the speed and yaw rate haven't
+    /// changed. Call this before applying the measurements for each loop.
+    pub fn predict(&mut self) {
+        let now = Instant::now();
+        let dt = self.last_predict.map(|t| seconds(now.duration_since(t)));
+        self.last_predict = Some(now);
+
+        match dt {
+            Some(dt) if self.frame.is_some() && self.heading_known => self.advance(dt),
+            _ => {}
+        }
+    }
+
+    /// move the estimate forwards by dt seconds
+    fn advance(&mut self, dt: f64) {
+        let (s, c) = self.x[HEADING].sin_cos();
+        let v = self.x[SPEED];
+
+        let mut f = identity();
+        f[EAST][HEADING] = v * c * dt;
+        f[EAST][SPEED] = s * dt;
+        f[NORTH][HEADING] = -v * s * dt;
+        f[NORTH][SPEED] = c * dt;
+        f[HEADING][YAW_RATE] = dt;
+
+        self.x[EAST] += v * s * dt;
+        self.x[NORTH] += v * c * dt;
+        self.x[HEADING] = normalize_radians(self.x[HEADING] + self.x[YAW_RATE] * dt);
+
+        // P = F P F' + Q
+        let mut p = [[0_f64; 5]; 5];
+        for r in 0..5 {
+            for c in 0..5 {
+                let mut sum = 0_f64;
+                for i in 0..5 {
+                    for j in 0..5 {
+                        sum += f[r][i] * self.p[i][j] * f[c][j];
+                    }
+                }
+                p[r][c] = sum;
+            }
+        }
+        p[SPEED][SPEED] += (ACCELERATION_SD * dt).powi(2);
+        p[YAW_RATE][YAW_RATE] += (ANGULAR_ACCELERATION_SD.to_radians() * dt).powi(2);
+        self.p = p;
+    }
+
+    /// Correct the estimate with a GPS fix. Fixes that have already been used are ignored, as
+    /// are positions that jump too far from the estimate unless that keeps happening.
+    pub fn update_gps(&mut self, fix: &GpsFix) {
+        if self.last_fix == Some(fix.received) {
+            return;
+        }
+        self.last_fix = Some(fix.received);
+
+        let sd = fix.hdop.map_or(DEFAULT_HDOP, |h| h as f64) * GPS_UERE;
+        let frame = match self.frame {
+            Some(f) => f,
+            None => {
+                self.frame = Some(LocalFrame::new(&fix.location()));
+                self.reset_position(0_f64, 0_f64, sd);
+                return;
+            }
+        };
+        let Point { east, north } = frame.to_point(&fix.location());
+
+        let e = east - self.x[EAST];
+        let n = north - self.x[NORTH];
+        let variance = sd * sd;
+        if self.within_gate(EAST, e, variance) && self.within_gate(NORTH, n, variance) {
+            self.rejected_fixes = 0;
+            self.update(EAST, e, variance);
+            let n = north - self.x[NORTH];
+            self.update(NORTH, n, variance);
+        } else {
+            self.rejected_fixes += 1;
+            if self.rejected_fixes >= MAX_REJECTED_FIXES {
+                println!("GPS disagrees with the estimated position, resetting to the fix");
+                self.rejected_fixes = 0;
+                self.reset_position(east, north, sd);
+            }
+        }
+
+        if let Some(speed) = fix.speed {
+            let innovation = speed as f64 - self.x[SPEED];
+            self.update(SPEED, innovation, GPS_SPEED_SD * GPS_SPEED_SD);
+        }
+    }
+
+    /// correct the estimate with a compass heading in degrees, unless the reading measured at
+    /// the same time has already been used
+    pub fn update_heading(&mut self, heading: f32, measured: Instant) {
+        if self.last_heading == Some(measured) {
+            return;
+        }
+        self.last_heading = Some(measured);
+
+        let heading = (heading as f64).to_radians();
+        if !self.heading_known {
+            self.heading_known = true;
+            self.x[HEADING] = normalize_radians(heading);
+            for i in 0..5 {
+                self.p[HEADING][i] = 0_f64;
+                self.p[i][HEADING] = 0_f64;
+            }
+            self.p[HEADING][HEADING] = COMPASS_SD.to_radians().powi(2);
+            return;
+        }
+        let innovation = normalize_radians(heading - self.x[HEADING] + PI) - PI;
+        self.update(HEADING, innovation, COMPASS_SD.to_radians().powi(2));
+    }
+
+    /// Correct the speed and yaw rate with the movement measured by an odometry source since
+    /// its previous reading. Each source needs a different name. None means the source isn't
+    /// working, and the next reading after it recovers is only used as a starting point.
+    pub fn update_odometry(&mut self, name: &'static str, odometry: Option<Odometry>) {
+        let odometry = match odometry {
+            Some(o) => o,
+            None => {
+                self.last_odometry.remove(name);
+                return;
+            }
+        };
+
+        if let Some(ref last) = self.last_odometry.get(name).cloned() {
+            if odometry.steps <= last.steps {
+                return;
+            }
+            let dt = seconds(odometry.updated.duration_since(last.updated));
+            if dt > 0_f64 {
+                let (_, forward, turn) = odometry.since(last);
+                // the uncertainty is per step, so combine it over the steps since the last reading
+                let steps = ((odometry.steps - last.steps) as f64).sqrt();
+
+                let speed_sd = (odometry.position_sd * steps / dt).max(MIN_SPEED_SD);
+                let innovation = forward / dt - self.x[SPEED];
+                self.update(SPEED, innovation, speed_sd * speed_sd);
+
+                let yaw_rate_sd = (odometry.heading_sd * steps / dt).max(MIN_YAW_RATE_SD).to_radians();
+                let innovation = turn.to_radians() / dt - self.x[YAW_RATE];
+                self.update(YAW_RATE, innovation, yaw_rate_sd * yaw_rate_sd);
+
+                self.last_odometry_speed = Some(Instant::now());
+            }
+        }
+        self.last_odometry.insert(name, odometry);
+    }
+
+    /// Correct the speed with an estimate from the motor commands, unless odometry has
+    /// measured it recently. Each change of command is only used once, since repeating it
+    /// doesn't make it any more accurate.
+    pub fn update_commanded(&mut self, commanded: (Motion, Motion)) {
+        if self.last_odometry_speed.is_some_and(|t| t.elapsed() < Duration::from_millis(ODOMETRY_TIMEOUT_MS)) {
+            self.last_commanded = None;
+            return;
+        }
+        if self.last_commanded == Some(commanded) {
+            return;
+        }
+        self.last_commanded = Some(commanded);
+        let speed = (self.wheel_speed(commanded.0) + self.wheel_speed(commanded.1)) / 2_f64;
+        let innovation = speed - self.x[SPEED];
+        self.update(SPEED, innovation, COMMANDED_SPEED_SD * COMMANDED_SPEED_SD);
+    }
+
+    /// Kalman update for a direct measurement of one element of the state
+    fn update(&mut self, i: usize, innovation: f64, variance: f64) {
+        let s = self.p[i][i] + variance;
+        let mut k = [0_f64; 5];
+        for r in 0..5 {
+            k[r] = self.p[r][i] / s;
+        }
+        for r in 0..5 {
+            self.x[r] += k[r] * innovation;
+        }
+        self.x[HEADING] = normalize_radians(self.x[HEADING]);
+
+        // P = (I - K H) P
+        let row = self.p[i];
+        for r in 0..5 {
+            for c in 0..5 {
+                self.p[r][c] -= k[r] * row[c];
+            }
+        }
+    }
+
+    /// is the innovation for a measurement of one element of the state plausible?
+    fn within_gate(&self, i: usize, innovation: f64, variance: f64) -> bool {
+        innovation * innovation <= GPS_GATE * GPS_GATE * (self.p[i][i] + variance)
+    }
+
+    /// forget the estimated position and start again from (east, north)
+    fn reset_position(&mut self, east: f64, north: f64, sd: f64) {
+        self.x[EAST] = east;
+        self.x[NORTH] = north;
+        for &i in [EAST, NORTH].iter() {
+            for j in 0..5 {
+                self.p[i][j] = 0_f64;
+                self.p[j][i] = 0_f64;
+            }
+            self.p[i][i] = sd * sd;
+        }
+    }
+
+    /// estimated wheel speed in m/s for a motor command
+    fn wheel_speed(&self, m: Motion) -> f64 {
+        match m {
+            Motion::Speed(n) => n as f64 / 127_f64 * self.max_wheel_speed,
+            Motion::Velocity(v) => v as f64,
+            Motion::Brake(_) => 0_f64,
+        }
+    }
+}
+
+fn identity() -> [[f64; 5]; 5] {
+    let mut m = [[0_f64; 5]; 5];
+    for i in 0..5 {
+        m[i][i] = 1_f64;
+    }
+    m
+}
+
+fn seconds(d: Duration) -> f64 {
+    d.as_secs() as f64 + d.subsec_nanos() as f64 / 1_000_000_000_f64
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::time::{Duration, Instant};
+
+    use super::super::frame::{LocalFrame, Point};
+    use super::super::gps::GpsFix;
+    use super::super::motors::Motion;
+    use super::super::odometry::Odometry;
+
+    const START: (f64, f64) = (39.950380, -105.060512);
+
+    fn fix_at(frame: &LocalFrame, east: f64, north: f64, hdop: f32, received: Instant) -> GpsFix {
+        let loc = frame.to_location(&Point::new(east, north));
+        let mut fix = GpsFix::new(loc.lat, loc.lon);
+        fix.hdop = Some(hdop);
+        fix.received = received;
+        fix
+    }
+
+    /// a filter that has had a fix at the origin (with an HDOP of 1) and a heading
+    fn started(heading: f32) -> (Localization, LocalFrame, Instant) {
+        let t = Instant::now();
+        let mut l = Localization::new(2_f64);
+        let mut fix = GpsFix::new(START.0, START.1);
+        fix.hdop = Some(1_f32);
+        fix.received = t;
+        l.update_gps(&fix);
+        l.update_heading(heading, t);
+        let frame = l.frame().unwrap();
+        (l, frame, t)
+    }
+
+    #[test]
+    fn predicts_a_straight_line() {
+        let (mut l, _, _) = started(90_f32);
+        l.x[SPEED] = 2_f64;
+        for _ in 0..10 {
+            l.advance(0.5);
+        }
+        let e = l.estimate().unwrap();
+        assert!((e.position.east - 10_f64).abs() < 1e-9);
+        assert!(e.position.north.abs() < 1e-9);
+        assert!((e.heading - 90_f64).abs() < 1e-9);
+        assert!(e.speed == 2_f64);
+        // the position becomes less certain the further the vehicle goes without a fix
+        assert!(e.position_sd() > GPS_UERE);
+    }
+
+    #[test]
+    fn weights_gps_by_hdop() {
+        let (mut l, frame, t) = started(0_f32);
+        // the position and the fix are equally uncertain so the estimate moves half way
+        l.update_gps(&fix_at(&frame, 0_f64, 2_f64, 1_f32, t + Duration::from_millis(100)));
+        let e = l.estimate().unwrap();
+        assert!((e.position.north - 1_f64).abs() < 1e-9);
+        assert!(e.position.east.abs() < 1e-9);
+
+        // the variance is now half that of a fix at HDOP 1, so one at HDOP 2 has 1/9 of the gain
+        l.update_gps(&fix_at(&frame, 0_f64, 10_f64, 2_f32, t + Duration::from_millis(200)));
+        let e = l.estimate().unwrap();
+        assert!((e.position.north - 2_f64).abs() < 1e-9);
+
+        // the same fix again is ignored
+        l.update_gps(&fix_at(&frame, 0_f64, 10_f64, 2_f32, t + Duration::from_millis(200)));
+        assert!((l.estimate().unwrap().position.north - 2_f64).abs() < 1e-9);
+    }
+
+    #[test]
+    fn heading_wraps_through_north() {
+        let (mut l, _, t) = started(359_f32);
+        l.update_heading(1_f32, t + Duration::from_millis(100));
+        let h = l.estimate().unwrap().heading;
+        assert!(!(0.01..=359.99).contains(&h), "heading {}", h);
+
+        // a reading that has already been used doesn't move the estimate again
+        l.update_heading(1_f32, t + Duration::from_millis(100));
+        assert_eq!(l.estimate().unwrap().heading, h);
+    }
+
+    #[test]
+    fn odometry_sources_are_tracked_separately() {
+        let (mut l, _, _) = started(0_f32);
+        l.p[SPEED][SPEED] = 100_f64;
+        let mut wheels = Odometry::new();
+        l.update_odometry("wheels", Some(wheels.clone()));
+        assert_eq!(l.x[SPEED], 0_f64);
+
+        // 1 m in 0.5 s
+        wheels.add(0_f64, 1_f64, 0_f64);
+        wheels.updated += Duration::from_millis(500);
+        l.update_odometry("wheels", Some(wheels.clone()));
+        let speed = l.x[SPEED];
+        assert!(speed > 1.99 && speed <= 2_f64, "speed {}", speed);
+
+        // another source's first reading only sets its starting point, however far it has
+        // already moved
+        let mut lidar = Odometry::new();
+        lidar.add(0_f64, 50_f64, 0_f64);
+        l.update_odometry("lidar", Some(lidar));
+        assert_eq!(l.x[SPEED], speed);
+
+        // as does the first reading after a source stops working
+        l.update_odometry("wheels", None);
+        wheels.add(0_f64, 50_f64, 0_f64);
+        wheels.updated += Duration::from_millis(500);
+        l.update_odometry("wheels", Some(wheels));
+        assert_eq!(l.x[SPEED], speed);
+    }
+
+    #[test]
+    fn uses_each_motor_command_once() {
+        let (mut l, _, _) = started(0_f32);
+        l.p[SPEED][SPEED] = 1_f64;
+        let full = (Motion::Speed(127), Motion::Speed(127));
+        l.update_commanded(full);
+        // equal variances, so half way to the commanded 2 m/s
+        assert!((l.x[SPEED] - 1_f64).abs() < 1e-9);
+        l.update_commanded(full);
+        assert!((l.x[SPEED] - 1_f64).abs() < 1e-9);
+        l.update_commanded((Motion::Brake(127), Motion::Brake(127)));
+        assert!(l.x[SPEED] < 1_f64);
+    }
+}
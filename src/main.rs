@@ -1,3 +1,8 @@
+// the code spells out `field: field`, names NMEA sentences and the AVC in capitals and walks
+// matrices and buffers by index, so those lints are left to the code's own style
+#![allow(clippy::redundant_field_names, clippy::upper_case_acronyms, clippy::needless_range_loop,
+         clippy::too_many_arguments)]
+
 extern crate sysfs_gpio;
 extern crate getopts;
 extern crate chrono;
@@ -18,15 +23,48 @@ use std::thread;
 use std::time::Duration;
 
 mod gps;
+mod nmea;
+mod ubx;
+mod gpsd;
 mod compass;
+mod calibration;
+mod wmm;
+mod i2c;
+mod imu;
+mod heading;
+mod ultrasonic;
+mod perception;
+mod scan;
+mod frame;
+mod tracking;
+mod occupancy;
+mod odometry;
+mod scan_matching;
 mod video;
 mod avc;
 mod motors;
+mod encoder;
+mod localization;
 mod switch;
 mod lidar;
-
-use gps::GPS;
-use compass::Compass;
+mod hardware;
+mod maths;
+mod sim;
+
+use gps::*;
+use compass::{Compass, CompassConfig};
+use calibration::CompassCalibration;
+use heading::HeadingEstimator;
+use lidar::{Lidar, LidarConfig, Rotation};
+use ultrasonic::Ultrasonic;
+use perception::{Sector, Sectors};
+use scan::ScanPoint;
+use tracking::Tracker;
+use scan_matching::ScanOdometry;
+use hardware::OdometrySource;
+use encoder::{EncoderConfig, WheelEncoders};
+use sim::*;
+use ubx::DynamicModel;
 use video::*;
 use avc::*;
 use switch::*;
@@ -38,6 +76,8 @@ pub struct Config {
     imu_device: &'static str,
     qik_device: &'static str,
     lidar_device: &'static str,
+    ultrasonic_device: &'static str,
+    compass_calibration: &'static str,
 }
 
 fn main() {
@@ -53,14 +93,19 @@ fn main() {
     opts.optflag("v", "test-video", "tests the video");
     opts.optflag("i", "test-imu", "tests the IMU");
     opts.optflag("m", "test-motors", "tests the motors");
+    opts.optflag("e", "test-encoders", "tests the wheel encoders and speed controllers");
     opts.optflag("s", "test-switch", "tests the switch");
+    opts.optflag("u", "test-ultrasonic", "tests the ultrasonic sensors");
+    opts.optflag("l", "test-lidar", "tests the LIDAR");
     opts.optflag("c", "capture-gps", "records a GPS waypoint to file");
+    opts.optflag("", "calibrate-compass", "calibrates the compass while the vehicle is turned in a circle");
     opts.optflag("a", "avc", "Start the web server");
+    opts.optflag("", "simulate", "runs the course against the built-in vehicle simulator");
     opts.optopt("f", "filename", "Course filename", "conf/avc.yaml");
 
     let matches = match opts.parse(&args[1..]) {
         Ok(m) => m,
-        Err(f) => panic!(f.to_string()),
+        Err(f) => panic!("{}", f),
     };
 
     let conf = Config {
@@ -68,39 +113,77 @@ fn main() {
         imu_device: "/dev/i2c-1",
         qik_device: "/dev/qik",
         lidar_device: "/dev/lidar",
+        ultrasonic_device: "/dev/spidev0.0",
+        compass_calibration: "conf/compass_calibration.yaml",
     };
 
     if matches.opt_present("g") {
-        test_gps(&conf);
+        // use the GPS settings from the course file, if one is given
+        let gps_config = match matches.opt_str("f") {
+            Some(f) => {
+                let course = load_course(&f);
+                load_gps_config(&course, &load_settings(&course))
+            }
+            None => GpsConfig::new(),
+        };
+        test_gps(&conf, gps_config);
     } else if matches.opt_present("v") {
         test_video(&conf);
     } else if matches.opt_present("i") {
         test_imu(&conf);
     } else if matches.opt_present("m") {
         test_motors(&conf);
+    } else if matches.opt_present("e") {
+        test_encoders(&conf, &matches.opt_str("f"));
     } else if matches.opt_present("s") {
         test_switch();
+    } else if matches.opt_present("l") {
+        test_lidar(&conf, &matches.opt_str("f"));
+    } else if matches.opt_present("u") {
+        test_ultrasonic(&conf);
     } else if matches.opt_present("c") {
         capture_gps(&conf);
+    } else if matches.opt_present("calibrate-compass") {
+        calibrate_compass(&conf);
     } else if matches.opt_present("a") {
         let filename = match matches.opt_str("f") {
             Some(f) => f,
             None => panic!("missing --filename argument"),
         };
         run_avc(conf, &filename);
+    } else if matches.opt_present("simulate") {
+        let filename = match matches.opt_str("f") {
+            Some(f) => f,
+            None => panic!("missing --filename argument"),
+        };
+        run_simulation(&filename);
     } else {
         panic!("missing cmd line argument .. try --help");
     }
 
 }
 
-fn run_avc(conf: Config, filename: &str) {
-
+fn load_course(filename: &str) -> Yaml {
     let mut input = String::new();
     let mut file = File::open(filename).unwrap();
     file.read_to_string(&mut input).unwrap();
-    let docs = YamlLoader::load_from_str(&input).unwrap();
-    let doc = &docs[0].as_hash().unwrap();
+    let mut docs = YamlLoader::load_from_str(&input).unwrap();
+    docs.remove(0)
+}
+
+/// read a number from the YAML, accepting both integer and real values
+fn yaml_f64(y: &Yaml) -> Option<f64> {
+    match *y {
+        Yaml::Integer(n) => Some(n as f64),
+        _ => y.as_f64(),
+    }
+}
+
+fn load_settings(course: &Yaml) -> Settings {
+    let doc = course.as_hash().unwrap();
+    let sectors = load_sectors(course);
+    let motor_config = load_motor_config(course);
+    let encoders = load_encoder_config(course).is_some();
 
     let waypoints = doc.get(&Yaml::String(String::from("waypoints"))).unwrap().as_vec().unwrap();
     let mut course: Vec<Location> = vec![];
@@ -112,7 +195,7 @@ fn run_avc(conf: Config, filename: &str) {
         course.push(Location::new(lat, lon));
     }
 
-    let settings = Settings {
+    Settings {
         max_speed: doc.get(&Yaml::String(String::from("max_speed")))
             .unwrap()
             .as_i64()
@@ -122,19 +205,329 @@ fn run_avc(conf: Config, filename: &str) {
             .unwrap()
             .as_i64()
             .unwrap() as u32,
+        sectors: sectors,
+        min_obstacle_confidence: doc.get(&Yaml::String(String::from("min_obstacle_confidence")))
+            .and_then(yaml_f64)
+            .unwrap_or(0.5) as f32,
         differential_drive_coefficient: 2_f32,
-        waypoint_accuracy: (0.000025, 0.000025),
+        waypoint_radius: doc.get(&Yaml::String(String::from("waypoint_radius")))
+            .and_then(yaml_f64)
+            .unwrap_or(2.5),
         usonic_sample_count: 4,
+        min_satellites: doc.get(&Yaml::String(String::from("min_satellites")))
+            .and_then(|y| y.as_i64())
+            .unwrap_or(4) as u8,
+        max_hdop: doc.get(&Yaml::String(String::from("max_hdop")))
+            .and_then(yaml_f64)
+            .unwrap_or(5_f64) as f32,
+        gps_timeout: doc.get(&Yaml::String(String::from("gps_timeout")))
+            .and_then(yaml_f64)
+            .map(|n| Duration::from_millis((n * 1000_f64) as u64))
+            .unwrap_or(Duration::from_secs(2)),
+        max_position_sd: doc.get(&Yaml::String(String::from("max_position_sd")))
+            .and_then(yaml_f64)
+            .unwrap_or(5_f64),
+        lidar_timeout: doc.get(&Yaml::String(String::from("lidar_timeout")))
+            .and_then(yaml_f64)
+            .map(|n| Duration::from_millis((n * 1000_f64) as u64))
+            .unwrap_or(Duration::from_secs(1)),
+        max_wheel_speed: motor_config.max_wheel_speed,
+        encoders: encoders,
+        record_video: true,
+        declination: doc.get(&Yaml::String(String::from("declination")))
+            .and_then(yaml_f64)
+            .map(|n| n as f32),
         waypoints: course,
+    }
+}
+
+/// Read the optional `simulation` and `obstacles` sections of the course file. The vehicle
+/// starts at the first waypoint unless a start position is specified.
+fn load_sim_settings(course: &Yaml, settings: &Settings) -> SimSettings {
+    let doc = course.as_hash().unwrap();
+
+    let first_wp = &settings.waypoints[0];
+    let mut sim = SimSettings::new((first_wp.lat, first_wp.lon));
+    sim.lidar = load_lidar_config(course);
+
+    if let Some(obstacles) = doc.get(&Yaml::String(String::from("obstacles"))) {
+        for o in obstacles.as_vec().unwrap() {
+            let o = o.as_vec().unwrap();
+            sim.obstacles.push(Obstacle {
+                lat: yaml_f64(&o[0]).unwrap(),
+                lon: yaml_f64(&o[1]).unwrap(),
+                radius: yaml_f64(&o[2]).unwrap(),
+            });
+        }
+    }
+
+    if let Some(s) = doc.get(&Yaml::String(String::from("simulation"))) {
+        let s = s.as_hash().unwrap();
+        let get = |key: &str| s.get(&Yaml::String(String::from(key))).and_then(yaml_f64);
+        if let Some(start) = s.get(&Yaml::String(String::from("start"))) {
+            let start = start.as_vec().unwrap();
+            sim.start = (yaml_f64(&start[0]).unwrap(), yaml_f64(&start[1]).unwrap());
+        }
+        if let Some(n) = get("start_heading") { sim.start_heading = n; }
+        if let Some(n) = get("gps_noise") { sim.gps_noise = n; }
+        if let Some(n) = get("gps_update_hz") { sim.gps_update_hz = n; }
+        if let Some(n) = get("compass_noise") { sim.compass_noise = n; }
+        if let Some(n) = get("compass_update_hz") { sim.compass_update_hz = n; }
+        if let Some(n) = get("max_wheel_speed") { sim.max_wheel_speed = n; }
+        if let Some(n) = get("track_width") { sim.track_width = n; }
+        if let Some(n) = get("lidar_range") { sim.lidar_range = n as u32; }
+        if let Some(n) = get("lidar_scan_hz") { sim.lidar_scan_hz = n; }
+        if let Some(b) = s.get(&Yaml::String(String::from("encoders"))).and_then(|y| y.as_bool()) {
+            sim.encoders = b;
+        }
+        if let Some(n) = get("start_delay") { sim.start_delay = Duration::from_millis((n * 1000_f64) as u64); }
+    }
+
+    sim
+}
+
+/// Read the optional `gps` section of the course file
+fn load_gps_config(course: &Yaml, settings: &Settings) -> GpsConfig {
+    let doc = course.as_hash().unwrap();
+
+    let mut config = GpsConfig::new();
+    config.stale_timeout = settings.gps_timeout;
+
+    if let Some(g) = doc.get(&Yaml::String(String::from("gps"))) {
+        let g = g.as_hash().unwrap();
+        let get = |key: &str| g.get(&Yaml::String(String::from(key)));
+        if let Some(protocol) = get("protocol") {
+            config.protocol = match protocol.as_str().unwrap() {
+                "nmea" => GpsProtocol::Nmea,
+                "ubx" => GpsProtocol::Ubx,
+                "gpsd" => GpsProtocol::Gpsd,
+                p => panic!("Unknown GPS protocol: {}", p),
+            };
+        }
+        if let Some(n) = get("baud_rate").and_then(|y| y.as_i64()) {
+            config.baud_rate = n as usize;
+        }
+        if let Some(n) = get("rate_hz").and_then(|y| y.as_i64()) {
+            // u-blox receivers measure at up to 25 Hz
+            config.rate_hz = Some(n.clamp(1, 25) as u32);
+        }
+        if let Some(address) = get("address") {
+            config.gpsd_address = String::from(address.as_str().unwrap());
+        }
+        if let Some(model) = get("dynamic_model") {
+            config.dynamic_model = Some(DynamicModel::parse(model.as_str().unwrap()).unwrap());
+        }
+    }
+
+    config
+}
+
+/// read a `[start, end]` range of angles, each from 0 to 359
+fn yaml_sector(y: &Yaml) -> Sector {
+    let v = y.as_vec().unwrap();
+    let angle = |y: &Yaml| match y.as_i64().unwrap() {
+        n @ 0..=359 => n as usize,
+        n => panic!("LIDAR sector angle {} is outside 0 to 359", n),
+    };
+    Sector::new(angle(&v[0]), angle(&v[1]))
+}
+
+/// Read the LIDAR mounting from the optional `lidar` section of the course file
+fn load_lidar_config(course: &Yaml) -> LidarConfig {
+    let doc = course.as_hash().unwrap();
+
+    let mut config = LidarConfig::new();
+
+    if let Some(l) = doc.get(&Yaml::String(String::from("lidar"))) {
+        let l = l.as_hash().unwrap();
+        let get = |key: &str| l.get(&Yaml::String(String::from(key)));
+        if let Some(n) = get("offset").and_then(|y| y.as_i64()) {
+            config.offset = n.rem_euclid(360) as usize;
+        }
+        if let Some(rotation) = get("rotation") {
+            config.rotation = match rotation.as_str().unwrap() {
+                "clockwise" => Rotation::Clockwise,
+                "counterclockwise" => Rotation::CounterClockwise,
+                r => panic!("Unknown LIDAR rotation: {}", r),
+            };
+        }
+        match get("mask") {
+            Some(&Yaml::Null) => config.mask = None,
+            Some(m) => config.mask = Some(yaml_sector(m)),
+            None => {}
+        }
+        if let Some(n) = get("motor_speed").and_then(|y| y.as_i64()) {
+            config.motor_speed = Some(n as i32);
+        }
+        if let Some(n) = get("sample_rate").and_then(|y| y.as_i64()) {
+            config.sample_rate = Some(n as i32);
+        }
+    }
+
+    config
+}
+
+/// Read the sector definitions from the `lidar` section of the course file
+fn load_sectors(course: &Yaml) -> Sectors {
+    let doc = course.as_hash().unwrap();
+
+    let mut sectors = Sectors::new();
+
+    let lidar = doc.get(&Yaml::String(String::from("lidar"))).and_then(|l| l.as_hash());
+    if let Some(s) = lidar.and_then(|l| l.get(&Yaml::String(String::from("sectors")))) {
+        let s = s.as_hash().unwrap();
+        let get = |key: &str| s.get(&Yaml::String(String::from(key))).map(yaml_sector);
+        if let Some(x) = get("side_left") {
+            sectors.side_left = x;
+        }
+        if let Some(x) = get("front_left") {
+            sectors.front_left = x;
+        }
+        if let Some(x) = get("front") {
+            sectors.front = x;
+        }
+        if let Some(x) = get("front_right") {
+            sectors.front_right = x;
+        }
+        if let Some(x) = get("side_right") {
+            sectors.side_right = x;
+        }
+    }
+
+    sectors
+}
+
+fn load_compass_config(course: &Yaml) -> CompassConfig {
+    let doc = course.as_hash().unwrap();
+
+    let mut config = CompassConfig::new();
+
+    if let Some(c) = doc.get(&Yaml::String(String::from("compass"))) {
+        let c = c.as_hash().unwrap();
+        let get = |key: &str| c.get(&Yaml::String(String::from(key)));
+        if let Some(n) = get("rate_hz").and_then(|y| y.as_i64()) {
+            config.rate_hz = n.max(1) as u32;
+        }
+        if let Some(n) = get("window").and_then(|y| y.as_i64()) {
+            config.window = n.max(1) as usize;
+        }
+        if let Some(n) = get("max_retries").and_then(|y| y.as_i64()) {
+            config.max_retries = n as u32;
+        }
+    }
+
+    config
+}
+
+/// Read the optional `motors` section of the course file
+fn load_motor_config(course: &Yaml) -> MotorConfig {
+    let doc = course.as_hash().unwrap();
+
+    let mut config = MotorConfig::new();
+
+    if let Some(m) = doc.get(&Yaml::String(String::from("motors"))) {
+        let m = m.as_hash().unwrap();
+        let get = |key: &str| m.get(&Yaml::String(String::from(key))).and_then(yaml_f64);
+        if let Some(n) = get("max_wheel_speed") { config.max_wheel_speed = n; }
+        if let Some(n) = get("kp") { config.kp = n; }
+        if let Some(n) = get("ki") { config.ki = n; }
+        if let Some(n) = get("kd") { config.kd = n; }
+    }
+
+    config
+}
+
+/// Read the `encoders` section of the course file, or None if the vehicle has no encoders
+fn load_encoder_config(course: &Yaml) -> Option<EncoderConfig> {
+    let doc = course.as_hash().unwrap();
+
+    doc.get(&Yaml::String(String::from("encoders"))).map(|e| {
+        let e = e.as_hash().unwrap();
+        let get = |key: &str| e.get(&Yaml::String(String::from(key)));
+        let pins = |y: &Yaml| {
+            let p = y.as_vec().unwrap();
+            (p[0].as_i64().unwrap() as u64, p[1].as_i64().unwrap() as u64)
+        };
+
+        let mut config = EncoderConfig::new();
+        if let Some(p) = get("left") { config.left = pins(p); }
+        if let Some(p) = get("right") { config.right = pins(p); }
+        if let Some(n) = get("ticks_per_rev").and_then(|y| y.as_i64()) { config.ticks_per_rev = n as u32; }
+        if let Some(n) = get("wheel_diameter").and_then(yaml_f64) { config.wheel_diameter = n; }
+        if let Some(n) = get("track_width").and_then(yaml_f64) { config.track_width = n; }
+        config
+    })
+}
+
+fn run_avc(conf: Config, filename: &str) {
+
+    let course = load_course(filename);
+    let settings = load_settings(&course);
+    let gps_config = load_gps_config(&course, &settings);
+
+    let mut qik = Qik::new(String::from(conf.qik_device), 18).unwrap();
+    qik.init().unwrap();
+
+    let encoders = load_encoder_config(&course).map(WheelEncoders::new);
+    let mut motors = Motors::new(&mut qik, load_motor_config(&course));
+    if let Some(ref encoders) = encoders {
+        encoders.start_thread();
+        motors.set_encoders(encoders.clone());
+    }
+
+    let mut io = IO {
+        gps: GPS::new(conf.gps_device, gps_config),
+        imu: HeadingEstimator::new(conf.imu_device,
+                                   Compass::new(conf.imu_device,
+                                                load_compass_calibration(&conf),
+                                                load_compass_config(&course)),
+                                   heading::DEFAULT_RATE_HZ),
+        odometry: encoders,
+        motors: motors,
+        lidar: Lidar::new(String::from(conf.lidar_device), load_lidar_config(&course)),
+        switch: Switch::new(17),
+        ultrasonic: Ultrasonic::new(conf.ultrasonic_device, settings.usonic_sample_count),
+    };
+
+    io.gps.start_thread();
+    io.imu.start_thread();
+    io.switch.start_thread();
+    io.ultrasonic.start_thread();
+
+    let avc = AVC::new(settings);
+    avc.run(&mut io);
+}
+
+fn run_simulation(filename: &str) {
+    println!("Simulating {}", filename);
+
+    let course = load_course(filename);
+    let mut settings = load_settings(&course);
+    settings.record_video = false;
+
+    let sim_settings = load_sim_settings(&course, &settings);
+    settings.encoders = sim_settings.encoders;
+    let sim = Simulator::new(sim_settings);
+
+    let mut io = IO {
+        gps: sim.gps(),
+        imu: sim.compass(),
+        odometry: sim.odometry(),
+        motors: sim.motors(),
+        lidar: sim.lidar(),
+        switch: sim.switch(),
+        ultrasonic: sim.ultrasonic(),
     };
 
-    let avc = AVC::new(conf, settings);
-    avc.run();
+    sim.start_thread();
+
+    let avc = AVC::new(settings);
+    avc.run(&mut io);
 }
 
 fn capture_gps(conf: &Config) {
     println!("Capturing GPS");
-    let gps = GPS::new(conf.gps_device);
+    let gps = GPS::new(conf.gps_device, GpsConfig::new());
     gps.start_thread();
     loop {
         if let Some(wp) = gps.get() {
@@ -153,8 +546,7 @@ fn capture_gps(conf: &Config) {
                             6,
                             wp.lon,
                             UTC::now());
-            let b = &s.as_ref();
-            file.write(b).unwrap();
+            file.write_all(s.as_bytes()).unwrap();
 
             break;
         }
@@ -163,26 +555,138 @@ fn capture_gps(conf: &Config) {
 }
 
 
-fn test_gps(conf: &Config) {
+fn test_gps(conf: &Config, gps_config: GpsConfig) {
     println!("Testing GPS");
-    let gps = GPS::new(conf.gps_device);
+    let gps = GPS::new(conf.gps_device, gps_config);
     gps.start_thread();
     loop {
-        println!("GPS: {:?}", gps.get());
+        match gps.get_fix() {
+            Some(fix) => println!("GPS: {:?} (receiver time {:?})", fix, fix.utc()),
+            None => println!("GPS: None (last fix received {:?} ago)", gps.fix_age()),
+        }
+        println!("Health: {:?}", gps.health());
         thread::sleep(Duration::from_millis(1000));
     }
 }
 
+/// load the compass calibration, falling back to no calibration if there isn't one
+fn load_compass_calibration(conf: &Config) -> CompassCalibration {
+    match CompassCalibration::load(conf.compass_calibration) {
+        Ok(c) => c,
+        Err(e) => {
+            println!("WARNING: compass is not calibrated ({})", e);
+            CompassCalibration::identity()
+        }
+    }
+}
+
+fn calibrate_compass(conf: &Config) {
+    println!("Calibrating compass. Slowly turn the vehicle through at least two full circles ...");
+    // don't average the readings
+    let mut config = CompassConfig::new();
+    config.window = 1;
+    let compass = Compass::new(conf.imu_device, CompassCalibration::identity(), config);
+    compass.start_thread();
+    let mut samples = vec![];
+    let mut last_update = None;
+    let start = UTC::now().timestamp();
+    while UTC::now().timestamp() - start < 30 {
+        match compass.reading() {
+            Some(ref r) if Some(r.updated) != last_update => {
+                last_update = Some(r.updated);
+                samples.push(r.raw);
+                if samples.len() % 20 == 0 {
+                    println!("Collected {} samples", samples.len());
+                }
+            }
+            _ => {}
+        }
+        thread::sleep(Duration::from_millis(10));
+    }
+    println!("Compass health: {:?}", compass.health());
+
+    match CompassCalibration::fit(&samples) {
+        Ok(c) => {
+            println!("Calibration: {:?}", c);
+            match c.save(conf.compass_calibration) {
+                Ok(_) => println!("Saved calibration to {}", conf.compass_calibration),
+                Err(e) => println!("Failed to save calibration: {}", e),
+            }
+        }
+        Err(e) => println!("Failed to calibrate compass: {}", e),
+    }
+}
+
 fn test_imu(conf: &Config) {
     println!("Testing IMU");
-    let mut compass = Compass::new(conf.imu_device);
-    //compass.start_thread().unwrap();
+    let compass = Compass::new(conf.imu_device, load_compass_calibration(conf), CompassConfig::new());
+    let mut imu = HeadingEstimator::new(conf.imu_device, compass, heading::DEFAULT_RATE_HZ);
+    imu.start_thread();
     loop {
-        println!("Compass: {:?}", compass.get());
+        match imu.get() {
+            Some(e) => println!("Heading: {:.*} +/- {:.*} Yaw rate: {:?} Gyro bias: {:.*} Pitch/roll: {:?}",
+                                1, e.heading, 1, e.uncertainty, e.yaw_rate, 3, e.gyro_bias, e.attitude),
+            None => println!("Heading: N/A"),
+        }
         thread::sleep(Duration::from_millis(1000));
     }
 }
 
+fn test_lidar(conf: &Config, filename: &Option<String>) {
+    println!("Testing LIDAR");
+    let config = match *filename {
+        Some(ref f) => load_lidar_config(&load_course(f)),
+        None => LidarConfig::new(),
+    };
+    let lidar = Lidar::new(String::from(conf.lidar_device), config);
+    let mut tracker = Tracker::new();
+    let mut scan_odometry = ScanOdometry::new();
+    let mut last_scan = None;
+    loop {
+        if let Some(scan) = lidar.get_scan() {
+            if Some(scan.finished) != last_scan {
+                last_scan = Some(scan.finished);
+                let good: Vec<&ScanPoint> = scan.points.iter().filter(|p| p.is_good()).collect();
+                let span = scan.finished.duration_since(scan.started);
+                let health = lidar.health();
+                println!("Scan: {} samples ({} good) in {} ms, {:.*} scans/s ({} scans, {} errors, {} restarts)",
+                         scan.points.len(),
+                         good.len(),
+                         span.as_secs() * 1000 + span.subsec_nanos() as u64 / 1_000_000,
+                         1, health.scan_rate, health.scans, health.errors, health.restarts);
+                if let Some(p) = good.iter().min_by_key(|p| p.distance) {
+                    let age = p.timestamp.elapsed();
+                    println!("Nearest: {:.*} degrees {} cm at {:?} measured {} ms ago",
+                             2, p.angle, p.distance, p.position(),
+                             age.as_secs() * 1000 + age.subsec_nanos() as u64 / 1_000_000);
+                }
+                scan_odometry.update(&scan);
+                match (scan_odometry.odometry(), scan_odometry.last_match()) {
+                    (Some(o), Some(m)) => println!("Odometry: {:.*}, {:.*} m, {:.*} degrees (rms {:.*} m, {:.*}% matched in {} iterations)",
+                                                   2, o.x, 2, o.y, 1, o.heading, 3, m.rms, 0, m.fraction * 100_f64, m.iterations),
+                    _ => println!("Odometry: scan could not be matched"),
+                }
+                tracker.update(&scan, None, None);
+                for o in tracker.objects().iter().filter(|o| o.missed == 0) {
+                    println!("Object {}: {:?} {:.*} cm away, {:.*} cm/s, {:?}",
+                             o.id, o.kind, 0, o.shape.distance(), 0, o.speed(), o.shape);
+                }
+            }
+        }
+        thread::sleep(Duration::from_millis(100));
+    }
+}
+
+fn test_ultrasonic(conf: &Config) {
+    println!("Testing ultrasonic sensors");
+    let ultrasonic = Ultrasonic::new(conf.ultrasonic_device, 4);
+    ultrasonic.start_thread();
+    loop {
+        println!("Ultrasonic: {:?}", ultrasonic.get());
+        thread::sleep(Duration::from_millis(500));
+    }
+}
+
 #[allow(dead_code)]
 fn test_qik(conf: &Config) {
     println!("Testing motors");
@@ -209,7 +713,7 @@ fn test_motors(conf: &Config) {
     qik.init().unwrap();
 
 
-    let mut motors = Motors::new(&mut qik);
+    let mut motors = Motors::new(&mut qik, MotorConfig::new());
     for i in 0..127 {
         motors.set(Motion::Speed(i), Motion::Speed(i));
         std::thread::sleep(Duration::from_millis(30));
@@ -217,13 +721,46 @@ fn test_motors(conf: &Config) {
     motors.set(Motion::Brake(127), Motion::Brake(127));
 }
 
+fn test_encoders(conf: &Config, filename: &Option<String>) {
+    println!("Testing wheel encoders");
+    let (motor_config, encoder_config) = match *filename {
+        Some(ref f) => {
+            let course = load_course(f);
+            (load_motor_config(&course), load_encoder_config(&course).unwrap_or(EncoderConfig::new()))
+        }
+        None => (MotorConfig::new(), EncoderConfig::new()),
+    };
+
+    let encoders = WheelEncoders::new(encoder_config);
+    encoders.start_thread();
+
+    let mut qik = qik::Qik::new(String::from(conf.qik_device), 18).unwrap();
+    qik.init().unwrap();
+    let mut motors = Motors::new(&mut qik, motor_config);
+    motors.set_encoders(encoders.clone());
+
+    // step the target velocity up and back down, holding each for a second
+    for &v in [0.25_f32, 0.5, 1.0, 0.5, 0.0].iter() {
+        for i in 0..50 {
+            motors.set(Motion::Velocity(v), Motion::Velocity(v));
+            if i % 10 == 0 {
+                println!("Target {:.*} m/s, measured {:?}, ticks {:?}, odometry {:?}",
+                         2, v, encoders.velocities(), encoders.ticks(),
+                         encoders.odometry().map(|o| (o.x, o.y, o.heading)));
+            }
+            thread::sleep(Duration::from_millis(20));
+        }
+    }
+    motors.set(Motion::Brake(127), Motion::Brake(127));
+}
+
 fn test_video(conf: &Config) {
 
-    let gps = GPS::new(conf.gps_device);
+    let gps = GPS::new(conf.gps_device, GpsConfig::new());
     gps.start_thread();
 
-    let mut compass = Compass::new(conf.imu_device);
-    //compass.start_thread().unwrap();
+    let compass = Compass::new(conf.imu_device, load_compass_calibration(conf), CompassConfig::new());
+    compass.start_thread();
 
     let video = Video::new(0);
 
@@ -265,7 +802,7 @@ fn test_video(conf: &Config) {
         video.draw_text(30,
                         y,
                         match gps.get() {
-                            None => format!("GPS: N/A"),
+                            None => "GPS: N/A".to_string(),
                             Some(loc) => format!("GPS: {:.*}, {:.*}", 6, loc.lat, 6, loc.lon),
                         },
                         &c);
@@ -274,7 +811,7 @@ fn test_video(conf: &Config) {
         video.draw_text(30,
                         y,
                         match compass.get() {
-                            None => format!("Compass: N/A"),
+                            None => "Compass: N/A".to_string(),
                             Some(b) => format!("Compass: {:.*}", 1, b),
                         },
                         &c);
@@ -0,0 +1,68 @@
+use std::f64::consts::PI;
+use std::ops::{Add, Rem};
+
+/// an angle in degrees in the range 0..360, for either f32 or f64
+pub fn normalize_degrees<T>(a: T) -> T
+    where T: Copy + PartialOrd + From<f32> + Add<Output = T> + Rem<Output = T>
+{
+    let full = T::from(360_f32);
+    let a = a % full;
+    if a < T::from(0_f32) { a + full } else { a }
+}
+
+/// an angle in radians in the range 0..2 PI
+pub fn normalize_radians(a: f64) -> f64 {
+    let a = a % (2_f64 * PI);
+    if a < 0_f64 { a + 2_f64 * PI } else { a }
+}
+
+/// determinant of a 3x3 matrix
+pub fn det3(m: &[[f64; 3]; 3]) -> f64 {
+    m[0][0] * (m[1][1] * m[2][2] - m[1][2] * m[2][1])
+        - m[0][1] * (m[1][0] * m[2][2] - m[1][2] * m[2][0])
+        + m[0][2] * (m[1][0] * m[2][1] - m[1][1] * m[2][0])
+}
+
+/// solve the 3x3 linear system a.x = b using Cramer's rule, or None if a is singular
+pub fn solve3(a: &[[f64; 3]; 3], b: &[f64; 3]) -> Option<[f64; 3]> {
+    let d = det3(a);
+    if d.abs() < 1e-12 {
+        return None;
+    }
+    let mut x = [0_f64; 3];
+    for i in 0..3 {
+        let mut m = *a;
+        for j in 0..3 {
+            m[j][i] = b[j];
+        }
+        x[i] = det3(&m) / d;
+    }
+    Some(x)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn normalizes_angles() {
+        assert_eq!(normalize_degrees(370_f32), 10_f32);
+        assert_eq!(normalize_degrees(-90_f64), 270_f64);
+        assert_eq!(normalize_degrees(-720_f64), 0_f64);
+        assert_eq!(normalize_degrees(359.5_f32), 359.5_f32);
+        assert!((normalize_radians(-PI / 2_f64) - 3_f64 * PI / 2_f64).abs() < 1e-12);
+        assert!((normalize_radians(5_f64 * PI) - PI).abs() < 1e-12);
+    }
+
+    #[test]
+    fn solves_a_linear_system() {
+        let a = [[2_f64, 1_f64, 0_f64], [1_f64, 3_f64, 1_f64], [0_f64, 1_f64, 4_f64]];
+        let x = solve3(&a, &[4_f64, 10_f64, 14_f64]).unwrap();
+        for (xi, expected) in x.iter().zip([1_f64, 2_f64, 3_f64].iter()) {
+            assert!((xi - expected).abs() < 1e-12, "{:?}", x);
+        }
+        let singular = [[1_f64, 2_f64, 3_f64], [2_f64, 4_f64, 6_f64], [0_f64, 1_f64, 4_f64]];
+        assert_eq!(det3(&singular), 0_f64);
+        assert!(solve3(&singular, &[1_f64, 2_f64, 3_f64]).is_none());
+    }
+}
@@ -1,31 +1,170 @@
 use qik::*;
 
+use std::time::{Duration, Instant};
+
+use super::encoder::WheelEncoders;
+use super::hardware::DriveActuator;
+
+/// the speed controllers are reset if they haven't been updated for this long
+const PID_RESET_TIMEOUT_MS: u64 = 500;
+
 #[derive(Debug,Copy,Clone,PartialEq)]
 pub enum Motion {
     Brake(u8),
     Speed(i8),
+    /// target wheel velocity in m/s, positive forwards
+    Velocity(f32),
+}
+
+/// Tuning for the wheel speed controllers
+#[derive(Debug, Clone)]
+pub struct MotorConfig {
+    /// approximate wheel speed in m/s at full power, used to estimate the power needed for a
+    /// velocity before the controller corrects it
+    pub max_wheel_speed: f64,
+    /// proportional, integral and derivative gains in power (-127 to 127) per m/s of error
+    pub kp: f64,
+    pub ki: f64,
+    pub kd: f64,
+}
+
+impl MotorConfig {
+    pub fn new() -> Self {
+        MotorConfig {
+            max_wheel_speed: 2_f64,
+            kp: 80_f64,
+            ki: 200_f64,
+            kd: 0_f64,
+        }
+    }
+}
+
+/// PID controller with integral windup protection
+#[derive(Debug, Clone)]
+pub struct Pid {
+    kp: f64,
+    ki: f64,
+    kd: f64,
+    /// the output is limited to -limit..limit
+    limit: f64,
+    integral: f64,
+    last_error: Option<f64>,
+}
+
+impl Pid {
+    pub fn new(kp: f64, ki: f64, kd: f64, limit: f64) -> Self {
+        Pid {
+            kp: kp,
+            ki: ki,
+            kd: kd,
+            limit: limit,
+            integral: 0_f64,
+            last_error: None,
+        }
+    }
+
+    /// the output for the error after dt seconds, added to a feed-forward estimate of the
+    /// output needed
+    pub fn update(&mut self, error: f64, dt: f64, feed_forward: f64) -> f64 {
+        let derivative = match self.last_error {
+            Some(e) if dt > 0_f64 => (error - e) / dt,
+            _ => 0_f64,
+        };
+        self.last_error = Some(error);
+
+        // stop integrating while the output is saturated so it can recover quickly, but keep
+        // integrating an error that brings it back out of saturation
+        let integral = self.integral + error * dt;
+        let output = feed_forward + self.kp * error + self.ki * integral + self.kd * derivative;
+        if output.abs() < self.limit || error * output < 0_f64 {
+            self.integral = integral;
+        }
+        output.max(-self.limit).min(self.limit)
+    }
+
+    pub fn reset(&mut self) {
+        self.integral = 0_f64;
+        self.last_error = None;
+    }
 }
 
 pub struct Motors<'a> {
-    qik: &'a mut Qik
+    qik: &'a mut Qik,
+    config: MotorConfig,
+    encoders: Option<WheelEncoders>,
+    /// speed controllers for the left and right wheels
+    pid: (Pid, Pid),
+    last_update: Option<Instant>,
 }
 
 impl<'a> Motors<'a> {
-    pub fn new(qik: &'a mut Qik) -> Self {
+    pub fn new(qik: &'a mut Qik, config: MotorConfig) -> Self {
+        let pid = Pid::new(config.kp, config.ki, config.kd, 127_f64);
         Motors {
             qik: qik,
+            config: config,
+            encoders: None,
+            pid: (pid.clone(), pid),
+            last_update: None,
         }
     }
 
+    /// Use the wheel encoders to control the speed of each wheel when given a velocity.
+    /// Without encoders the power is estimated from `max_wheel_speed`.
+    pub fn set_encoders(&mut self, encoders: WheelEncoders) {
+        self.encoders = Some(encoders);
+    }
+
+    /// Set the motors. Velocities are only tracked if this is called regularly (at least
+    /// every few hundred ms), since the speed controllers are updated on each call.
     pub fn set(&mut self, left: Motion, right: Motion) {
-        self._set(Motor::M0, left);
-        self._set(Motor::M1, right);
+        let now = Instant::now();
+        let dt = match self.last_update {
+            Some(t) if now.duration_since(t) < Duration::from_millis(PID_RESET_TIMEOUT_MS) => {
+                let d = now.duration_since(t);
+                Some(d.as_secs() as f64 + d.subsec_nanos() as f64 / 1_000_000_000_f64)
+            }
+            _ => None,
+        };
+        self.last_update = Some(now);
+
+        let velocities = self.encoders.as_ref().and_then(|e| e.velocities());
+
+        self._set(Motor::M0, left, velocities.map(|v| v.0), dt, true);
+        self._set(Motor::M1, right, velocities.map(|v| v.1), dt, false);
     }
 
-    fn _set(&mut self, m: Motor, n: Motion) {
+    /// the power for a velocity, correcting it with the measured velocity if there is one
+    fn control(&mut self, target: f64, measured: Option<f64>, dt: Option<f64>, left: bool) -> i8 {
+        let feed_forward = target / self.config.max_wheel_speed * 127_f64;
+        let pid = self.pid(left);
+        match (measured, dt) {
+            (Some(v), Some(dt)) => pid.update(target - v, dt, feed_forward) as i8,
+            _ => {
+                pid.reset();
+                feed_forward.clamp(-127_f64, 127_f64) as i8
+            }
+        }
+    }
+
+    fn pid(&mut self, left: bool) -> &mut Pid {
+        if left { &mut self.pid.0 } else { &mut self.pid.1 }
+    }
+
+    fn _set(&mut self, m: Motor, n: Motion, measured: Option<f64>, dt: Option<f64>, left: bool) {
         let m = match n {
-            Motion::Brake(n) => self.qik.set_brake(m, n),
-            Motion::Speed(n) => self.qik.set_speed(m, n),
+            Motion::Brake(n) => {
+                self.pid(left).reset();
+                self.qik.set_brake(m, n)
+            }
+            Motion::Speed(n) => {
+                self.pid(left).reset();
+                self.qik.set_speed(m, n)
+            }
+            Motion::Velocity(v) => {
+                let n = self.control(v as f64, measured, dt, left);
+                self.qik.set_speed(m, n)
+            }
         };
         match m {
             Ok(_) => {},
@@ -35,3 +174,55 @@ impl<'a> Motors<'a> {
         }
     }
 }
+
+impl<'a> DriveActuator for Motors<'a> {
+    fn drive(&mut self, left: Motion, right: Motion) {
+        self.set(left, right);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn tracks_a_constant_error() {
+        let mut pid = Pid::new(0.5, 2_f64, 0_f64, 127_f64);
+        assert_eq!(pid.update(2_f64, 0.1, 10_f64), 10_f64 + 1_f64 + 2_f64 * 0.2);
+        assert_eq!(pid.update(2_f64, 0.1, 10_f64), 10_f64 + 1_f64 + 2_f64 * 0.4);
+        pid.reset();
+        assert_eq!(pid.update(2_f64, 0.1, 10_f64), 10_f64 + 1_f64 + 2_f64 * 0.2);
+    }
+
+    #[test]
+    fn stops_integrating_while_saturated() {
+        let mut pid = Pid::new(0.1, 1_f64, 0_f64, 10_f64);
+        assert!((pid.update(40_f64, 0.1, 0_f64) - 8_f64).abs() < 1e-9);
+        assert!((pid.integral - 4_f64).abs() < 1e-9);
+
+        // the output saturates on the next step and the integral stays where it was
+        for _ in 0..50 {
+            assert_eq!(pid.update(40_f64, 0.1, 0_f64), 10_f64);
+            assert!((pid.integral - 4_f64).abs() < 1e-9);
+        }
+
+        // once the error changes sign the integral unwinds straight away
+        let output = pid.update(-5_f64, 0.1, 0_f64);
+        assert!((pid.integral - 3.5).abs() < 1e-9);
+        assert!((output - 3_f64).abs() < 1e-9);
+    }
+
+    #[test]
+    fn unwinds_while_still_saturated() {
+        // the feed forward keeps the output saturated after the error changes sign, but the
+        // integral must still come down
+        let mut pid = Pid::new(0.1, 1_f64, 0_f64, 10_f64);
+        pid.update(40_f64, 0.1, 0_f64);
+        assert_eq!(pid.update(-1_f64, 0.1, 10_f64), 10_f64);
+        assert!((pid.integral - 3.9).abs() < 1e-9);
+        for _ in 0..10 {
+            pid.update(-1_f64, 0.1, 10_f64);
+        }
+        assert!((pid.integral - 2.9).abs() < 1e-9);
+    }
+}
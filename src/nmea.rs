@@ -0,0 +1,427 @@
+use chrono::{NaiveDate, NaiveTime};
+
+use std::time::Instant;
+
+use super::gps::{FixType, GpsFix};
+
+const KNOTS_TO_METRES_PER_SECOND: f32 = 0.514444;
+
+/// the NMEA sentences that we know how to parse
+#[derive(Debug, Clone, PartialEq)]
+pub enum Sentence {
+    /// fix data
+    GGA {
+        time: Option<NaiveTime>,
+        position: Option<(f64, f64)>,
+        quality: u8,
+        satellites: u8,
+        hdop: Option<f32>,
+        altitude: Option<f32>,
+    },
+    /// recommended minimum data (position is None if the receiver says the data is invalid)
+    RMC {
+        time: Option<NaiveTime>,
+        date: Option<NaiveDate>,
+        position: Option<(f64, f64)>,
+        speed: Option<f32>,
+        course: Option<f32>,
+    },
+    /// course and speed over ground
+    VTG {
+        course: Option<f32>,
+        speed: Option<f32>,
+    },
+    /// DOP and active satellites
+    GSA {
+        fix_type: FixType,
+        satellites: u8,
+        hdop: Option<f32>,
+    },
+    /// satellites in view
+    GSV {
+        satellites_in_view: u8,
+    },
+    /// geographic position (position is None if the receiver says the data is invalid)
+    GLL {
+        time: Option<NaiveTime>,
+        position: Option<(f64, f64)>,
+    },
+    /// a well-formed sentence that we don't use, such as $GPTXT
+    Unsupported(String),
+}
+
+/// Parse a single NMEA sentence, validating the checksum. Returns the talker ID (e.g. "GP",
+/// "GN" or "GL") and the sentence.
+pub fn parse(line: &str) -> Result<(String, Sentence), String> {
+    let line = line.trim();
+
+    // sentences are plain ASCII, and the fields are sliced by byte position below
+    if !line.is_ascii() {
+        return Err(format!("Sentence is not ASCII: {}", line));
+    }
+    if !line.starts_with('$') {
+        return Err(format!("Sentence does not start with '$': {}", line));
+    }
+
+    let star = match line.find('*') {
+        Some(n) => n,
+        None => return Err(format!("Sentence has no checksum: {}", line)),
+    };
+
+    let data = &line[1..star];
+    let expected = match u8::from_str_radix(&line[star + 1..], 16) {
+        Ok(n) => n,
+        Err(_) => return Err(format!("Invalid checksum: {}", line)),
+    };
+    let actual = data.bytes().fold(0_u8, |acc, b| acc ^ b);
+    if actual != expected {
+        return Err(format!("Checksum mismatch (expected {:02X}, actual {:02X}): {}",
+                           expected, actual, line));
+    }
+
+    let parts: Vec<&str> = data.split(',').collect();
+    if parts[0].len() != 5 {
+        return Err(format!("Invalid sentence type: {}", line));
+    }
+    let talker = String::from(&parts[0][0..2]);
+    let f = |i: usize| if i < parts.len() { parts[i] } else { "" };
+
+    let sentence = match &parts[0][2..] {
+        "GGA" => {
+            let quality = parse_u8(f(6)).unwrap_or(0);
+            Sentence::GGA {
+                time: parse_time(f(1)),
+                position: if quality > 0 {
+                    parse_position(f(2), f(3), f(4), f(5))
+                } else {
+                    None
+                },
+                quality: quality,
+                satellites: parse_u8(f(7)).unwrap_or(0),
+                hdop: parse_f32(f(8)),
+                altitude: parse_f32(f(9)),
+            }
+        }
+        "RMC" => {
+            Sentence::RMC {
+                time: parse_time(f(1)),
+                date: parse_date(f(9)),
+                position: if f(2) == "A" {
+                    parse_position(f(3), f(4), f(5), f(6))
+                } else {
+                    None
+                },
+                speed: parse_f32(f(7)).map(|n| n * KNOTS_TO_METRES_PER_SECOND),
+                course: parse_f32(f(8)),
+            }
+        }
+        "VTG" => {
+            Sentence::VTG {
+                course: parse_f32(f(1)),
+                speed: parse_f32(f(5)).map(|n| n * KNOTS_TO_METRES_PER_SECOND),
+            }
+        }
+        "GSA" => {
+            Sentence::GSA {
+                fix_type: match f(2) {
+                    "2" => FixType::Fix2D,
+                    "3" => FixType::Fix3D,
+                    _ => FixType::NoFix,
+                },
+                satellites: (3..15).filter(|&i| !f(i).is_empty()).count() as u8,
+                hdop: parse_f32(f(16)),
+            }
+        }
+        "GSV" => Sentence::GSV { satellites_in_view: parse_u8(f(3)).unwrap_or(0) },
+        "GLL" => {
+            Sentence::GLL {
+                time: parse_time(f(5)),
+                position: if f(6) == "A" {
+                    parse_position(f(1), f(2), f(3), f(4))
+                } else {
+                    None
+                },
+            }
+        }
+        other => Sentence::Unsupported(String::from(other)),
+    };
+
+    Ok((talker, sentence))
+}
+
+/// Combines the information from the stream of NMEA sentences into a single fix
+pub struct NmeaParser {
+    fix: GpsFix,
+    /// satellites in view per talker ID, since each constellation reports separately
+    satellites_in_view: Vec<(String, u8)>,
+}
+
+impl NmeaParser {
+    pub fn new() -> Self {
+        NmeaParser {
+            fix: GpsFix::new(0_f64, 0_f64),
+            satellites_in_view: vec![],
+        }
+    }
+
+    /// Parse a sentence and merge it into the current fix. Returns the updated fix if the
+    /// sentence reported a valid position.
+    pub fn parse_line(&mut self, line: &str) -> Result<Option<GpsFix>, String> {
+        let (talker, sentence) = parse(line)?;
+
+        let mut has_position = false;
+        match sentence {
+            Sentence::GGA { time, position, quality, satellites, hdop, altitude } => {
+                if quality == 0 {
+                    self.fix.fix_type = FixType::NoFix;
+                } else if self.fix.fix_type == FixType::NoFix {
+                    self.fix.fix_type = FixType::Fix2D;
+                }
+                self.update_time(time);
+                self.fix.satellites = satellites;
+                self.fix.hdop = hdop;
+                self.fix.altitude = altitude;
+                has_position = self.update_position(position);
+            }
+            Sentence::RMC { time, date, position, speed, course } => {
+                self.update_time(time);
+                if date.is_some() {
+                    self.fix.date = date;
+                }
+                self.fix.speed = speed;
+                self.fix.course = course;
+                has_position = self.update_position(position);
+            }
+            Sentence::VTG { course, speed } => {
+                self.fix.speed = speed;
+                self.fix.course = course;
+            }
+            Sentence::GSA { fix_type, hdop, .. } => {
+                self.fix.fix_type = fix_type;
+                if hdop.is_some() {
+                    self.fix.hdop = hdop;
+                }
+            }
+            Sentence::GSV { satellites_in_view } => {
+                match self.satellites_in_view.iter().position(|(t, _)| *t == talker) {
+                    Some(i) => self.satellites_in_view[i].1 = satellites_in_view,
+                    None => self.satellites_in_view.push((talker, satellites_in_view)),
+                }
+                self.fix.satellites_in_view =
+                    self.satellites_in_view.iter().fold(0, |acc, &(_, n)| acc + n);
+            }
+            Sentence::GLL { time, position } => {
+                self.update_time(time);
+                has_position = self.update_position(position);
+            }
+            Sentence::Unsupported(_) => {}
+        }
+
+        if has_position {
+            Ok(Some(self.fix.clone()))
+        } else {
+            Ok(None)
+        }
+    }
+
+    fn update_time(&mut self, time: Option<NaiveTime>) {
+        if time.is_some() {
+            self.fix.time = time;
+        }
+    }
+
+    fn update_position(&mut self, position: Option<(f64, f64)>) -> bool {
+        match position {
+            Some((lat, lon)) => {
+                self.fix.lat = lat;
+                self.fix.lon = lon;
+                self.fix.received = Instant::now();
+                if self.fix.fix_type == FixType::NoFix {
+                    self.fix.fix_type = FixType::Fix2D;
+                }
+                true
+            }
+            None => {
+                self.fix.fix_type = FixType::NoFix;
+                false
+            }
+        }
+    }
+}
+
+fn parse_f32(s: &str) -> Option<f32> {
+    s.parse::<f32>().ok()
+}
+
+fn parse_u8(s: &str) -> Option<u8> {
+    s.parse::<u8>().ok()
+}
+
+/// parse hhmmss.sss
+fn parse_time(s: &str) -> Option<NaiveTime> {
+    if s.len() < 6 {
+        return None;
+    }
+    let h = match s[0..2].parse::<u32>() { Ok(n) => n, Err(_) => return None };
+    let m = match s[2..4].parse::<u32>() { Ok(n) => n, Err(_) => return None };
+    let sec = match s[4..].parse::<f64>() { Ok(n) => n, Err(_) => return None };
+    let milli = ((sec - sec.floor()) * 1000_f64).round() as u32;
+    NaiveTime::from_hms_milli_opt(h, m, sec.floor() as u32, milli)
+}
+
+/// parse ddmmyy
+fn parse_date(s: &str) -> Option<NaiveDate> {
+    if s.len() != 6 {
+        return None;
+    }
+    let d = match s[0..2].parse::<u32>() { Ok(n) => n, Err(_) => return None };
+    let m = match s[2..4].parse::<u32>() { Ok(n) => n, Err(_) => return None };
+    let y = match s[4..6].parse::<i32>() { Ok(n) => n, Err(_) => return None };
+    NaiveDate::from_ymd_opt(2000 + y, m, d)
+}
+
+/// parse latitude (ddmm.mmmm) and longitude (dddmm.mmmm) into signed decimal degrees
+fn parse_position(lat: &str, ns: &str, lon: &str, ew: &str) -> Option<(f64, f64)> {
+    let lat = match (parse_degrees(lat, 2), ns) {
+        (Some(n), "N") => n,
+        (Some(n), "S") => -n,
+        _ => return None,
+    };
+    let lon = match (parse_degrees(lon, 3), ew) {
+        (Some(n), "E") => n,
+        (Some(n), "W") => -n,
+        _ => return None,
+    };
+    Some((lat, lon))
+}
+
+fn parse_degrees(s: &str, degree_digits: usize) -> Option<f64> {
+    if s.len() <= degree_digits {
+        return None;
+    }
+    match (s[0..degree_digits].parse::<f64>(), s[degree_digits..].parse::<f64>()) {
+        (Ok(d), Ok(m)) => Some(d + m / 60_f64),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn near(a: f64, b: f64) -> bool {
+        (a - b).abs() < 1e-6
+    }
+
+    #[test]
+    fn parses_gga() {
+        let (talker, s) = parse("$GPGGA,123519,4807.038,N,01131.000,E,1,08,0.9,545.4,M,46.9,M,,*47").unwrap();
+        assert_eq!(talker, "GP");
+        match s {
+            Sentence::GGA { time, position: Some((lat, lon)), quality, satellites, hdop, altitude } => {
+                assert_eq!(time, NaiveTime::from_hms_opt(12, 35, 19));
+                assert!(near(lat, 48.1173) && near(lon, 11.516666666666667));
+                assert_eq!((quality, satellites, hdop, altitude), (1, 8, Some(0.9), Some(545.4)));
+            }
+            other => panic!("{:?}", other),
+        }
+
+        let (talker, s) = parse("$GNGGA,001043.00,4404.14036,N,12118.85961,W,1,12,0.98,1113.0,M,-21.3,M,,*47").unwrap();
+        assert_eq!(talker, "GN");
+        match s {
+            Sentence::GGA { position: Some((lat, lon)), satellites: 12, hdop: Some(hdop), .. } => {
+                assert!(near(lat, 44.069006) && near(lon, -121.31432683333333));
+                assert_eq!(hdop, 0.98);
+            }
+            other => panic!("{:?}", other),
+        }
+    }
+
+    #[test]
+    fn parses_rmc() {
+        let (_, s) = parse("$GPRMC,123519,A,4807.038,N,01131.000,E,022.4,084.4,230394,003.1,W*6A").unwrap();
+        match s {
+            Sentence::RMC { time, date, position: Some(_), speed: Some(speed), course } => {
+                assert_eq!(time, NaiveTime::from_hms_opt(12, 35, 19));
+                // two digit years are taken to be in this century
+                assert_eq!(date, NaiveDate::from_ymd_opt(2094, 3, 23));
+                assert!((speed - 22.4 * KNOTS_TO_METRES_PER_SECOND).abs() < 1e-4);
+                assert_eq!(course, Some(84.4));
+            }
+            other => panic!("{:?}", other),
+        }
+
+        // NMEA 4.1 adds a mode field, and the course is empty when stationary
+        let (talker, s) = parse("$GNRMC,001031.00,A,4404.13993,N,12118.86023,W,0.146,,100117,,,A*7B").unwrap();
+        assert_eq!(talker, "GN");
+        match s {
+            Sentence::RMC { date, position: Some(_), course: None, .. } => {
+                assert_eq!(date, NaiveDate::from_ymd_opt(2017, 1, 10));
+            }
+            other => panic!("{:?}", other),
+        }
+    }
+
+    #[test]
+    fn parses_vtg_gsa_gsv_and_gll() {
+        assert_eq!(parse("$GPVTG,054.7,T,034.4,M,005.5,N,010.2,K*48").unwrap().1,
+                   Sentence::VTG { course: Some(54.7), speed: Some(5.5 * KNOTS_TO_METRES_PER_SECOND) });
+        assert_eq!(parse("$GPGSA,A,3,04,05,,09,12,,,24,,,,,2.5,1.3,2.1*39").unwrap().1,
+                   Sentence::GSA { fix_type: FixType::Fix3D, satellites: 5, hdop: Some(1.3) });
+        assert_eq!(parse("$GNGSA,A,3,80,71,73,79,69,,,,,,,,1.83,1.09,1.47*17").unwrap().1,
+                   Sentence::GSA { fix_type: FixType::Fix3D, satellites: 5, hdop: Some(1.09) });
+        assert_eq!(parse("$GPGSV,2,1,08,01,40,083,46,02,17,308,41,12,07,344,39,14,22,228,45*75").unwrap().1,
+                   Sentence::GSV { satellites_in_view: 8 });
+        let (talker, s) = parse("$GLGSV,3,1,10,65,32,167,28,66,63,314,31,67,30,290,30,72,22,109,25*6F").unwrap();
+        assert_eq!((&talker[..], s), ("GL", Sentence::GSV { satellites_in_view: 10 }));
+        match parse("$GPGLL,4916.45,N,12311.12,W,225444,A,*1D").unwrap().1 {
+            Sentence::GLL { time, position: Some((lat, lon)) } => {
+                assert_eq!(time, NaiveTime::from_hms_opt(22, 54, 44));
+                assert!(near(lat, 49.274166666666666) && near(lon, -123.18533333333333));
+            }
+            other => panic!("{:?}", other),
+        }
+    }
+
+    #[test]
+    fn handles_empty_fields() {
+        match parse("$GPGGA,123519,4807.038,N,01131.000,E,1,08,,,M,,M,,*5B").unwrap().1 {
+            Sentence::GGA { position: Some(_), satellites: 8, hdop: None, altitude: None, .. } => {}
+            other => panic!("{:?}", other),
+        }
+        match parse("$GPRMC,123519,V,,,,,,,230394,,*33").unwrap().1 {
+            Sentence::RMC { position: None, speed: None, course: None, .. } => {}
+            other => panic!("{:?}", other),
+        }
+    }
+
+    #[test]
+    fn rejects_bad_sentences() {
+        assert!(parse("$GPVTG,054.7,T,034.4,M,005.5,N,010.2,K*49").is_err());
+        assert!(parse("$GPVTG,054.7,T,034.4,M,005.5,N,010.2,K").is_err());
+        assert!(parse("GPVTG,054.7,T,034.4,M,005.5,N,010.2,K*48").is_err());
+        assert!(parse("$GPVTG,054.7,T,034.4,M,005.5,N,010.2,K*XX").is_err());
+        // these have valid checksums but used to panic when slicing the multibyte character
+        assert!(parse("$G\u{e9}GA,123519,4807.038,N,01131.000,E,1,08,0.9,545.4,M,46.9,M,,*3A").is_err());
+        assert!(parse("$GPGLL,4916.45,N,12311.12,W,2\u{e9}5444,A,*45").is_err());
+    }
+
+    #[test]
+    fn merges_sentences_into_a_fix() {
+        let mut parser = NmeaParser::new();
+        assert!(parser.parse_line("$GPGSV,2,1,08,01,40,083,46,02,17,308,41,12,07,344,39,14,22,228,45*75").unwrap().is_none());
+        assert!(parser.parse_line("$GLGSV,3,1,10,65,32,167,28,66,63,314,31,67,30,290,30,72,22,109,25*6F").unwrap().is_none());
+        assert!(parser.parse_line("$GPGSA,A,3,04,05,,09,12,,,24,,,,,2.5,1.3,2.1*39").unwrap().is_none());
+        let fix = parser.parse_line("$GPGGA,123519,4807.038,N,01131.000,E,1,08,0.9,545.4,M,46.9,M,,*47").unwrap().unwrap();
+        assert_eq!(fix.fix_type, FixType::Fix3D);
+        assert_eq!((fix.satellites, fix.satellites_in_view), (8, 18));
+        assert_eq!(fix.hdop, Some(0.9));
+
+        let fix = parser.parse_line("$GPRMC,123519,A,4807.038,N,01131.000,E,022.4,084.4,230394,003.1,W*6A").unwrap().unwrap();
+        assert_eq!(fix.date, NaiveDate::from_ymd_opt(2094, 3, 23));
+        assert_eq!(fix.course, Some(84.4));
+
+        // losing the fix
+        assert!(parser.parse_line("$GPRMC,123519,V,,,,,,,230394,,*33").unwrap().is_none());
+    }
+}
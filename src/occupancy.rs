@@ -0,0 +1,358 @@
+use std::time::Instant;
+
+use super::frame::Point;
+use super::perception::Sector;
+use super::scan::{Scan, MAX_DISTANCE};
+
+/// size of each cell in metres
+const RESOLUTION: f64 = 0.1;
+
+/// number of cells along each side of the grid
+const GRID_SIZE: usize = 400;
+
+/// the grid is moved to keep the vehicle at least this many cells from its edges
+const RECENTER_MARGIN: usize = 100;
+
+/// log odds added to a cell that a LIDAR beam ended in
+const LOG_ODDS_OCCUPIED: f32 = 0.85;
+
+/// log odds added to a cell that a LIDAR beam passed through
+const LOG_ODDS_FREE: f32 = -0.4;
+
+/// log odds are limited to this range so that cells can change state quickly
+const MAX_LOG_ODDS: f32 = 4_f32;
+
+/// log odds decay towards zero (unknown) with this time constant in seconds
+const DECAY_TIME_CONSTANT: f32 = 10_f32;
+
+/// cells more likely than this to be occupied are treated as obstacles
+pub const OCCUPIED_PROBABILITY: f32 = 0.65;
+
+/// Where the vehicle is and which way it is pointing
+#[derive(Debug, Clone)]
+pub struct Pose {
+    /// position in the local frame
+    pub position: Point,
+    /// degrees clockwise from true north
+    pub heading: f32,
+}
+
+/// A square patch of the grid around the vehicle, rotated so that straight ahead is up
+#[derive(Debug, Clone)]
+pub struct LocalMap {
+    /// number of cells along each side
+    pub size: usize,
+    /// size of each cell in metres
+    pub resolution: f64,
+    /// probability that each cell is occupied, row by row starting at the front left
+    pub cells: Vec<f32>,
+}
+
+impl LocalMap {
+    /// probability that the cell is occupied
+    pub fn get(&self, row: usize, col: usize) -> f32 {
+        self.cells[row * self.size + col]
+    }
+
+    /// position of the middle of the cell in metres (x to the right, y straight ahead)
+    pub fn position(&self, row: usize, col: usize) -> (f64, f64) {
+        let half = self.size as f64 / 2_f64;
+        ((col as f64 + 0.5 - half) * self.resolution, (half - row as f64 - 0.5) * self.resolution)
+    }
+
+    /// distance in cm to the nearest occupied cell in each of the sectors, if there is one,
+    /// looking at each cell only once
+    pub fn min_distances(&self, sectors: &[&Sector]) -> Vec<Option<u32>> {
+        let mut min: Vec<Option<f64>> = vec![None; sectors.len()];
+        for row in 0..self.size {
+            for col in 0..self.size {
+                if self.get(row, col) <= OCCUPIED_PROBABILITY {
+                    continue;
+                }
+                let (x, y) = self.position(row, col);
+                let angle = x.atan2(y).to_degrees();
+                let angle = if angle < 0_f64 { angle + 360_f64 } else { angle };
+                let d = (x * x + y * y).sqrt();
+                for (sector, m) in sectors.iter().zip(min.iter_mut()) {
+                    if sector.contains(angle as f32) && m.is_none_or(|m| d < m) {
+                        *m = Some(d);
+                    }
+                }
+            }
+        }
+        min.iter().map(|m| m.map(|d| (d * 100_f64) as u32)).collect()
+    }
+}
+
+/// Log-odds occupancy grid of the area around the vehicle, built up from successive LIDAR
+/// scans. Each cell covers RESOLUTION metres and the grid follows the vehicle, forgetting
+/// anything that falls off its edges.
+pub struct OccupancyGrid {
+    /// position in metres (east, north) of the corner of cell (0, 0) in the local frame
+    corner: (f64, f64),
+    /// log odds of each cell being occupied, row by row from the south west corner
+    cells: Vec<f32>,
+    last_scan: Option<Instant>,
+}
+
+impl OccupancyGrid {
+    pub fn new() -> Self {
+        OccupancyGrid {
+            corner: (0_f64, 0_f64),
+            cells: vec![0_f32; GRID_SIZE * GRID_SIZE],
+            last_scan: None,
+        }
+    }
+
+    /// Add a scan taken at the given pose. Each beam clears the cells it passed through and
+    /// marks the cell it ended in as occupied. Scans that have already been added are ignored.
+    pub fn integrate(&mut self, scan: &Scan, pose: &Pose) {
+        if self.last_scan == Some(scan.finished) {
+            return;
+        }
+        let (east, north) = (pose.position.east, pose.position.north);
+        match self.last_scan {
+            Some(last) => {
+                let elapsed = scan.finished.duration_since(last);
+                self.decay(elapsed.as_secs() as f32 + elapsed.subsec_nanos() as f32 / 1_000_000_000_f32);
+            }
+            None => {
+                // center the grid on the first pose
+                let half = GRID_SIZE as f64 * RESOLUTION / 2_f64;
+                self.corner = (east - half, north - half);
+            }
+        }
+        self.last_scan = Some(scan.finished);
+        self.recenter(east, north);
+
+        let start = match self.cell(east, north) {
+            Some(c) => c,
+            None => return,
+        };
+
+        for p in scan.points.iter().filter(|p| p.is_good()) {
+            // nothing was detected along this beam, but it still shows the space is clear
+            let hit = p.distance < MAX_DISTANCE;
+            let (x, y) = p.position();
+            let (e, n) = vehicle_to_world((east, north), pose.heading, x as f64 / 100_f64, y as f64 / 100_f64);
+            let end = (((e - self.corner.0) / RESOLUTION).floor() as i64,
+                       ((n - self.corner.1) / RESOLUTION).floor() as i64);
+
+            let ray = line(start, end);
+            let free = if hit { ray.len() - 1 } else { ray.len() };
+            for &(col, row) in ray[..free].iter() {
+                self.add(col, row, LOG_ODDS_FREE);
+            }
+            if hit {
+                self.add(end.0, end.1, LOG_ODDS_OCCUPIED);
+            }
+        }
+    }
+
+    /// Sample a square of `size` by `size` cells of `resolution` metres centered on the pose,
+    /// in the vehicle frame
+    pub fn local_map(&self, pose: &Pose, size: usize, resolution: f64) -> LocalMap {
+        let mut map = LocalMap {
+            size: size,
+            resolution: resolution,
+            cells: vec![0.5_f32; size * size],
+        };
+        if self.last_scan.is_none() {
+            return map;
+        }
+        let (east, north) = (pose.position.east, pose.position.north);
+        for row in 0..size {
+            for col in 0..size {
+                let (x, y) = map.position(row, col);
+                let (e, n) = vehicle_to_world((east, north), pose.heading, x, y);
+                if let Some((c, r)) = self.cell(e, n) {
+                    map.cells[row * size + col] = probability(self.cells[r as usize * GRID_SIZE + c as usize]);
+                }
+            }
+        }
+        map
+    }
+
+    /// fade every cell towards unknown
+    fn decay(&mut self, seconds: f32) {
+        let factor = (-seconds / DECAY_TIME_CONSTANT).exp();
+        for l in self.cells.iter_mut() {
+            *l *= factor;
+        }
+    }
+
+    /// shift the grid by whole cells if the vehicle is getting close to an edge
+    fn recenter(&mut self, east: f64, north: f64) {
+        let col = ((east - self.corner.0) / RESOLUTION).floor() as i64;
+        let row = ((north - self.corner.1) / RESOLUTION).floor() as i64;
+        let (min, max) = (RECENTER_MARGIN as i64, (GRID_SIZE - RECENTER_MARGIN) as i64);
+        if col >= min && col < max && row >= min && row < max {
+            return;
+        }
+
+        let half = (GRID_SIZE / 2) as i64;
+        let (dc, dr) = (col - half, row - half);
+        let mut cells = vec![0_f32; GRID_SIZE * GRID_SIZE];
+        for r in 0..GRID_SIZE as i64 {
+            for c in 0..GRID_SIZE as i64 {
+                let (oc, or) = (c + dc, r + dr);
+                if oc >= 0 && oc < GRID_SIZE as i64 && or >= 0 && or < GRID_SIZE as i64 {
+                    cells[(r * GRID_SIZE as i64 + c) as usize] = self.cells[(or * GRID_SIZE as i64 + oc) as usize];
+                }
+            }
+        }
+        self.cells = cells;
+        self.corner = (self.corner.0 + dc as f64 * RESOLUTION, self.corner.1 + dr as f64 * RESOLUTION);
+    }
+
+    fn add(&mut self, col: i64, row: i64, log_odds: f32) {
+        if col >= 0 && col < GRID_SIZE as i64 && row >= 0 && row < GRID_SIZE as i64 {
+            let l = &mut self.cells[row as usize * GRID_SIZE + col as usize];
+            *l = (*l + log_odds).clamp(-MAX_LOG_ODDS, MAX_LOG_ODDS);
+        }
+    }
+
+    /// the (column, row) of the cell containing the position, if it is on the grid
+    fn cell(&self, east: f64, north: f64) -> Option<(i64, i64)> {
+        let col = ((east - self.corner.0) / RESOLUTION).floor() as i64;
+        let row = ((north - self.corner.1) / RESOLUTION).floor() as i64;
+        if col >= 0 && col < GRID_SIZE as i64 && row >= 0 && row < GRID_SIZE as i64 {
+            Some((col, row))
+        } else {
+            None
+        }
+    }
+}
+
+/// convert a position in the vehicle frame (x to the right, y straight ahead, in metres) to
+/// metres east and north
+fn vehicle_to_world(position: (f64, f64), heading: f32, x: f64, y: f64) -> (f64, f64) {
+    let (s, c) = (heading as f64).to_radians().sin_cos();
+    (position.0 + x * c + y * s, position.1 - x * s + y * c)
+}
+
+fn probability(log_odds: f32) -> f32 {
+    1_f32 - 1_f32 / (1_f32 + log_odds.exp())
+}
+
+/// the cells on a straight line between two cells, including both ends (Bresenham)
+fn line(start: (i64, i64), end: (i64, i64)) -> Vec<(i64, i64)> {
+    let (dx, dy) = ((end.0 - start.0).abs(), -(end.1 - start.1).abs());
+    let (sx, sy) = (if start.0 < end.0 { 1 } else { -1 }, if start.1 < end.1 { 1 } else { -1 });
+    let mut err = dx + dy;
+    let (mut x, mut y) = start;
+    let mut cells = vec![];
+    loop {
+        cells.push((x, y));
+        if x == end.0 && y == end.1 {
+            break;
+        }
+        let e2 = 2 * err;
+        if e2 >= dy {
+            err += dy;
+            x += sx;
+        }
+        if e2 <= dx {
+            err += dx;
+            y += sy;
+        }
+    }
+    cells
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::time::{Duration, Instant};
+
+    use super::super::scan::ScanPoint;
+
+    fn scan(points: &[(f32, u32)], finished: Instant) -> Scan {
+        let points = points.iter()
+            .map(|&(angle, distance)| {
+                ScanPoint {
+                    angle: angle,
+                    distance: distance,
+                    signal_strength: 200,
+                    timestamp: finished,
+                }
+            })
+            .collect();
+        Scan::new(points, finished, finished)
+    }
+
+    /// facing east from the middle of a cell
+    fn pose() -> Pose {
+        Pose {
+            position: Point::new(0.05, 0.05),
+            heading: 90_f32,
+        }
+    }
+
+    fn log_odds(grid: &OccupancyGrid, east: f64, north: f64) -> f32 {
+        let (c, r) = grid.cell(east, north).unwrap();
+        grid.cells[r as usize * GRID_SIZE + c as usize]
+    }
+
+    #[test]
+    fn marks_the_hit_cell_occupied_and_the_ray_free() {
+        let mut grid = OccupancyGrid::new();
+        grid.integrate(&scan(&[(0_f32, 200)], Instant::now()), &pose());
+
+        assert_eq!(log_odds(&grid, 2.05, 0.05), LOG_ODDS_OCCUPIED);
+        assert!(probability(log_odds(&grid, 2.05, 0.05)) > OCCUPIED_PROBABILITY);
+        for i in 0..20 {
+            assert_eq!(log_odds(&grid, 0.05 + i as f64 * RESOLUTION, 0.05), LOG_ODDS_FREE);
+        }
+        // beyond the hit and beside the beam is still unknown
+        assert_eq!(log_odds(&grid, 2.55, 0.05), 0_f32);
+        assert_eq!(log_odds(&grid, 1.05, 0.55), 0_f32);
+    }
+
+    #[test]
+    fn clears_the_whole_beam_when_nothing_is_detected() {
+        let mut grid = OccupancyGrid::new();
+        grid.integrate(&scan(&[(0_f32, MAX_DISTANCE)], Instant::now()), &pose());
+
+        let end = MAX_DISTANCE as f64 / 100_f64 + 0.05;
+        assert_eq!(log_odds(&grid, end, 0.05), LOG_ODDS_FREE);
+        assert_eq!(log_odds(&grid, end - 5_f64, 0.05), LOG_ODDS_FREE);
+    }
+
+    #[test]
+    fn ignores_a_scan_that_has_already_been_added() {
+        let mut grid = OccupancyGrid::new();
+        let s = scan(&[(0_f32, 200)], Instant::now());
+        grid.integrate(&s, &pose());
+        grid.integrate(&s, &pose());
+        assert_eq!(log_odds(&grid, 2.05, 0.05), LOG_ODDS_OCCUPIED);
+    }
+
+    #[test]
+    fn fades_towards_unknown() {
+        let now = Instant::now();
+        let mut grid = OccupancyGrid::new();
+        grid.integrate(&scan(&[(0_f32, 200)], now), &pose());
+        let later = now + Duration::from_secs(DECAY_TIME_CONSTANT as u64);
+        grid.integrate(&scan(&[], later), &pose());
+
+        let expected = LOG_ODDS_OCCUPIED * (-1_f32).exp();
+        assert!((log_odds(&grid, 2.05, 0.05) - expected).abs() < 1e-4);
+        let expected = LOG_ODDS_FREE * (-1_f32).exp();
+        assert!((log_odds(&grid, 1.05, 0.05) - expected).abs() < 1e-4);
+    }
+
+    #[test]
+    fn finds_the_nearest_obstacle_in_each_sector() {
+        let mut grid = OccupancyGrid::new();
+        grid.integrate(&scan(&[(0_f32, 200), (90_f32, 150)], Instant::now()), &pose());
+
+        let map = grid.local_map(&pose(), 60, 0.1);
+        let (ahead, right, left) = (Sector::new(315, 45), Sector::new(45, 135), Sector::new(225, 315));
+        let d = map.min_distances(&[&ahead, &right, &left]);
+        assert!(d[0].unwrap() >= 195 && d[0].unwrap() <= 205, "{:?}", d);
+        assert!(d[1].unwrap() >= 145 && d[1].unwrap() <= 155, "{:?}", d);
+        assert_eq!(d[2], None);
+    }
+}
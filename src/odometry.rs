@@ -0,0 +1,59 @@
+use std::time::Instant;
+
+use super::maths::normalize_degrees;
+
+/// How far the vehicle has moved since an odometry source was started, in the source's own
+/// frame: x to the right of and y ahead of the vehicle's starting position, in metres
+#[derive(Debug, Clone)]
+pub struct Odometry {
+    pub x: f64,
+    pub y: f64,
+    /// change in heading in degrees (clockwise) since the start
+    pub heading: f64,
+    /// standard deviation of the translation (metres) and rotation (degrees) of the latest
+    /// step, for weighting the odometry against other sources
+    pub position_sd: f64,
+    pub heading_sd: f64,
+    /// number of steps that have been integrated
+    pub steps: u64,
+    pub updated: Instant,
+}
+
+impl Odometry {
+    pub fn new() -> Self {
+        Odometry {
+            x: 0_f64,
+            y: 0_f64,
+            heading: 0_f64,
+            position_sd: 0_f64,
+            heading_sd: 0_f64,
+            steps: 0,
+            updated: Instant::now(),
+        }
+    }
+
+    /// Add a step of `right` and `forward` metres in the vehicle frame at the start of the
+    /// step, followed by a clockwise turn in degrees
+    pub fn add(&mut self, right: f64, forward: f64, turn: f64) {
+        let (s, c) = self.heading.to_radians().sin_cos();
+        self.x += right * c + forward * s;
+        self.y += -right * s + forward * c;
+        self.heading = normalize_degrees(self.heading + turn);
+        self.steps += 1;
+        self.updated = Instant::now();
+    }
+
+    /// The movement since an earlier reading from the same source as (right, forward, turn),
+    /// in metres in the vehicle frame at the time of the earlier reading and degrees clockwise
+    pub fn since(&self, earlier: &Odometry) -> (f64, f64, f64) {
+        let (dx, dy) = (self.x - earlier.x, self.y - earlier.y);
+        let (s, c) = earlier.heading.to_radians().sin_cos();
+        let mut turn = self.heading - earlier.heading;
+        if turn > 180_f64 {
+            turn -= 360_f64;
+        } else if turn < -180_f64 {
+            turn += 360_f64;
+        }
+        (dx * c - dy * s, dx * s + dy * c, turn)
+    }
+}
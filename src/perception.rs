@@ -0,0 +1,288 @@
+use super::hardware::{RangeScanner, UltrasonicSensors};
+use super::occupancy::LocalMap;
+use super::ultrasonic::UltrasonicRanges;
+
+/// the LIDAR reports this distance (in cm) when nothing was detected
+pub const LIDAR_MAX_RANGE: u32 = 1000;
+
+/// the ultrasonic sensors can't see further than this (in cm), since the Octasonic reports
+/// each distance in a single byte
+pub const ULTRASONIC_MAX_RANGE: u32 = 255;
+
+/// readings within this many cm (or 20%) of each other are considered to agree
+const AGREEMENT_TOLERANCE: u32 = 30;
+
+/// confidence when only the LIDAR covers a sector
+const LIDAR_ONLY_CONFIDENCE: f32 = 0.7;
+
+/// confidence when only the ultrasonic sensor covers a sector
+const ULTRASONIC_ONLY_CONFIDENCE: f32 = 0.6;
+
+/// confidence when both sensors cover a sector but disagree
+const DISAGREEMENT_CONFIDENCE: f32 = 0.5;
+
+/// A range of LIDAR angles in degrees (0 = straight ahead, clockwise), wrapping around
+/// through zero if start > end
+#[derive(Debug, Clone)]
+pub struct Sector {
+    pub start: usize,
+    pub end: usize,
+}
+
+impl Sector {
+    pub fn new(start: usize, end: usize) -> Self {
+        Sector { start: start, end: end }
+    }
+
+    /// is the angle (0..360) within the sector?
+    pub fn contains(&self, angle: f32) -> bool {
+        let (start, end) = (self.start as f32, self.end as f32);
+        if start <= end {
+            angle >= start && angle < end
+        } else {
+            angle >= start || angle < end
+        }
+    }
+}
+
+/// The LIDAR sectors covered by each of the ultrasonic sensors
+#[derive(Debug, Clone)]
+pub struct Sectors {
+    pub side_left: Sector,
+    pub front_left: Sector,
+    pub front: Sector,
+    pub front_right: Sector,
+    pub side_right: Sector,
+}
+
+impl Sectors {
+    pub fn new() -> Self {
+        Sectors {
+            side_left: Sector::new(250, 290),
+            front_left: Sector::new(225, 315),
+            front: Sector::new(315, 45),
+            front_right: Sector::new(45, 135),
+            side_right: Sector::new(70, 110),
+        }
+    }
+}
+
+/// The fused distance to the nearest obstacle in a sector
+#[derive(Debug, Clone, PartialEq)]
+pub struct SectorReading {
+    /// distance in cm
+    pub distance: u32,
+    /// how much we trust the distance, from 0 (no data) to 1 (both sensors agree)
+    pub confidence: f32,
+    /// what each sensor reported
+    pub lidar: Option<u32>,
+    pub ultrasonic: Option<u32>,
+}
+
+impl SectorReading {
+    pub fn none() -> Self {
+        SectorReading {
+            distance: LIDAR_MAX_RANGE,
+            confidence: 0_f32,
+            lidar: None,
+            ultrasonic: None,
+        }
+    }
+
+    /// is there an obstacle closer than the distance that we're confident about?
+    pub fn blocked(&self, distance: u32, min_confidence: f32) -> bool {
+        self.distance < distance && self.confidence >= min_confidence
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Obstacles {
+    pub side_left: SectorReading,
+    pub front_left: SectorReading,
+    pub front: SectorReading,
+    pub front_right: SectorReading,
+    pub side_right: SectorReading,
+}
+
+impl Obstacles {
+    pub fn new() -> Self {
+        Obstacles {
+            side_left: SectorReading::none(),
+            front_left: SectorReading::none(),
+            front: SectorReading::none(),
+            front_right: SectorReading::none(),
+            side_right: SectorReading::none(),
+        }
+    }
+}
+
+/// Combines the LIDAR scan with the ultrasonic sensors, which catch glass and low objects
+/// that the LIDAR misses
+pub struct ObstacleDetector {
+    sectors: Sectors,
+}
+
+impl ObstacleDetector {
+    pub fn new(sectors: Sectors) -> Self {
+        ObstacleDetector { sectors: sectors }
+    }
+
+    pub fn detect<R, U>(&self, lidar: &R, ultrasonic: &U) -> Obstacles
+        where R: RangeScanner,
+              U: UltrasonicSensors
+    {
+        let s = &self.sectors;
+        let lidar_min = |sector: &Sector| Some(lidar.min_distance(sector.start, sector.end));
+        let u = ultrasonic.ranges();
+        let us = |f: fn(&UltrasonicRanges) -> u32| u.as_ref().map(f);
+
+        Obstacles {
+            side_left: fuse(lidar_min(&s.side_left), us(|r| r.side_left)),
+            front_left: fuse(lidar_min(&s.front_left), us(|r| r.front_left)),
+            front: fuse(lidar_min(&s.front), us(|r| r.front)),
+            front_right: fuse(lidar_min(&s.front_right), us(|r| r.front_right)),
+            side_right: fuse(lidar_min(&s.side_right), us(|r| r.side_right)),
+        }
+    }
+
+    /// Add the obstacles remembered by the occupancy grid, which may have left the sensors'
+    /// view since they were seen
+    pub fn include_map(&self, obstacles: &mut Obstacles, map: &LocalMap) {
+        let s = &self.sectors;
+        let d = map.min_distances(&[&s.side_left, &s.front_left, &s.front, &s.front_right,
+                                     &s.side_right]);
+        remember(&mut obstacles.side_left, d[0]);
+        remember(&mut obstacles.front_left, d[1]);
+        remember(&mut obstacles.front, d[2]);
+        remember(&mut obstacles.front_right, d[3]);
+        remember(&mut obstacles.side_right, d[4]);
+    }
+}
+
+/// use a mapped obstacle if it is nearer than what the sensors can see now. The grid is built
+/// from the LIDAR, so it is trusted as much as the LIDAR alone.
+fn remember(reading: &mut SectorReading, mapped: Option<u32>) {
+    if let Some(d) = mapped {
+        if d < reading.distance {
+            reading.distance = d;
+            reading.confidence = reading.confidence.max(LIDAR_ONLY_CONFIDENCE);
+        }
+    }
+}
+
+/// Fuse the readings for a sector. None means the sensor isn't working. The sensors
+/// report their maximum range (or zero, for the ultrasonic sensors) when nothing is detected.
+pub fn fuse(lidar: Option<u32>, ultrasonic: Option<u32>) -> SectorReading {
+    let ultrasonic = ultrasonic.map(|d| {
+        if d == 0 || d >= ULTRASONIC_MAX_RANGE { ULTRASONIC_MAX_RANGE } else { d }
+    });
+    let (distance, confidence) = match (lidar, ultrasonic) {
+        (None, None) => (LIDAR_MAX_RANGE, 0_f32),
+        (Some(l), None) => (l, LIDAR_ONLY_CONFIDENCE),
+        (None, Some(u)) => {
+            if u == ULTRASONIC_MAX_RANGE {
+                (LIDAR_MAX_RANGE, ULTRASONIC_ONLY_CONFIDENCE)
+            } else {
+                (u, ULTRASONIC_ONLY_CONFIDENCE)
+            }
+        }
+        (Some(l), Some(u)) => {
+            if u == ULTRASONIC_MAX_RANGE {
+                // the ultrasonic sensor can only confirm that nothing is within its range
+                let c = if l + tolerance(u) >= u { 1_f32 } else { DISAGREEMENT_CONFIDENCE };
+                (l, c)
+            } else if (l as i64 - u as i64).unsigned_abs() as u32 <= tolerance(l.min(u)) {
+                (l.min(u), 1_f32)
+            } else {
+                (l.min(u), DISAGREEMENT_CONFIDENCE)
+            }
+        }
+    };
+    SectorReading {
+        distance: distance,
+        confidence: confidence,
+        lidar: lidar,
+        ultrasonic: ultrasonic,
+    }
+}
+
+fn tolerance(d: u32) -> u32 {
+    AGREEMENT_TOLERANCE.max(d / 5)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn trusts_agreeing_sensors_most() {
+        let r = fuse(Some(150), Some(160));
+        assert_eq!(r.distance, 150);
+        assert_eq!(r.confidence, 1_f32);
+        assert_eq!((r.lidar, r.ultrasonic), (Some(150), Some(160)));
+
+        // 20% of the distance when that is more than the fixed tolerance
+        assert_eq!(fuse(Some(200), Some(240)).confidence, 1_f32);
+        assert_eq!(fuse(Some(200), Some(245)).confidence, DISAGREEMENT_CONFIDENCE);
+    }
+
+    #[test]
+    fn uses_the_nearer_reading_when_the_sensors_disagree() {
+        // the ultrasonic sensor sees glass that the LIDAR doesn't
+        let r = fuse(Some(800), Some(100));
+        assert_eq!(r.distance, 100);
+        assert_eq!(r.confidence, DISAGREEMENT_CONFIDENCE);
+        assert!(r.blocked(150, 0.5));
+        assert!(!r.blocked(150, 0.6));
+    }
+
+    #[test]
+    fn falls_back_to_a_single_sensor() {
+        let r = fuse(Some(200), None);
+        assert_eq!((r.distance, r.confidence), (200, LIDAR_ONLY_CONFIDENCE));
+        let r = fuse(None, Some(200));
+        assert_eq!((r.distance, r.confidence), (200, ULTRASONIC_ONLY_CONFIDENCE));
+        assert_eq!(fuse(None, None), SectorReading::none());
+    }
+
+    #[test]
+    fn treats_an_ultrasonic_miss_as_clear_within_its_range() {
+        // nothing within the ultrasonic sensor's range confirms a distant LIDAR reading
+        for &miss in [0, ULTRASONIC_MAX_RANGE, 400].iter() {
+            let r = fuse(Some(700), Some(miss));
+            assert_eq!(r.distance, 700);
+            assert_eq!(r.confidence, 1_f32);
+            assert_eq!(r.ultrasonic, Some(ULTRASONIC_MAX_RANGE));
+        }
+        // and doesn't say anything about what is further away
+        assert_eq!(fuse(Some(300), Some(ULTRASONIC_MAX_RANGE)).confidence, 1_f32);
+        assert_eq!(fuse(Some(254), Some(ULTRASONIC_MAX_RANGE)).confidence, 1_f32);
+        // but contradicts a near one
+        let r = fuse(Some(100), Some(0));
+        assert_eq!((r.distance, r.confidence), (100, DISAGREEMENT_CONFIDENCE));
+        // and on its own says nothing is near
+        let r = fuse(None, Some(0));
+        assert_eq!((r.distance, r.confidence), (LIDAR_MAX_RANGE, ULTRASONIC_ONLY_CONFIDENCE));
+    }
+
+    #[test]
+    fn remembers_nearer_mapped_obstacles() {
+        let mut r = fuse(None, Some(300));
+        remember(&mut r, Some(100));
+        assert_eq!((r.distance, r.confidence), (100, LIDAR_ONLY_CONFIDENCE));
+
+        let mut r = fuse(Some(150), Some(160));
+        remember(&mut r, Some(200));
+        remember(&mut r, None);
+        assert_eq!((r.distance, r.confidence), (150, 1_f32));
+    }
+
+    #[test]
+    fn sectors_wrap_through_zero() {
+        let front = Sector::new(315, 45);
+        assert!(front.contains(0_f32) && front.contains(359.5) && front.contains(44.9));
+        assert!(!front.contains(45_f32) && !front.contains(180_f32));
+        let right = Sector::new(45, 135);
+        assert!(right.contains(45_f32) && !right.contains(135_f32) && !right.contains(0_f32));
+    }
+}
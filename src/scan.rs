@@ -0,0 +1,65 @@
+use std::time::Instant;
+
+/// the LIDAR reports this distance (in cm) when nothing was detected
+pub const MAX_DISTANCE: u32 = 1000;
+
+/// samples with a weaker signal than this are unreliable
+pub const MIN_SIGNAL_STRENGTH: u8 = 100;
+
+/// A single LIDAR sample
+#[derive(Debug, Clone)]
+pub struct ScanPoint {
+    /// angle in degrees relative to the vehicle (0 = straight ahead, clockwise)
+    pub angle: f32,
+    /// distance in cm
+    pub distance: u32,
+    pub signal_strength: u8,
+    /// when the sample was measured
+    pub timestamp: Instant,
+}
+
+impl ScanPoint {
+    /// is the sample reliable enough to use?
+    pub fn is_good(&self) -> bool {
+        self.signal_strength > MIN_SIGNAL_STRENGTH && self.distance > 1
+    }
+
+    /// position in cm relative to the sensor (x to the right, y straight ahead)
+    pub fn position(&self) -> (f32, f32) {
+        let a = self.angle.to_radians();
+        (self.distance as f32 * a.sin(), self.distance as f32 * a.cos())
+    }
+}
+
+/// One complete revolution of the LIDAR, in the order the samples were measured
+#[derive(Debug, Clone)]
+pub struct Scan {
+    pub points: Vec<ScanPoint>,
+    /// when the first and last samples were measured
+    pub started: Instant,
+    pub finished: Instant,
+}
+
+impl Scan {
+    pub fn new(points: Vec<ScanPoint>, started: Instant, finished: Instant) -> Self {
+        Scan {
+            points: points,
+            started: started,
+            finished: finished,
+        }
+    }
+
+    /// Resample the good points into one bin per degree, keeping the nearest distance in each
+    /// bin. Bins with no points are set to MAX_DISTANCE.
+    pub fn resample(&self, buffer: &mut [u32]) {
+        for i in 0..360 {
+            buffer[i] = MAX_DISTANCE;
+        }
+        for p in self.points.iter().filter(|p| p.is_good()) {
+            let bin = p.angle.floor() as usize % 360;
+            if p.distance < buffer[bin] {
+                buffer[bin] = p.distance;
+            }
+        }
+    }
+}
@@ -0,0 +1,380 @@
+use std::collections::HashMap;
+use std::time::Instant;
+
+use super::hardware::OdometrySource;
+use super::maths::solve3;
+use super::odometry::Odometry;
+use super::scan::{Scan, MAX_DISTANCE};
+
+/// only every nth good point of the new scan is matched, to save time
+const SUBSAMPLE: usize = 2;
+
+/// give up on a match after this many iterations
+const MAX_ITERATIONS: usize = 30;
+
+/// stop iterating once the estimate moves less than this (metres and radians)
+const CONVERGENCE: f64 = 0.001;
+
+/// points are only paired if they are within this distance (metres), which shrinks towards
+/// MIN_CORRESPONDENCE_DISTANCE as the match improves
+const MAX_CORRESPONDENCE_DISTANCE: f64 = 0.5;
+const MIN_CORRESPONDENCE_DISTANCE: f64 = 0.1;
+
+/// a match is rejected unless at least this many points, and this fraction of the points,
+/// could be paired
+const MIN_MATCHES: usize = 20;
+const MIN_MATCH_FRACTION: f64 = 0.5;
+
+/// the uncertainty of a match is never assumed to be less than this (metres and degrees)
+const MIN_POSITION_SD: f64 = 0.02;
+const MIN_HEADING_SD: f64 = 0.5;
+
+/// A rigid 2D transform: rotate counterclockwise by theta (radians) then translate by (x, y)
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Transform {
+    pub x: f64,
+    pub y: f64,
+    pub theta: f64,
+}
+
+impl Transform {
+    pub fn identity() -> Self {
+        Transform { x: 0_f64, y: 0_f64, theta: 0_f64 }
+    }
+
+    pub fn apply(&self, p: (f64, f64)) -> (f64, f64) {
+        let (s, c) = self.theta.sin_cos();
+        (p.0 * c - p.1 * s + self.x, p.0 * s + p.1 * c + self.y)
+    }
+}
+
+/// The result of matching one scan against another
+#[derive(Debug, Clone)]
+pub struct Match {
+    /// maps points in the new scan onto the reference scan
+    pub transform: Transform,
+    /// rms distance (metres) between the paired points
+    pub rms: f64,
+    /// fraction of the new scan's points that were paired
+    pub fraction: f64,
+    pub iterations: usize,
+}
+
+/// Match a scan against a reference scan using point-to-line iterative closest point,
+/// starting from an initial guess. Points are in metres in the vehicle frame (x to the right,
+/// y ahead) and the reference points must be in angle order so that neighbouring points can
+/// be joined into lines. Returns None if the scans don't overlap well enough to trust the
+/// result.
+pub fn icp(reference: &[(f64, f64)], points: &[(f64, f64)], guess: Transform) -> Option<Match> {
+    if reference.len() < MIN_MATCHES || points.len() < MIN_MATCHES {
+        return None;
+    }
+
+    let normals = normals(reference);
+    let mut t = guess;
+    let mut pairs: Vec<((f64, f64), usize)> = vec![];
+    let mut iterations = 0;
+
+    while iterations < MAX_ITERATIONS {
+        let max_distance = (MAX_CORRESPONDENCE_DISTANCE * 0.85_f64.powi(iterations as i32))
+            .max(MIN_CORRESPONDENCE_DISTANCE);
+        iterations += 1;
+
+        pairs = pair(reference, points, &t, max_distance);
+        if pairs.len() < MIN_MATCHES {
+            return None;
+        }
+
+        // linearise about the current estimate and solve for a small correction (dx, dy,
+        // dtheta) that minimises the distance from each point to the line through its pair,
+        // or to the paired point itself where there is no line
+        let mut a = [[0_f64; 3]; 3];
+        let mut b = [0_f64; 3];
+        for &(p, i) in pairs.iter() {
+            let q = t.apply(p);
+            let r = reference[i];
+            let directions = match normals[i] {
+                Some(n) => vec![n],
+                None => vec![(1_f64, 0_f64), (0_f64, 1_f64)],
+            };
+            for n in directions {
+                let j = [n.0, n.1, q.0 * n.1 - q.1 * n.0];
+                let e = (q.0 - r.0) * n.0 + (q.1 - r.1) * n.1;
+                for row in 0..3 {
+                    for col in 0..3 {
+                        a[row][col] += j[row] * j[col];
+                    }
+                    b[row] -= j[row] * e;
+                }
+            }
+        }
+        let delta = solve3(&a, &b)?;
+
+        // apply the correction on top of the current estimate
+        let (s, c) = delta[2].sin_cos();
+        t = Transform {
+            x: t.x * c - t.y * s + delta[0],
+            y: t.x * s + t.y * c + delta[1],
+            theta: t.theta + delta[2],
+        };
+
+        if (delta[0] * delta[0] + delta[1] * delta[1]).sqrt() + delta[2].abs() < CONVERGENCE {
+            break;
+        }
+    }
+
+    let fraction = pairs.len() as f64 / points.len() as f64;
+    if fraction < MIN_MATCH_FRACTION {
+        return None;
+    }
+    let sum: f64 = pairs.iter()
+        .map(|&(p, i)| {
+            let q = t.apply(p);
+            let r = reference[i];
+            match normals[i] {
+                Some(n) => ((q.0 - r.0) * n.0 + (q.1 - r.1) * n.1).powi(2),
+                None => (q.0 - r.0).powi(2) + (q.1 - r.1).powi(2),
+            }
+        })
+        .sum();
+
+    Some(Match {
+        transform: t,
+        rms: (sum / pairs.len() as f64).sqrt(),
+        fraction: fraction,
+        iterations: iterations,
+    })
+}
+
+/// the unit normal of the line through each point's neighbours, if they are close enough to
+/// be part of the same surface
+fn normals(points: &[(f64, f64)]) -> Vec<Option<(f64, f64)>> {
+    (0..points.len())
+        .map(|i| {
+            if i == 0 || i + 1 == points.len() {
+                return None;
+            }
+            let (a, b) = (points[i - 1], points[i + 1]);
+            let (dx, dy) = (b.0 - a.0, b.1 - a.1);
+            let length = (dx * dx + dy * dy).sqrt();
+            if length > 0_f64 && length < MAX_CORRESPONDENCE_DISTANCE {
+                Some((-dy / length, dx / length))
+            } else {
+                None
+            }
+        })
+        .collect()
+}
+
+/// pair each point with the index of the nearest reference point within max_distance, once
+/// transformed. The reference points are bucketed into a grid of max_distance squares so that
+/// only the neighbouring squares need to be searched.
+fn pair(reference: &[(f64, f64)],
+        points: &[(f64, f64)],
+        t: &Transform,
+        max_distance: f64) -> Vec<((f64, f64), usize)> {
+    let cell = |p: (f64, f64)| {
+        ((p.0 / max_distance).floor() as i64, (p.1 / max_distance).floor() as i64)
+    };
+    let mut grid: HashMap<(i64, i64), Vec<usize>> = HashMap::new();
+    for (i, &r) in reference.iter().enumerate() {
+        grid.entry(cell(r)).or_default().push(i);
+    }
+
+    let max_d2 = max_distance * max_distance;
+    let mut pairs = vec![];
+    for &p in points {
+        let q = t.apply(p);
+        let (cx, cy) = cell(q);
+        let mut best: Option<(usize, f64)> = None;
+        for gx in cx - 1..cx + 2 {
+            for gy in cy - 1..cy + 2 {
+                for &i in grid.get(&(gx, gy)).map_or(&[][..], |v| &v[..]) {
+                    let r = reference[i];
+                    let d2 = (q.0 - r.0).powi(2) + (q.1 - r.1).powi(2);
+                    if d2 <= max_d2 && best.is_none_or(|b| d2 < b.1 || (d2 == b.1 && i < b.0)) {
+                        best = Some((i, d2));
+                    }
+                }
+            }
+        }
+        if let Some((i, _)) = best {
+            pairs.push((p, i));
+        }
+    }
+    pairs
+}
+
+/// Estimates how the vehicle has moved by matching each LIDAR scan against the previous one
+pub struct ScanOdometry {
+    /// points of the previous scan in metres
+    reference: Vec<(f64, f64)>,
+    last_scan: Option<Instant>,
+    /// the previous step, used as the initial guess for the next since the vehicle's
+    /// motion doesn't change much between scans
+    last_step: Transform,
+    odometry: Odometry,
+    /// the latest match, or None if the latest scan couldn't be matched
+    last_match: Option<Match>,
+}
+
+impl ScanOdometry {
+    pub fn new() -> Self {
+        ScanOdometry {
+            reference: vec![],
+            last_scan: None,
+            last_step: Transform::identity(),
+            odometry: Odometry::new(),
+            last_match: None,
+        }
+    }
+
+    /// Match a new scan against the previous one and add the movement between them to the
+    /// odometry. Scans that have already been seen are ignored.
+    pub fn update(&mut self, scan: &Scan) {
+        if self.last_scan == Some(scan.finished) {
+            return;
+        }
+        self.last_scan = Some(scan.finished);
+
+        let mut good: Vec<_> = scan.points.iter()
+            .filter(|p| p.is_good() && p.distance < MAX_DISTANCE)
+            .collect();
+        good.sort_by(|a, b| a.angle.partial_cmp(&b.angle).unwrap());
+        let points: Vec<(f64, f64)> = good.iter()
+            .map(|p| {
+                let (x, y) = p.position();
+                (x as f64 / 100_f64, y as f64 / 100_f64)
+            })
+            .collect();
+
+        let sample: Vec<(f64, f64)> = points.iter().cloned().step_by(SUBSAMPLE).collect();
+        self.last_match = icp(&self.reference, &sample, self.last_step)
+            .or_else(|| icp(&self.reference, &sample, Transform::identity()));
+
+        match self.last_match {
+            Some(ref m) => {
+                // the transform maps the new scan into the previous vehicle frame, so it is the
+                // vehicle's own motion: its translation is where the vehicle is now and theta is
+                // how far it turned counterclockwise, negated since odometry turns are clockwise
+                let t = m.transform;
+                self.odometry.add(t.x, t.y, -t.theta.to_degrees());
+                let sd = (m.rms / (m.fraction * m.fraction)).max(MIN_POSITION_SD);
+                self.odometry.position_sd = sd;
+                self.odometry.heading_sd = (sd.to_degrees() / 2_f64).max(MIN_HEADING_SD);
+                self.last_step = t;
+            }
+            None => {
+                self.last_step = Transform::identity();
+            }
+        }
+
+        self.reference = points;
+    }
+
+    /// the latest match, or None if the latest scan couldn't be matched
+    pub fn last_match(&self) -> Option<Match> {
+        self.last_match.clone()
+    }
+}
+
+impl OdometrySource for ScanOdometry {
+    /// the total movement so far, or None if the latest scan couldn't be matched
+    fn odometry(&self) -> Option<Odometry> {
+        self.last_match.as_ref().map(|_| self.odometry.clone())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::time::{Duration, Instant};
+
+    use super::super::hardware::OdometrySource;
+    use super::super::scan::{Scan, ScanPoint};
+
+    /// walls of a rectangular room in metres, off center so that no two poses look the same
+    const ROOM: (f64, f64, f64, f64) = (-2_f64, 3_f64, -1.5_f64, 4_f64);
+
+    /// a scan of the room, one sample per degree, from a vehicle at (x, y) metres facing
+    /// heading degrees clockwise from the room's y axis
+    fn scan(x: f64, y: f64, heading: f64, finished: Instant) -> Scan {
+        let points = (0..360)
+            .map(|a| {
+                let (dx, dy) = (heading + a as f64).to_radians().sin_cos();
+                let mut t = f64::MAX;
+                let walls = [(ROOM.0, dx, x), (ROOM.1, dx, x), (ROOM.2, dy, y), (ROOM.3, dy, y)];
+                for &(wall, d, p) in walls.iter() {
+                    if d != 0_f64 && (wall - p) / d > 0_f64 {
+                        t = t.min((wall - p) / d);
+                    }
+                }
+                ScanPoint {
+                    angle: a as f32,
+                    distance: (t * 100_f64).round() as u32,
+                    signal_strength: 200,
+                    timestamp: finished,
+                }
+            })
+            .collect();
+        Scan::new(points, finished - Duration::from_millis(100), finished)
+    }
+
+    fn metres(scan: &Scan) -> Vec<(f64, f64)> {
+        scan.points.iter()
+            .map(|p| {
+                let (x, y) = p.position();
+                (x as f64 / 100_f64, y as f64 / 100_f64)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn matches_a_scan_against_itself() {
+        let s = scan(0.5, 0.5, 0_f64, Instant::now());
+        let m = icp(&metres(&s), &metres(&s), Transform::identity()).unwrap();
+        assert!(m.transform.x.abs() < 0.001 && m.transform.y.abs() < 0.001);
+        assert!(m.transform.theta.abs() < 0.001);
+        assert!(m.fraction > 0.99);
+        assert!(m.rms < 0.01);
+    }
+
+    #[test]
+    fn recovers_a_known_motion() {
+        let now = Instant::now();
+        let before = scan(0_f64, 0_f64, 0_f64, now);
+        // 5cm right, 20cm ahead and 5 degrees clockwise
+        let after = scan(0.05, 0.2, 5_f64, now);
+        let m = icp(&metres(&before), &metres(&after), Transform::identity()).unwrap();
+        assert!((m.transform.x - 0.05).abs() < 0.01, "{:?}", m);
+        assert!((m.transform.y - 0.2).abs() < 0.01, "{:?}", m);
+        // a clockwise turn is a negative (counterclockwise) theta
+        assert!((m.transform.theta.to_degrees() + 5_f64).abs() < 0.3, "{:?}", m);
+    }
+
+    #[test]
+    fn reports_clockwise_turns_as_positive() {
+        let now = Instant::now();
+        let mut odometry = ScanOdometry::new();
+        odometry.update(&scan(0_f64, 0_f64, 0_f64, now));
+        assert!(odometry.odometry().is_none());
+
+        odometry.update(&scan(0.05, 0.2, 5_f64, now + Duration::from_millis(100)));
+        let o = odometry.odometry().unwrap();
+        assert!((o.x - 0.05).abs() < 0.01, "{:?}", o);
+        assert!((o.y - 0.2).abs() < 0.01, "{:?}", o);
+        assert!((o.heading - 5_f64).abs() < 0.3, "{:?}", o);
+
+        // the same scan again is ignored
+        odometry.update(&scan(0.05, 0.2, 5_f64, now + Duration::from_millis(100)));
+        assert_eq!(odometry.odometry().unwrap().steps, 1);
+    }
+
+    #[test]
+    fn rejects_scans_that_do_not_overlap() {
+        let s = metres(&scan(0_f64, 0_f64, 0_f64, Instant::now()));
+        let far: Vec<_> = s.iter().map(|p| (p.0 + 20_f64, p.1)).collect();
+        assert!(icp(&s, &far, Transform::identity()).is_none());
+    }
+}
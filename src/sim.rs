@@ -0,0 +1,438 @@
+extern crate navigation;
+extern crate rand;
+
+use self::rand::distributions::{IndependentSample, Normal};
+use navigation::Location;
+
+use super::frame::{LocalFrame, Point};
+use super::gps::{FixType, GpsFix};
+use super::hardware::*;
+use super::lidar::LidarConfig;
+use super::maths::{normalize_degrees, normalize_radians};
+use super::motors::Motion;
+use super::odometry::Odometry;
+use super::perception::ULTRASONIC_MAX_RANGE;
+use super::scan::{Scan, ScanPoint, MAX_DISTANCE};
+use super::ultrasonic::UltrasonicRanges;
+
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// half the width of the simulated ultrasonic beams in degrees
+const ULTRASONIC_BEAM: f64 = 15_f64;
+
+/// a cylindrical obstacle such as a barrel or a post
+#[derive(Debug, Clone)]
+pub struct Obstacle {
+    pub lat: f64,
+    pub lon: f64,
+    /// radius in metres
+    pub radius: f64,
+}
+
+#[derive(Debug, Clone)]
+pub struct SimSettings {
+    /// starting position (lat, lon)
+    pub start: (f64, f64),
+    /// starting heading in degrees
+    pub start_heading: f64,
+    /// standard deviation of the GPS error in metres
+    pub gps_noise: f64,
+    /// how often the simulated GPS produces a new fix
+    pub gps_update_hz: f64,
+    /// standard deviation of the compass error in degrees
+    pub compass_noise: f64,
+    /// how often the simulated compass produces a new reading
+    pub compass_update_hz: f64,
+    /// wheel speed in m/s when the motors are set to Speed(127)
+    pub max_wheel_speed: f64,
+    /// distance between the left and right wheels in metres
+    pub track_width: f64,
+    /// simulate wheel encoders (otherwise the vehicle has no odometry)
+    pub encoders: bool,
+    /// maximum range of the simulated LIDAR in cm
+    pub lidar_range: u32,
+    /// how often the simulated LIDAR completes a scan
+    pub lidar_scan_hz: f64,
+    /// how the simulated LIDAR is mounted, as on the vehicle
+    pub lidar: LidarConfig,
+    /// how long after starting the simulation the virtual start switch is turned on
+    pub start_delay: Duration,
+    pub obstacles: Vec<Obstacle>,
+}
+
+impl SimSettings {
+    pub fn new(start: (f64, f64)) -> Self {
+        SimSettings {
+            start: start,
+            start_heading: 0_f64,
+            gps_noise: 1.5,
+            gps_update_hz: 1_f64,
+            compass_noise: 2_f64,
+            compass_update_hz: 10_f64,
+            max_wheel_speed: 2_f64,
+            track_width: 0.25,
+            encoders: true,
+            lidar_range: 1000,
+            lidar_scan_hz: 10_f64,
+            lidar: LidarConfig::new(),
+            start_delay: Duration::from_secs(2),
+            obstacles: vec![],
+        }
+    }
+}
+
+/// kinematic state of the simulated vehicle in a flat frame centered on the start position
+#[derive(Debug, Clone)]
+struct Vehicle {
+    /// metres east of the start position
+    x: f64,
+    /// metres north of the start position
+    y: f64,
+    /// heading in radians, 0 = north, increasing clockwise
+    heading: f64,
+    /// left and right wheel speeds in m/s
+    left: f64,
+    right: f64,
+}
+
+struct World {
+    settings: SimSettings,
+    /// anchored at the start position
+    frame: LocalFrame,
+    vehicle: Vehicle,
+    /// most recent (noisy) GPS fix (lat, lon, time)
+    gps: Option<(f64, f64, Instant)>,
+    /// most recent (noisy) compass heading in degrees and when it was measured
+    compass: Option<(f32, Instant)>,
+    /// most recent complete LIDAR scan
+    scan: Option<Scan>,
+    /// nearest distance in each 1 degree bin of the scan, 0 until the first scan
+    points: [u32; 360],
+    /// movement measured by the simulated wheel encoders
+    odometry: Odometry,
+    started: Instant,
+}
+
+/// Simulates a differential-drive vehicle along with its sensors. The simulated devices
+/// returned by `gps()`, `compass()` etc all share the same world.
+pub struct Simulator {
+    world: Arc<Mutex<World>>,
+}
+
+impl Simulator {
+    pub fn new(settings: SimSettings) -> Self {
+        let heading = settings.start_heading.to_radians();
+        let frame = LocalFrame::new(&Location::new(settings.start.0, settings.start.1));
+        Simulator {
+            world: Arc::new(Mutex::new(World {
+                settings: settings,
+                frame: frame,
+                vehicle: Vehicle {
+                    x: 0_f64,
+                    y: 0_f64,
+                    heading: heading,
+                    left: 0_f64,
+                    right: 0_f64,
+                },
+                gps: None,
+                compass: None,
+                scan: None,
+                points: [0; 360],
+                odometry: Odometry::new(),
+                started: Instant::now(),
+            })),
+        }
+    }
+
+    /// start the thread that moves the vehicle and updates the sensors
+    pub fn start_thread(&self) {
+        let world = self.world.clone();
+        thread::spawn(move || {
+            let mut rng = rand::thread_rng();
+            let mut last_step = Instant::now();
+            let mut last_gps = None;
+            let mut last_compass = None;
+            let mut last_scan = None;
+            let mut last_report = Instant::now();
+            loop {
+                thread::sleep(Duration::from_millis(10));
+
+                let mut w = world.lock().unwrap();
+
+                let now = Instant::now();
+                let dt = duration_secs(now.duration_since(last_step));
+                last_step = now;
+
+                // differential drive kinematics
+                let (speed, yaw_rate) = {
+                    let track_width = w.settings.track_width;
+                    let v = &mut w.vehicle;
+                    let speed = (v.left + v.right) / 2_f64;
+                    let yaw_rate = (v.left - v.right) / track_width;
+                    v.heading = normalize_radians(v.heading + yaw_rate * dt);
+                    v.x += speed * v.heading.sin() * dt;
+                    v.y += speed * v.heading.cos() * dt;
+                    (speed, yaw_rate)
+                };
+                w.odometry.add(0_f64, speed * dt, (yaw_rate * dt).to_degrees());
+
+                // produce a new GPS fix at the configured rate
+                if due(last_gps, now, w.settings.gps_update_hz) {
+                    last_gps = Some(now);
+                    let (mut x, mut y) = (w.vehicle.x, w.vehicle.y);
+                    if w.settings.gps_noise > 0_f64 {
+                        let noise = Normal::new(0_f64, w.settings.gps_noise);
+                        x += noise.ind_sample(&mut rng);
+                        y += noise.ind_sample(&mut rng);
+                    }
+                    let loc = w.frame.to_location(&Point::new(x, y));
+                    w.gps = Some((loc.lat, loc.lon, now));
+                }
+
+                if due(last_compass, now, w.settings.compass_update_hz) {
+                    last_compass = Some(now);
+                    let mut heading = w.vehicle.heading.to_degrees();
+                    if w.settings.compass_noise > 0_f64 {
+                        let noise = Normal::new(0_f64, w.settings.compass_noise);
+                        heading += noise.ind_sample(&mut rng);
+                    }
+                    w.compass = Some((normalize_degrees(heading) as f32, now));
+                }
+
+                if due(last_scan, now, w.settings.lidar_scan_hz) {
+                    let started = last_scan.unwrap_or(now);
+                    last_scan = Some(now);
+                    let scan = SimLidar::sweep(&w, started, now);
+                    scan.resample(&mut w.points);
+                    w.scan = Some(scan);
+                }
+
+                if now.duration_since(last_report) >= Duration::from_secs(1) {
+                    last_report = now;
+                    println!("SIM: x={:.*}m y={:.*}m heading={:.*} speed={:.*}/{:.*} m/s",
+                             2, w.vehicle.x,
+                             2, w.vehicle.y,
+                             1, w.vehicle.heading.to_degrees(),
+                             2, w.vehicle.left,
+                             2, w.vehicle.right);
+                }
+            }
+        });
+    }
+
+    pub fn gps(&self) -> SimGps {
+        SimGps { world: self.world.clone() }
+    }
+
+    pub fn compass(&self) -> SimCompass {
+        SimCompass { world: self.world.clone() }
+    }
+
+    pub fn lidar(&self) -> SimLidar {
+        SimLidar { world: self.world.clone() }
+    }
+
+    pub fn ultrasonic(&self) -> SimUltrasonic {
+        SimUltrasonic { world: self.world.clone() }
+    }
+
+    pub fn odometry(&self) -> SimOdometry {
+        SimOdometry { world: self.world.clone() }
+    }
+
+    pub fn motors(&self) -> SimMotors {
+        SimMotors { world: self.world.clone() }
+    }
+
+    pub fn switch(&self) -> SimSwitch {
+        SimSwitch { world: self.world.clone() }
+    }
+}
+
+pub struct SimGps {
+    world: Arc<Mutex<World>>,
+}
+
+impl PositionSource for SimGps {
+    fn fix(&self) -> Option<GpsFix> {
+        let w = self.world.lock().unwrap();
+        w.gps.map(|(lat, lon, received)| {
+            let speed = (w.vehicle.left + w.vehicle.right) / 2_f64;
+            let mut fix = GpsFix::new(lat, lon);
+            fix.received = received;
+            fix.fix_type = FixType::Fix3D;
+            fix.satellites = 10;
+            fix.satellites_in_view = 12;
+            fix.hdop = Some(1_f32);
+            fix.speed = Some(speed.abs() as f32);
+            fix.course = Some(w.vehicle.heading.to_degrees() as f32);
+            fix
+        })
+    }
+}
+
+pub struct SimOdometry {
+    world: Arc<Mutex<World>>,
+}
+
+impl OdometrySource for SimOdometry {
+    fn odometry(&self) -> Option<Odometry> {
+        let w = self.world.lock().unwrap();
+        if w.settings.encoders { Some(w.odometry.clone()) } else { None }
+    }
+}
+
+pub struct SimCompass {
+    world: Arc<Mutex<World>>,
+}
+
+impl HeadingSource for SimCompass {
+    fn heading(&mut self) -> Option<(f32, Instant)> {
+        let w = self.world.lock().unwrap();
+        w.compass
+    }
+}
+
+pub struct SimLidar {
+    world: Arc<Mutex<World>>,
+}
+
+impl SimLidar {
+    /// cast a ray from the vehicle at the given angle (degrees relative to the vehicle
+    /// heading) and return the distance to the nearest obstacle in cm, or MAX_DISTANCE if
+    /// there isn't one within range
+    fn cast(w: &World, angle: f64) -> u32 {
+        let bearing = w.vehicle.heading + angle.to_radians();
+        let (dx, dy) = (bearing.sin(), bearing.cos());
+        let max_range = w.settings.lidar_range as f64 / 100_f64;
+        let mut nearest = max_range;
+        for o in &w.settings.obstacles {
+            let p = w.frame.to_point(&Location::new(o.lat, o.lon));
+            let (ox, oy) = (p.east, p.north);
+            // vector from the vehicle to the center of the obstacle
+            let (cx, cy) = (ox - w.vehicle.x, oy - w.vehicle.y);
+            // distance along the ray to the point closest to the center
+            let t = cx * dx + cy * dy;
+            let d2 = cx * cx + cy * cy - t * t;
+            let r2 = o.radius * o.radius;
+            if d2 > r2 {
+                continue;
+            }
+            let hit = t - (r2 - d2).sqrt();
+            if hit >= 0_f64 && hit < nearest {
+                nearest = hit;
+            }
+        }
+        if nearest < max_range {
+            ((nearest * 100_f64) as u32).min(MAX_DISTANCE)
+        } else {
+            MAX_DISTANCE
+        }
+    }
+
+    /// one revolution of the sensor, converted to vehicle angles and masked the same way as
+    /// the real LIDAR
+    fn sweep(w: &World, started: Instant, finished: Instant) -> Scan {
+        let config = &w.settings.lidar;
+        let points = (0..360)
+            .map(|i| config.vehicle_angle(i as f32))
+            .filter(|angle| !config.mask.as_ref().is_some_and(|m| m.contains(*angle)))
+            .map(|angle| ScanPoint {
+                angle: angle,
+                distance: SimLidar::cast(w, angle as f64),
+                signal_strength: 255,
+                timestamp: finished,
+            })
+            .collect();
+        Scan::new(points, started, finished)
+    }
+}
+
+impl RangeScanner for SimLidar {
+    fn min_distance(&self, start: usize, end: usize) -> u32 {
+        let w = self.world.lock().unwrap();
+        let end = if start < end { end } else { end + 360 };
+        (start..end).map(|i| w.points[i % 360]).min().unwrap_or(w.points[start])
+    }
+
+    fn scan(&self) -> Option<Scan> {
+        let w = self.world.lock().unwrap();
+        w.scan.clone()
+    }
+}
+
+pub struct SimUltrasonic {
+    world: Arc<Mutex<World>>,
+}
+
+impl SimUltrasonic {
+    /// distance in cm to the nearest obstacle within the beam pointing at the given angle
+    fn beam(w: &World, angle: f64) -> u32 {
+        let d = [angle - ULTRASONIC_BEAM, angle, angle + ULTRASONIC_BEAM].iter()
+            .map(|a| SimLidar::cast(w, *a))
+            .min()
+            .unwrap();
+        if d < ULTRASONIC_MAX_RANGE { d } else { ULTRASONIC_MAX_RANGE }
+    }
+}
+
+impl UltrasonicSensors for SimUltrasonic {
+    fn ranges(&self) -> Option<UltrasonicRanges> {
+        let w = self.world.lock().unwrap();
+        Some(UltrasonicRanges {
+            side_left: SimUltrasonic::beam(&w, 270_f64),
+            front_left: SimUltrasonic::beam(&w, 315_f64),
+            front: SimUltrasonic::beam(&w, 0_f64),
+            front_right: SimUltrasonic::beam(&w, 45_f64),
+            side_right: SimUltrasonic::beam(&w, 90_f64),
+        })
+    }
+}
+
+pub struct SimMotors {
+    world: Arc<Mutex<World>>,
+}
+
+impl DriveActuator for SimMotors {
+    fn drive(&mut self, left: Motion, right: Motion) {
+        let mut w = self.world.lock().unwrap();
+        let max_wheel_speed = w.settings.max_wheel_speed;
+        w.vehicle.left = wheel_speed(left, max_wheel_speed);
+        w.vehicle.right = wheel_speed(right, max_wheel_speed);
+    }
+}
+
+pub struct SimSwitch {
+    world: Arc<Mutex<World>>,
+}
+
+impl StartSwitch for SimSwitch {
+    fn state(&self) -> Option<bool> {
+        let w = self.world.lock().unwrap();
+        Some(w.started.elapsed() >= w.settings.start_delay)
+    }
+}
+
+/// convert a motor command to a wheel speed in m/s (braking is treated as an instant stop)
+fn wheel_speed(m: Motion, max_wheel_speed: f64) -> f64 {
+    match m {
+        Motion::Speed(n) => n as f64 / 127_f64 * max_wheel_speed,
+        // the simulated wheels track the target velocity perfectly
+        Motion::Velocity(v) => (v as f64).max(-max_wheel_speed).min(max_wheel_speed),
+        Motion::Brake(_) => 0_f64,
+    }
+}
+
+/// is a new reading due from a sensor that updates at the given rate?
+fn due(last: Option<Instant>, now: Instant, rate_hz: f64) -> bool {
+    match last {
+        None => true,
+        Some(t) => duration_secs(now.duration_since(t)) >= 1_f64 / rate_hz,
+    }
+}
+
+fn duration_secs(d: Duration) -> f64 {
+    d.as_secs() as f64 + d.subsec_nanos() as f64 / 1_000_000_000_f64
+}
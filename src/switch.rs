@@ -5,6 +5,8 @@ use std::thread::sleep;
 use std::sync::{Arc, Mutex};
 use std::time::Duration;
 
+use super::hardware::StartSwitch;
+
 #[derive(Debug)]
 pub struct Switch {
     pin: u64,
@@ -41,17 +43,11 @@ impl Switch {
             // loop forever
             loop {
 
-                let baseline = match input.get_value() {
-                    Ok(n) => n,
-                    _ => 123,
-                };
+                let baseline = input.get_value().unwrap_or(123);
 
                 let mut value = baseline;
                 for _ in 0..9 {
-                    value = match input.get_value() {
-                        Ok(n) => n,
-                        _ => 123,
-                    };
+                    value = input.get_value().unwrap_or(123);
                     if baseline != value {
                         break;
                     }
@@ -67,3 +63,9 @@ impl Switch {
         });
     }
 }
+
+impl StartSwitch for Switch {
+    fn state(&self) -> Option<bool> {
+        self.get()
+    }
+}
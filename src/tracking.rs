@@ -0,0 +1,555 @@
+use std::time::{Duration, Instant};
+
+use super::maths::solve3;
+use super::scan::{Scan, MAX_DISTANCE};
+
+/// neighbouring points further apart than this (in cm, plus a fraction of their distance
+/// from the sensor) belong to different objects
+const CLUSTER_GAP: f32 = 15_f32;
+const CLUSTER_GAP_FACTOR: f32 = 0.05;
+
+/// clusters with fewer points than this are treated as noise
+const MIN_CLUSTER_POINTS: usize = 3;
+
+/// a circle is only fitted if its residual is this much smaller than the line's
+const CIRCLE_FIT_RATIO: f32 = 0.5;
+
+/// anything bigger than this (radius in cm) isn't a round object
+const MAX_CIRCLE_RADIUS: f32 = 100_f32;
+
+/// lines at least this long (in cm) are walls or fences
+const WALL_MIN_LENGTH: f32 = 100_f32;
+
+/// round objects no bigger than this (radius in cm) are barrels
+const BARREL_MAX_RADIUS: f32 = 40_f32;
+
+/// objects moving faster than this (in cm/s, over the ground) are other vehicles
+const MIN_MOVING_SPEED: f32 = 50_f32;
+
+/// an object must have been seen in this many scans before its velocity is trusted
+const MIN_HITS_FOR_VELOCITY: u32 = 3;
+
+/// a detection further than this (in cm) from a track's predicted position starts a new track
+const MAX_ASSOCIATION_DISTANCE: f32 = 50_f32;
+
+/// tracks are dropped after they have been missing from this many scans
+const MAX_MISSED_SCANS: u32 = 3;
+
+/// gains of the alpha-beta filter that smooths each track's position and velocity
+const ALPHA: f32 = 0.5;
+const BETA: f32 = 0.3;
+
+/// The outline fitted to a cluster of points, in cm in the vehicle frame (x to the right,
+/// y straight ahead)
+#[derive(Debug, Clone, PartialEq)]
+pub enum Shape {
+    Line { start: (f32, f32), end: (f32, f32) },
+    Circle { center: (f32, f32), radius: f32 },
+}
+
+impl Shape {
+    /// the middle of the line or the center of the circle
+    pub fn center(&self) -> (f32, f32) {
+        match *self {
+            Shape::Line { start, end } => ((start.0 + end.0) / 2_f32, (start.1 + end.1) / 2_f32),
+            Shape::Circle { center, .. } => center,
+        }
+    }
+
+    /// distance in cm from the vehicle to the nearest part of the shape
+    pub fn distance(&self) -> f32 {
+        match *self {
+            Shape::Line { start, end } => {
+                let (dx, dy) = (end.0 - start.0, end.1 - start.1);
+                let len2 = dx * dx + dy * dy;
+                let t = if len2 > 0_f32 {
+                    (-(start.0 * dx + start.1 * dy) / len2).clamp(0_f32, 1_f32)
+                } else {
+                    0_f32
+                };
+                norm((start.0 + t * dx, start.1 + t * dy))
+            }
+            Shape::Circle { center, radius } => (norm(center) - radius).max(0_f32),
+        }
+    }
+
+    fn transform(&self, forward: f32, rotation: f32) -> Shape {
+        match *self {
+            Shape::Line { start, end } => Shape::Line {
+                start: transform(start, forward, rotation),
+                end: transform(end, forward, rotation),
+            },
+            Shape::Circle { center, radius } => Shape::Circle {
+                center: transform(center, forward, rotation),
+                radius: radius,
+            },
+        }
+    }
+}
+
+/// What an object is thought to be
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ObjectKind {
+    Wall,
+    Barrel,
+    Vehicle,
+    Unknown,
+}
+
+/// An object that has been followed across successive scans
+#[derive(Debug, Clone)]
+pub struct TrackedObject {
+    /// unique for the life of the tracker
+    pub id: u32,
+    pub kind: ObjectKind,
+    /// the shape fitted to the latest scan the object was seen in
+    pub shape: Shape,
+    /// estimated position in cm in the vehicle frame
+    pub center: (f32, f32),
+    /// estimated velocity over the ground in cm/s, in the vehicle frame
+    pub velocity: (f32, f32),
+    /// number of scans the object has been seen in
+    pub hits: u32,
+    /// number of scans in a row the object has been missing from
+    pub missed: u32,
+}
+
+impl TrackedObject {
+    /// ground speed in cm/s
+    pub fn speed(&self) -> f32 {
+        norm(self.velocity)
+    }
+
+    fn classify(&self) -> ObjectKind {
+        match self.shape {
+            Shape::Line { start, end } if norm((end.0 - start.0, end.1 - start.1)) >= WALL_MIN_LENGTH => {
+                ObjectKind::Wall
+            }
+            _ if self.hits >= MIN_HITS_FOR_VELOCITY && self.speed() >= MIN_MOVING_SPEED => {
+                ObjectKind::Vehicle
+            }
+            Shape::Circle { radius, .. } if radius <= BARREL_MAX_RADIUS => ObjectKind::Barrel,
+            _ => ObjectKind::Unknown,
+        }
+    }
+}
+
+/// Segments each LIDAR scan into objects and tracks them from scan to scan
+pub struct Tracker {
+    next_id: u32,
+    objects: Vec<TrackedObject>,
+    /// when the last scan finished, and the heading at the time
+    last_scan: Option<Instant>,
+    last_heading: Option<f32>,
+}
+
+impl Tracker {
+    pub fn new() -> Self {
+        Tracker {
+            next_id: 1,
+            objects: vec![],
+            last_scan: None,
+            last_heading: None,
+        }
+    }
+
+    /// the objects currently being tracked, including any that were missing from the latest
+    /// scan but haven't been dropped yet
+    pub fn objects(&self) -> &[TrackedObject] {
+        &self.objects
+    }
+
+    /// Update the tracks from a new scan. The vehicle's heading (degrees) and ground speed
+    /// (m/s) are used to remove its own motion so that velocities are over the ground. Scans
+    /// that have already been seen are ignored.
+    pub fn update(&mut self, scan: &Scan, heading: Option<f32>, speed: Option<f32>) {
+        if self.last_scan == Some(scan.finished) {
+            return;
+        }
+        let dt = self.last_scan.map(|t| secs(scan.finished.duration_since(t)));
+        self.last_scan = Some(scan.finished);
+
+        // move the tracks into the new vehicle frame and predict where they are now
+        if let Some(dt) = dt {
+            let forward = speed.unwrap_or(0_f32) * 100_f32 * dt;
+            let rotation = match (self.last_heading, heading) {
+                (Some(a), Some(b)) => angle_diff(a, b),
+                _ => 0_f32,
+            };
+            for o in self.objects.iter_mut() {
+                o.shape = o.shape.transform(forward, rotation);
+                o.center = transform(o.center, forward, rotation);
+                o.velocity = transform(o.velocity, 0_f32, rotation);
+                o.center = (o.center.0 + o.velocity.0 * dt, o.center.1 + o.velocity.1 * dt);
+            }
+        }
+        self.last_heading = heading;
+
+        let detections: Vec<Shape> = segment(scan).iter().map(|c| fit(c)).collect();
+
+        // greedily pair the closest track and detection until none are close enough
+        let mut pairs = vec![];
+        for (i, o) in self.objects.iter().enumerate() {
+            for (j, d) in detections.iter().enumerate() {
+                let c = d.center();
+                let distance = norm((c.0 - o.center.0, c.1 - o.center.1));
+                if distance <= MAX_ASSOCIATION_DISTANCE {
+                    pairs.push((distance, i, j));
+                }
+            }
+        }
+        pairs.sort_by(|a, b| a.0.partial_cmp(&b.0).unwrap());
+
+        let mut matched_objects = vec![false; self.objects.len()];
+        let mut matched_detections = vec![false; detections.len()];
+        for &(_, i, j) in pairs.iter() {
+            if matched_objects[i] || matched_detections[j] {
+                continue;
+            }
+            matched_objects[i] = true;
+            matched_detections[j] = true;
+
+            let o = &mut self.objects[i];
+            let c = detections[j].center();
+            let innovation = (c.0 - o.center.0, c.1 - o.center.1);
+            o.center = (o.center.0 + ALPHA * innovation.0, o.center.1 + ALPHA * innovation.1);
+            if let Some(dt) = dt {
+                if dt > 0_f32 {
+                    o.velocity = (o.velocity.0 + BETA * innovation.0 / dt,
+                                  o.velocity.1 + BETA * innovation.1 / dt);
+                }
+            }
+            o.shape = detections[j].clone();
+            o.hits += 1;
+            o.missed = 0;
+        }
+
+        for (i, matched) in matched_objects.iter().enumerate() {
+            if !matched {
+                self.objects[i].missed += 1;
+            }
+        }
+        self.objects.retain(|o| o.missed <= MAX_MISSED_SCANS);
+
+        for (j, d) in detections.into_iter().enumerate() {
+            if !matched_detections[j] {
+                self.objects.push(TrackedObject {
+                    id: self.next_id,
+                    kind: ObjectKind::Unknown,
+                    center: d.center(),
+                    shape: d,
+                    velocity: (0_f32, 0_f32),
+                    hits: 1,
+                    missed: 0,
+                });
+                self.next_id += 1;
+            }
+        }
+
+        for o in self.objects.iter_mut() {
+            o.kind = o.classify();
+        }
+    }
+}
+
+/// Split the good points of a scan into clusters of neighbouring points, in cm in the vehicle
+/// frame. Points where nothing was detected are ignored.
+pub fn segment(scan: &Scan) -> Vec<Vec<(f32, f32)>> {
+    let mut points: Vec<(f32, u32, (f32, f32))> = scan.points.iter()
+        .filter(|p| p.is_good() && p.distance < MAX_DISTANCE)
+        .map(|p| (p.angle, p.distance, p.position()))
+        .collect();
+    points.sort_by(|a, b| a.0.partial_cmp(&b.0).unwrap());
+
+    let mut clusters: Vec<Vec<(f32, f32)>> = vec![];
+    let mut previous: Option<(u32, (f32, f32))> = None;
+    for &(_, distance, p) in points.iter() {
+        let split = match previous {
+            Some((d, q)) => norm((p.0 - q.0, p.1 - q.1)) > gap(d.min(distance)),
+            None => true,
+        };
+        if split {
+            clusters.push(vec![]);
+        }
+        clusters.last_mut().unwrap().push(p);
+        previous = Some((distance, p));
+    }
+
+    // the first and last clusters are the same object if it spans straight ahead
+    if clusters.len() > 1 {
+        let (first, last) = (points[0], points[points.len() - 1]);
+        let (p, q) = (first.2, last.2);
+        if norm((p.0 - q.0, p.1 - q.1)) <= gap(first.1.min(last.1)) {
+            let mut last = clusters.pop().unwrap();
+            last.extend(clusters[0].iter().cloned());
+            clusters[0] = last;
+        }
+    }
+
+    clusters.into_iter().filter(|c| c.len() >= MIN_CLUSTER_POINTS).collect()
+}
+
+/// Fit a line segment or, if it fits much better, a circle to a cluster of points
+pub fn fit(points: &[(f32, f32)]) -> Shape {
+    let n = points.len() as f32;
+    let mean = points.iter().fold((0_f32, 0_f32), |m, p| (m.0 + p.0 / n, m.1 + p.1 / n));
+
+    // the line runs along the direction of greatest spread
+    let (mut sxx, mut syy, mut sxy) = (0_f32, 0_f32, 0_f32);
+    for p in points {
+        let (dx, dy) = (p.0 - mean.0, p.1 - mean.1);
+        sxx += dx * dx;
+        syy += dy * dy;
+        sxy += dx * dy;
+    }
+    let (sxx, syy, sxy) = (sxx / n, syy / n, sxy / n);
+    let spread = ((sxx - syy) * (sxx - syy) + 4_f32 * sxy * sxy).sqrt();
+    let line_rms = ((sxx + syy - spread) / 2_f32).max(0_f32).sqrt();
+
+    let angle = 0.5_f32 * (2_f32 * sxy).atan2(sxx - syy);
+    let axis = (angle.cos(), angle.sin());
+    let (mut t_min, mut t_max) = (0_f32, 0_f32);
+    for p in points {
+        let t = (p.0 - mean.0) * axis.0 + (p.1 - mean.1) * axis.1;
+        t_min = t_min.min(t);
+        t_max = t_max.max(t);
+    }
+    let line = Shape::Line {
+        start: (mean.0 + t_min * axis.0, mean.1 + t_min * axis.1),
+        end: (mean.0 + t_max * axis.0, mean.1 + t_max * axis.1),
+    };
+
+    match fit_circle(points, mean) {
+        // the LIDAR sees the near side of a round object, so its center must be further away
+        Some((center, radius, rms)) if radius <= MAX_CIRCLE_RADIUS
+                                       && norm(center) > norm(mean)
+                                       && rms < line_rms * CIRCLE_FIT_RATIO => {
+            Shape::Circle { center: center, radius: radius }
+        }
+        _ => line,
+    }
+}
+
+/// Algebraic least squares circle fit, returning the center, radius and rms residual
+fn fit_circle(points: &[(f32, f32)], mean: (f32, f32)) -> Option<((f32, f32), f32, f32)> {
+    // fit x^2 + y^2 + Dx + Ey + F = 0 relative to the mean to keep the numbers small
+    let mut a = [[0_f64; 3]; 3];
+    let mut b = [0_f64; 3];
+    for p in points {
+        let (x, y) = ((p.0 - mean.0) as f64, (p.1 - mean.1) as f64);
+        let row = [x, y, 1_f64];
+        let z = -(x * x + y * y);
+        for i in 0..3 {
+            for j in 0..3 {
+                a[i][j] += row[i] * row[j];
+            }
+            b[i] += row[i] * z;
+        }
+    }
+
+    let solution = solve3(&a, &b)?;
+
+    let (cx, cy) = (-solution[0] / 2_f64, -solution[1] / 2_f64);
+    let r2 = cx * cx + cy * cy - solution[2];
+    if r2 <= 0_f64 {
+        return None;
+    }
+    let radius = r2.sqrt() as f32;
+    let center = (cx as f32 + mean.0, cy as f32 + mean.1);
+
+    let n = points.len() as f32;
+    let sum: f32 = points.iter()
+        .map(|p| {
+            let e = norm((p.0 - center.0, p.1 - center.1)) - radius;
+            e * e
+        })
+        .sum();
+    Some((center, radius, (sum / n).sqrt()))
+}
+
+/// the largest gap between neighbouring points of the same object at this distance
+fn gap(distance: u32) -> f32 {
+    CLUSTER_GAP + distance as f32 * CLUSTER_GAP_FACTOR
+}
+
+/// move a point into the vehicle frame after the vehicle has moved forward (cm) and then
+/// turned clockwise (degrees)
+fn transform(p: (f32, f32), forward: f32, rotation: f32) -> (f32, f32) {
+    let (x, y) = (p.0, p.1 - forward);
+    let (s, c) = rotation.to_radians().sin_cos();
+    (x * c - y * s, x * s + y * c)
+}
+
+/// clockwise change in heading (degrees) from a to b
+fn angle_diff(a: f32, b: f32) -> f32 {
+    let d = (b - a) % 360_f32;
+    if d > 180_f32 {
+        d - 360_f32
+    } else if d < -180_f32 {
+        d + 360_f32
+    } else {
+        d
+    }
+}
+
+fn norm(v: (f32, f32)) -> f32 {
+    (v.0 * v.0 + v.1 * v.1).sqrt()
+}
+
+fn secs(d: Duration) -> f32 {
+    d.as_secs() as f32 + d.subsec_nanos() as f32 / 1_000_000_000_f32
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use super::super::scan::ScanPoint;
+
+    /// a post in cm in the vehicle frame
+    const POST: ((f32, f32), f32) = ((0_f32, 200_f32), 15_f32);
+
+    /// distance in cm along the beam at angle (degrees clockwise from ahead) to the nearest of
+    /// the circles and the wall y = wall, if any
+    fn cast(angle: f32, circles: &[((f32, f32), f32)], wall: Option<(f32, f32, f32)>) -> u32 {
+        let (dx, dy) = angle.to_radians().sin_cos();
+        let mut nearest = MAX_DISTANCE as f32;
+        for &(c, r) in circles {
+            let b = dx * c.0 + dy * c.1;
+            let disc = b * b - (c.0 * c.0 + c.1 * c.1 - r * r);
+            if disc >= 0_f32 && b - disc.sqrt() > 0_f32 {
+                nearest = nearest.min(b - disc.sqrt());
+            }
+        }
+        if let Some((y, x_min, x_max)) = wall {
+            if dy > 0_f32 {
+                let t = y / dy;
+                if dx * t >= x_min && dx * t <= x_max {
+                    nearest = nearest.min(t);
+                }
+            }
+        }
+        nearest.round() as u32
+    }
+
+    fn scan(circles: &[((f32, f32), f32)],
+            wall: Option<(f32, f32, f32)>,
+            finished: Instant) -> Scan {
+        let points = (0..720)
+            .map(|i| {
+                let angle = i as f32 / 2_f32;
+                ScanPoint {
+                    angle: angle,
+                    distance: cast(angle, circles, wall),
+                    signal_strength: 200,
+                    timestamp: finished,
+                }
+            })
+            .collect();
+        Scan::new(points, finished, finished)
+    }
+
+    #[test]
+    fn segments_objects_and_joins_the_one_straight_ahead() {
+        // the post spans 0 degrees, so is at both ends of the scan
+        let wall = Some((400_f32, 100_f32, 300_f32));
+        let s = scan(&[POST, ((-150_f32, 150_f32), 20_f32)], wall, Instant::now());
+        let clusters = segment(&s);
+        assert_eq!(clusters.len(), 3, "{:?}", clusters);
+        assert!(clusters.iter().any(|c| c.iter().all(|p| (p.1 - 190_f32).abs() < 10_f32)
+                                     && c.iter().any(|p| p.0 < 0_f32)
+                                     && c.iter().any(|p| p.0 > 0_f32)));
+    }
+
+    #[test]
+    fn fits_a_line_to_a_wall() {
+        let points: Vec<_> = (0..21).map(|i| (-100_f32 + i as f32 * 10_f32, 300_f32)).collect();
+        match fit(&points) {
+            Shape::Line { start, end } => {
+                let (left, right) = if start.0 < end.0 { (start, end) } else { (end, start) };
+                assert!((left.0 + 100_f32).abs() < 0.1 && (left.1 - 300_f32).abs() < 0.1,
+                        "{:?}", left);
+                assert!((right.0 - 100_f32).abs() < 0.1 && (right.1 - 300_f32).abs() < 0.1,
+                        "{:?}", right);
+            }
+            s => panic!("expected a line, got {:?}", s),
+        }
+    }
+
+    #[test]
+    fn fits_a_circle_to_a_post() {
+        let clusters = segment(&scan(&[((50_f32, 200_f32), 15_f32)], None, Instant::now()));
+        assert_eq!(clusters.len(), 1);
+        match fit(&clusters[0]) {
+            Shape::Circle { center, radius } => {
+                assert!((center.0 - 50_f32).abs() < 2_f32 && (center.1 - 200_f32).abs() < 2_f32,
+                        "{:?}", center);
+                assert!((radius - 15_f32).abs() < 2_f32, "{}", radius);
+            }
+            s => panic!("expected a circle, got {:?}", s),
+        }
+    }
+
+    #[test]
+    fn follows_a_moving_object() {
+        let start = Instant::now();
+        let dt = Duration::from_millis(100);
+        let mut tracker = Tracker::new();
+
+        // the post crosses in front of the stationary vehicle at 1 m/s to the right
+        let post = |t: u32| ((-100_f32 + 10_f32 * t as f32, 200_f32), 15_f32);
+        tracker.update(&scan(&[post(0)], None, start), Some(0_f32), Some(0_f32));
+        assert_eq!(tracker.objects().len(), 1);
+        let id = tracker.objects()[0].id;
+        assert_eq!(tracker.objects()[0].velocity, (0_f32, 0_f32));
+
+        // after one step the velocity has moved BETA of the way towards the measured 100 cm/s
+        tracker.update(&scan(&[post(1)], None, start + dt), Some(0_f32), Some(0_f32));
+        assert_eq!(tracker.objects().len(), 1);
+        let o = &tracker.objects()[0];
+        assert_eq!(o.id, id);
+        assert_eq!(o.hits, 2);
+        assert!((o.velocity.0 - BETA * 100_f32).abs() < 5_f32, "{:?}", o);
+        assert!(o.velocity.1.abs() < 5_f32, "{:?}", o);
+
+        for t in 2..20 {
+            tracker.update(&scan(&[post(t)], None, start + dt * t), Some(0_f32), Some(0_f32));
+        }
+        let o = &tracker.objects()[0];
+        assert_eq!(o.id, id);
+        assert!((o.velocity.0 - 100_f32).abs() < 10_f32, "{:?}", o);
+        assert!(o.velocity.1.abs() < 10_f32, "{:?}", o);
+        assert_eq!(o.kind, ObjectKind::Vehicle);
+    }
+
+    #[test]
+    fn removes_the_vehicles_own_motion() {
+        let start = Instant::now();
+        let dt = Duration::from_millis(100);
+        let mut tracker = Tracker::new();
+
+        // driving at 1 m/s towards a post, which gets 10cm nearer each scan
+        for t in 0..10 {
+            let post = ((0_f32, 300_f32 - 10_f32 * t as f32), 15_f32);
+            tracker.update(&scan(&[post], None, start + dt * t), Some(90_f32), Some(1_f32));
+        }
+        assert_eq!(tracker.objects().len(), 1);
+        let o = &tracker.objects()[0];
+        assert!(o.speed() < 10_f32, "{:?}", o);
+        assert_eq!(o.kind, ObjectKind::Barrel);
+    }
+
+    #[test]
+    fn drops_objects_that_disappear() {
+        let start = Instant::now();
+        let dt = Duration::from_millis(100);
+        let mut tracker = Tracker::new();
+        tracker.update(&scan(&[POST], None, start), None, None);
+        for t in 1..(MAX_MISSED_SCANS + 1) {
+            tracker.update(&scan(&[], None, start + dt * t), None, None);
+            assert_eq!(tracker.objects()[0].missed, t);
+        }
+        tracker.update(&scan(&[], None, start + dt * (MAX_MISSED_SCANS + 1)), None, None);
+        assert!(tracker.objects().is_empty());
+    }
+}
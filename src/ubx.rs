@@ -0,0 +1,416 @@
+use chrono::{NaiveDate, NaiveTime};
+
+use super::gps::{FixType, GpsFix};
+
+const SYNC_1: u8 = 0xB5;
+const SYNC_2: u8 = 0x62;
+
+pub const CLASS_NAV: u8 = 0x01;
+pub const CLASS_ACK: u8 = 0x05;
+pub const CLASS_CFG: u8 = 0x06;
+pub const CLASS_NMEA: u8 = 0xF0;
+
+pub const NAV_DOP: u8 = 0x04;
+pub const NAV_PVT: u8 = 0x07;
+pub const ACK_NAK: u8 = 0x00;
+pub const ACK_ACK: u8 = 0x01;
+pub const CFG_MSG: u8 = 0x01;
+pub const CFG_RATE: u8 = 0x08;
+pub const CFG_NAV5: u8 = 0x24;
+
+/// the largest payload we expect to receive, used to resync quickly after garbage
+const MAX_PAYLOAD: usize = 1024;
+
+/// the receiver's navigation model, which constrains the motion it expects
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum DynamicModel {
+    Portable = 0,
+    Stationary = 2,
+    Pedestrian = 3,
+    Automotive = 4,
+    Sea = 5,
+    Airborne1g = 6,
+    Airborne2g = 7,
+    Airborne4g = 8,
+}
+
+impl DynamicModel {
+    pub fn parse(s: &str) -> Result<DynamicModel, String> {
+        match s {
+            "portable" => Ok(DynamicModel::Portable),
+            "stationary" => Ok(DynamicModel::Stationary),
+            "pedestrian" => Ok(DynamicModel::Pedestrian),
+            "automotive" => Ok(DynamicModel::Automotive),
+            "sea" => Ok(DynamicModel::Sea),
+            "airborne1g" => Ok(DynamicModel::Airborne1g),
+            "airborne2g" => Ok(DynamicModel::Airborne2g),
+            "airborne4g" => Ok(DynamicModel::Airborne4g),
+            _ => Err(format!("Unknown dynamic model: {}", s)),
+        }
+    }
+}
+
+/// a single UBX message
+#[derive(Debug, Clone, PartialEq)]
+pub struct Frame {
+    pub class: u8,
+    pub id: u8,
+    pub payload: Vec<u8>,
+}
+
+impl Frame {
+    pub fn new(class: u8, id: u8, payload: Vec<u8>) -> Self {
+        Frame {
+            class: class,
+            id: id,
+            payload: payload,
+        }
+    }
+
+    /// encode the frame including the sync bytes and checksum, ready to send to the receiver
+    pub fn encode(&self) -> Vec<u8> {
+        let len = self.payload.len();
+        let mut b = Vec::with_capacity(len + 8);
+        b.push(SYNC_1);
+        b.push(SYNC_2);
+        b.push(self.class);
+        b.push(self.id);
+        b.push((len & 0xFF) as u8);
+        b.push((len >> 8) as u8);
+        b.extend_from_slice(&self.payload);
+        let (ck_a, ck_b) = checksum(&b[2..]);
+        b.push(ck_a);
+        b.push(ck_b);
+        b
+    }
+
+    /// If this is an ACK-ACK or ACK-NAK for the message that was sent, whether the receiver
+    /// accepted it
+    pub fn acknowledges(&self, sent: &Frame) -> Option<bool> {
+        if self.class != CLASS_ACK || self.payload.len() < 2
+            || self.payload[0] != sent.class || self.payload[1] != sent.id {
+            return None;
+        }
+        match self.id {
+            ACK_ACK => Some(true),
+            ACK_NAK => Some(false),
+            _ => None,
+        }
+    }
+}
+
+/// CFG-RATE: set the measurement rate
+pub fn cfg_rate(rate_hz: u32) -> Frame {
+    let period_ms = (1000 / rate_hz) as u16;
+    let mut p = vec![];
+    push_u16(&mut p, period_ms);
+    push_u16(&mut p, 1); // one navigation solution per measurement
+    push_u16(&mut p, 1); // align measurements to GPS time
+    Frame::new(CLASS_CFG, CFG_RATE, p)
+}
+
+/// CFG-NAV5: set the dynamic model, leaving the other navigation settings unchanged
+pub fn cfg_nav5(model: DynamicModel) -> Frame {
+    let mut p = vec![0_u8; 36];
+    p[0] = 0x01; // mask: only apply the dynamic model
+    p[2] = model as u8;
+    Frame::new(CLASS_CFG, CFG_NAV5, p)
+}
+
+/// CFG-MSG: set how often a message is sent on the current port (0 disables it)
+pub fn cfg_msg(class: u8, id: u8, rate: u8) -> Frame {
+    Frame::new(CLASS_CFG, CFG_MSG, vec![class, id, rate])
+}
+
+/// Splits a stream of bytes into UBX frames, skipping anything else (such as NMEA sentences)
+pub struct FrameReader {
+    buf: Vec<u8>,
+}
+
+impl FrameReader {
+    pub fn new() -> Self {
+        FrameReader { buf: vec![] }
+    }
+
+    /// Add a byte to the stream. Returns a frame once a complete one has been read, or an
+    /// error if the frame was corrupt.
+    pub fn push(&mut self, b: u8) -> Option<Result<Frame, String>> {
+        match self.buf.len() {
+            0 => {
+                if b == SYNC_1 {
+                    self.buf.push(b);
+                }
+                None
+            }
+            1 => {
+                if b == SYNC_2 {
+                    self.buf.push(b);
+                } else {
+                    self.buf.clear();
+                    if b == SYNC_1 {
+                        self.buf.push(b);
+                    }
+                }
+                None
+            }
+            n => {
+                self.buf.push(b);
+                if n + 1 < 6 {
+                    return None;
+                }
+                let len = self.buf[4] as usize | (self.buf[5] as usize) << 8;
+                if len > MAX_PAYLOAD {
+                    self.buf.clear();
+                    return Some(Err(format!("UBX payload too long ({} bytes)", len)));
+                }
+                if self.buf.len() < len + 8 {
+                    return None;
+                }
+                let (ck_a, ck_b) = checksum(&self.buf[2..len + 6]);
+                let result = if ck_a == self.buf[len + 6] && ck_b == self.buf[len + 7] {
+                    Ok(Frame::new(self.buf[2], self.buf[3], self.buf[6..len + 6].to_vec()))
+                } else {
+                    Err(format!("UBX checksum mismatch for message {:02X}/{:02X}",
+                                self.buf[2], self.buf[3]))
+                };
+                self.buf.clear();
+                Some(result)
+            }
+        }
+    }
+}
+
+/// Combines UBX navigation messages into a single fix
+pub struct UbxParser {
+    hdop: Option<f32>,
+}
+
+impl UbxParser {
+    pub fn new() -> Self {
+        UbxParser { hdop: None }
+    }
+
+    /// Process a frame. Returns the updated fix if the frame reported a valid position.
+    pub fn parse_frame(&mut self, frame: &Frame) -> Result<Option<GpsFix>, String> {
+        match (frame.class, frame.id) {
+            (CLASS_NAV, NAV_PVT) => self.parse_nav_pvt(&frame.payload),
+            (CLASS_NAV, NAV_DOP) => {
+                if frame.payload.len() < 18 {
+                    return Err(format!("NAV-DOP too short ({} bytes)", frame.payload.len()));
+                }
+                self.hdop = Some(u16_at(&frame.payload, 12) as f32 * 0.01);
+                Ok(None)
+            }
+            (CLASS_ACK, ACK_ACK) => Ok(None),
+            (CLASS_ACK, ACK_NAK) => {
+                if frame.payload.len() >= 2 {
+                    println!("GPS rejected UBX message {:02X}/{:02X}",
+                             frame.payload[0], frame.payload[1]);
+                }
+                Ok(None)
+            }
+            _ => Ok(None),
+        }
+    }
+
+    fn parse_nav_pvt(&self, p: &[u8]) -> Result<Option<GpsFix>, String> {
+        if p.len() < 92 {
+            return Err(format!("NAV-PVT too short ({} bytes)", p.len()));
+        }
+
+        let gnss_fix_ok = p[21] & 0x01 != 0;
+        let fix_type = match p[20] {
+            2 if gnss_fix_ok => FixType::Fix2D,
+            3 | 4 if gnss_fix_ok => FixType::Fix3D,
+            _ => FixType::NoFix,
+        };
+        if fix_type == FixType::NoFix {
+            return Ok(None);
+        }
+
+        let lon = i32_at(p, 24) as f64 * 1e-7;
+        let lat = i32_at(p, 28) as f64 * 1e-7;
+
+        let mut fix = GpsFix::new(lat, lon);
+        fix.fix_type = fix_type;
+
+        let valid = p[11];
+        if valid & 0x01 != 0 {
+            fix.date = NaiveDate::from_ymd_opt(u16_at(p, 4) as i32, p[6] as u32, p[7] as u32);
+        }
+        if valid & 0x02 != 0 {
+            let nano = i32_at(p, 16);
+            let milli = if nano > 0 { nano as u32 / 1_000_000 } else { 0 };
+            fix.time = NaiveTime::from_hms_milli_opt(p[8] as u32, p[9] as u32, p[10] as u32, milli);
+        }
+
+        fix.satellites = p[23];
+        fix.altitude = Some(i32_at(p, 36) as f32 / 1000_f32);
+        fix.speed = Some(i32_at(p, 60) as f32 / 1000_f32);
+        fix.course = Some(i32_at(p, 64) as f32 * 1e-5);
+        fix.hdop = self.hdop;
+
+        Ok(Some(fix))
+    }
+}
+
+/// 8-bit Fletcher checksum over the class, id, length and payload
+fn checksum(b: &[u8]) -> (u8, u8) {
+    let mut ck_a = 0_u8;
+    let mut ck_b = 0_u8;
+    for &x in b {
+        ck_a = ck_a.wrapping_add(x);
+        ck_b = ck_b.wrapping_add(ck_a);
+    }
+    (ck_a, ck_b)
+}
+
+fn push_u16(b: &mut Vec<u8>, n: u16) {
+    b.push((n & 0xFF) as u8);
+    b.push((n >> 8) as u8);
+}
+
+fn u16_at(b: &[u8], i: usize) -> u16 {
+    b[i] as u16 | (b[i + 1] as u16) << 8
+}
+
+fn i32_at(b: &[u8], i: usize) -> i32 {
+    (b[i] as u32 | (b[i + 1] as u32) << 8 | (b[i + 2] as u32) << 16 | (b[i + 3] as u32) << 24) as i32
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn read_all(reader: &mut FrameReader, bytes: &[u8]) -> Vec<Result<Frame, String>> {
+        bytes.iter().filter_map(|&b| reader.push(b)).collect()
+    }
+
+    #[test]
+    fn encodes_cfg_messages() {
+        assert_eq!(cfg_rate(5).encode(),
+                   vec![0xB5, 0x62, 0x06, 0x08, 0x06, 0x00, 0xC8, 0x00, 0x01, 0x00, 0x01, 0x00, 0xDE, 0x6A]);
+        assert_eq!(cfg_rate(10).encode(),
+                   vec![0xB5, 0x62, 0x06, 0x08, 0x06, 0x00, 0x64, 0x00, 0x01, 0x00, 0x01, 0x00, 0x7A, 0x12]);
+        assert_eq!(cfg_msg(CLASS_NMEA, 0x00, 0).encode(),
+                   vec![0xB5, 0x62, 0x06, 0x01, 0x03, 0x00, 0xF0, 0x00, 0x00, 0xFA, 0x0F]);
+        assert_eq!(cfg_msg(CLASS_NAV, NAV_PVT, 1).encode(),
+                   vec![0xB5, 0x62, 0x06, 0x01, 0x03, 0x00, 0x01, 0x07, 0x01, 0x13, 0x51]);
+
+        let nav5 = cfg_nav5(DynamicModel::Automotive).encode();
+        assert_eq!(nav5.len(), 44);
+        assert_eq!(&nav5[..9], &[0xB5, 0x62, 0x06, 0x24, 0x24, 0x00, 0x01, 0x00, 0x04][..]);
+        assert_eq!(&nav5[42..], &[0x53, 0x70][..]);
+    }
+
+    #[test]
+    fn reads_frames_after_garbage() {
+        let mut reader = FrameReader::new();
+        // the tail of an NMEA sentence, a stray sync byte and a repeated one before the frame
+        let mut bytes = b"$GPGGA,,,*7A\r\n".to_vec();
+        bytes.extend_from_slice(&[0xB5, 0x00, 0xB5, 0xB5, 0x62, 0x05, 0x01, 0x02, 0x00, 0x06, 0x08, 0x16, 0x3F]);
+        let frames = read_all(&mut reader, &bytes);
+        assert_eq!(frames, vec![Ok(Frame::new(CLASS_ACK, ACK_ACK, vec![CLASS_CFG, CFG_RATE]))]);
+    }
+
+    #[test]
+    fn reads_a_frame_split_across_reads() {
+        let mut reader = FrameReader::new();
+        let bytes = cfg_rate(5).encode();
+        assert!(read_all(&mut reader, &bytes[..3]).is_empty());
+        assert!(read_all(&mut reader, &bytes[3..9]).is_empty());
+        assert_eq!(read_all(&mut reader, &bytes[9..]), vec![Ok(cfg_rate(5))]);
+    }
+
+    #[test]
+    fn rejects_corrupt_frames() {
+        let mut reader = FrameReader::new();
+        let mut bytes = cfg_rate(5).encode();
+        bytes[7] ^= 0x01;
+        let frames = read_all(&mut reader, &bytes);
+        assert_eq!(frames.len(), 1);
+        assert!(frames[0].is_err());
+
+        // a length that is too long is rejected straight away rather than waiting for it
+        let frames = read_all(&mut reader, &[0xB5, 0x62, 0x01, 0x07, 0xFF, 0xFF]);
+        assert_eq!(frames.len(), 1);
+        assert!(frames[0].is_err());
+
+        // and the reader recovers
+        assert_eq!(read_all(&mut reader, &cfg_rate(10).encode()), vec![Ok(cfg_rate(10))]);
+    }
+
+    #[test]
+    fn matches_acks_to_the_message_sent() {
+        let sent = cfg_rate(5);
+        assert_eq!(Frame::new(CLASS_ACK, ACK_ACK, vec![CLASS_CFG, CFG_RATE]).acknowledges(&sent), Some(true));
+        assert_eq!(Frame::new(CLASS_ACK, ACK_NAK, vec![CLASS_CFG, CFG_RATE]).acknowledges(&sent), Some(false));
+        assert_eq!(Frame::new(CLASS_ACK, ACK_ACK, vec![CLASS_CFG, CFG_MSG]).acknowledges(&sent), None);
+        assert_eq!(Frame::new(CLASS_NAV, NAV_DOP, vec![CLASS_CFG, CFG_RATE]).acknowledges(&sent), None);
+    }
+
+    fn push_i32(b: &mut [u8], i: usize, n: i32) {
+        for j in 0..4 {
+            b[i + j] = (n >> (8 * j)) as u8;
+        }
+    }
+
+    /// a NAV-PVT payload for a 3D fix on 2017-04-22 at 18:42:08.500
+    fn nav_pvt(fix_type: u8, flags: u8) -> Vec<u8> {
+        let mut p = vec![0_u8; 92];
+        p[4] = 0xE1; // 2017
+        p[5] = 0x07;
+        p[6] = 4;
+        p[7] = 22;
+        p[8] = 18;
+        p[9] = 42;
+        p[10] = 8;
+        p[11] = 0x03; // valid date and time
+        push_i32(&mut p, 16, 500_000_000);
+        p[20] = fix_type;
+        p[21] = flags;
+        p[23] = 9;
+        push_i32(&mut p, 24, -1050605120);
+        push_i32(&mut p, 28, 399503800);
+        push_i32(&mut p, 36, 1650200);
+        push_i32(&mut p, 60, 1250);
+        push_i32(&mut p, 64, 27150000);
+        p
+    }
+
+    #[test]
+    fn decodes_nav_pvt_and_nav_dop() {
+        let mut dop = vec![0_u8; 18];
+        dop[12] = 0x5A; // hDOP 0.9
+        let mut bytes = Frame::new(CLASS_NAV, NAV_DOP, dop).encode();
+        bytes.extend(Frame::new(CLASS_NAV, NAV_PVT, nav_pvt(3, 0x01)).encode());
+
+        let mut reader = FrameReader::new();
+        let mut parser = UbxParser::new();
+        let fixes: Vec<GpsFix> = read_all(&mut reader, &bytes).into_iter()
+            .filter_map(|f| parser.parse_frame(&f.unwrap()).unwrap())
+            .collect();
+        assert_eq!(fixes.len(), 1);
+        let fix = &fixes[0];
+        assert_eq!(fix.fix_type, FixType::Fix3D);
+        assert!((fix.lat - 39.95038).abs() < 1e-9);
+        assert!((fix.lon - -105.060512).abs() < 1e-9);
+        assert!((fix.hdop.unwrap() - 0.9).abs() < 1e-6);
+        assert_eq!(fix.satellites, 9);
+        assert_eq!(fix.altitude, Some(1650.2));
+        assert_eq!(fix.speed, Some(1.25));
+        assert!((fix.course.unwrap() - 271.5).abs() < 1e-4);
+        assert_eq!(fix.date, NaiveDate::from_ymd_opt(2017, 4, 22));
+        assert_eq!(fix.time, NaiveTime::from_hms_milli_opt(18, 42, 8, 500));
+    }
+
+    #[test]
+    fn ignores_nav_pvt_without_a_fix() {
+        let mut parser = UbxParser::new();
+        // 3D fix type but the receiver doesn't trust it
+        assert!(parser.parse_frame(&Frame::new(CLASS_NAV, NAV_PVT, nav_pvt(3, 0x00))).unwrap().is_none());
+        // time only
+        assert!(parser.parse_frame(&Frame::new(CLASS_NAV, NAV_PVT, nav_pvt(5, 0x01))).unwrap().is_none());
+        assert!(parser.parse_frame(&Frame::new(CLASS_NAV, NAV_PVT, vec![0_u8; 40])).is_err());
+    }
+}
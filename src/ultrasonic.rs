@@ -0,0 +1,225 @@
+extern crate spidev;
+
+use self::spidev::{Spidev, SpidevOptions, SpidevTransfer, SPI_MODE_0};
+
+use super::hardware::UltrasonicSensors;
+use super::perception::ULTRASONIC_MAX_RANGE;
+
+use std::collections::VecDeque;
+use std::io;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+// Octasonic commands. The command goes in the high nibble of the first byte and its
+// parameter (if any) in the low nibble. The response is returned during the next transfer.
+const CMD_GET_PROTOCOL_VERSION: u8 = 0x01;
+const CMD_SET_SENSOR_COUNT: u8 = 0x02;
+const CMD_GET_SENSOR_READING: u8 = 0x04;
+
+/// number of HC-SR04 sensors connected to the Octasonic
+pub const SENSOR_COUNT: usize = 5;
+
+// which Octasonic channel each sensor is connected to
+const SIDE_LEFT: usize = 0;
+const FRONT_LEFT: usize = 1;
+const FRONT: usize = 2;
+const FRONT_RIGHT: usize = 3;
+const SIDE_RIGHT: usize = 4;
+
+/// the readings are discarded if the sensors haven't been polled for this long
+const STALE_TIMEOUT_MS: u64 = 500;
+
+/// Distances in cm to the nearest object seen by each sensor
+#[derive(Debug, Clone)]
+pub struct UltrasonicRanges {
+    pub side_left: u32,
+    pub front_left: u32,
+    pub front: u32,
+    pub front_right: u32,
+    pub side_right: u32,
+}
+
+/// Octasonic breakout board, which triggers up to 8 HC-SR04 sensors in turn and reports the
+/// latest distance for each over SPI
+pub struct Octasonic {
+    spi: Spidev,
+}
+
+impl Octasonic {
+    pub fn new(f: &str, sensor_count: usize) -> io::Result<Self> {
+        let mut spi = Spidev::open(f)?;
+        let options = SpidevOptions::new()
+            .bits_per_word(8)
+            .max_speed_hz(20_000)
+            .mode(SPI_MODE_0)
+            .build();
+        spi.configure(&options)?;
+        let octasonic = Octasonic { spi: spi };
+        octasonic.send(CMD_SET_SENSOR_COUNT, sensor_count as u8)?;
+        Ok(octasonic)
+    }
+
+    pub fn protocol_version(&self) -> io::Result<u8> {
+        self.send(CMD_GET_PROTOCOL_VERSION, 0)?;
+        self.send(0, 0)
+    }
+
+    /// the latest distance in cm measured by a sensor
+    pub fn get_sensor_reading(&self, n: usize) -> io::Result<u8> {
+        self.send(CMD_GET_SENSOR_READING, n as u8)?;
+        self.send(0, 0)
+    }
+
+    fn send(&self, cmd: u8, param: u8) -> io::Result<u8> {
+        // a write transfer also reads back a byte for each byte written
+        let mut transfer = SpidevTransfer::write(&[cmd << 4 | param & 0x0F]);
+        self.spi.transfer(&mut transfer)?;
+        match transfer.rx_buf {
+            Some(ref rx) if rx.len() == 1 => Ok(rx[0]),
+            _ => Err(io::Error::other("no response from Octasonic")),
+        }
+    }
+}
+
+struct Shared {
+    ranges: Option<UltrasonicRanges>,
+    updated: Instant,
+}
+
+/// Polls the Octasonic on a background thread and median filters the readings
+pub struct Ultrasonic {
+    filename: &'static str,
+    sample_count: usize,
+    shared: Arc<Mutex<Shared>>,
+}
+
+impl Ultrasonic {
+    /// each distance is the median of the last `sample_count` readings from that sensor
+    pub fn new(f: &'static str, sample_count: usize) -> Self {
+        Ultrasonic {
+            filename: f,
+            sample_count: if sample_count > 0 { sample_count } else { 1 },
+            shared: Arc::new(Mutex::new(Shared {
+                ranges: None,
+                updated: Instant::now(),
+            })),
+        }
+    }
+
+    pub fn start_thread(&self) {
+
+        let f = self.filename;
+        let sample_count = self.sample_count;
+        let shared = self.shared.clone();
+
+        thread::spawn(move || {
+
+            loop {
+                let octasonic = match Octasonic::new(f, SENSOR_COUNT) {
+                    Ok(o) => o,
+                    Err(e) => {
+                        println!("Failed to open Octasonic: {}", e);
+                        thread::sleep(Duration::from_millis(1000));
+                        continue;
+                    }
+                };
+
+                match octasonic.protocol_version() {
+                    Ok(v) => println!("Octasonic protocol version {}", v),
+                    Err(e) => println!("Failed to read Octasonic protocol version: {}", e),
+                }
+
+                let mut samples: Vec<VecDeque<u32>> = vec![VecDeque::new(); SENSOR_COUNT];
+
+                'poll: loop {
+                    for i in 0..SENSOR_COUNT {
+                        match octasonic.get_sensor_reading(i) {
+                            Ok(d) => {
+                                samples[i].push_back(distance(d));
+                                while samples[i].len() > sample_count {
+                                    samples[i].pop_front();
+                                }
+                            }
+                            Err(e) => {
+                                println!("Failed to read ultrasonic sensor {}: {}", i, e);
+                                break 'poll;
+                            }
+                        }
+                    }
+
+                    let d: Vec<u32> = samples.iter().map(median).collect();
+                    {
+                        let mut s = shared.lock().unwrap();
+                        s.ranges = Some(UltrasonicRanges {
+                            side_left: d[SIDE_LEFT],
+                            front_left: d[FRONT_LEFT],
+                            front: d[FRONT],
+                            front_right: d[FRONT_RIGHT],
+                            side_right: d[SIDE_RIGHT],
+                        });
+                        s.updated = Instant::now();
+                    }
+
+                    thread::sleep(Duration::from_millis(10));
+                }
+
+                thread::sleep(Duration::from_millis(1000));
+            }
+        });
+    }
+
+    /// the latest median filtered distances, or None if the sensors aren't responding
+    pub fn get(&self) -> Option<UltrasonicRanges> {
+        let s = self.shared.lock().unwrap();
+        if s.updated.elapsed() < Duration::from_millis(STALE_TIMEOUT_MS) {
+            s.ranges.clone()
+        } else {
+            None
+        }
+    }
+}
+
+/// The distance in cm for a reading from the Octasonic, which is 0 if there was no echo and
+/// 255 if the distance doesn't fit in a byte. Either way nothing is within range, so they are
+/// both treated as the furthest reading by the median filter.
+fn distance(reading: u8) -> u32 {
+    if reading == 0 { ULTRASONIC_MAX_RANGE } else { (reading as u32).min(ULTRASONIC_MAX_RANGE) }
+}
+
+fn median(samples: &VecDeque<u32>) -> u32 {
+    let mut v: Vec<u32> = samples.iter().cloned().collect();
+    v.sort();
+    v[v.len() / 2]
+}
+
+impl UltrasonicSensors for Ultrasonic {
+    fn ranges(&self) -> Option<UltrasonicRanges> {
+        self.get()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn filter(readings: &[u8]) -> u32 {
+        median(&readings.iter().map(|&r| distance(r)).collect())
+    }
+
+    #[test]
+    fn takes_the_median_reading() {
+        assert_eq!(filter(&[120]), 120);
+        assert_eq!(filter(&[120, 20, 118]), 118);
+        assert_eq!(filter(&[100, 101, 102, 103]), 102);
+    }
+
+    #[test]
+    fn treats_no_echo_as_out_of_range() {
+        // a missed echo doesn't drag the distance down to zero
+        assert_eq!(filter(&[0, 150, 152, 0, 151]), 152);
+        assert_eq!(filter(&[0, 0, 150]), ULTRASONIC_MAX_RANGE);
+        assert_eq!(filter(&[255, 0, 255, 80]), ULTRASONIC_MAX_RANGE);
+        assert_eq!(filter(&[255, 80, 81]), 81);
+    }
+}
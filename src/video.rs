@@ -44,7 +44,7 @@ impl Video {
         let f = CString::new(filename).unwrap();
         match unsafe { video_init(self.camera, f.as_ptr()) } {
             0 => Ok(()),
-            s @ _ => Err(s),
+            s => Err(s),
         }
     }
 
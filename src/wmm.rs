@@ -0,0 +1,224 @@
+use chrono::{Datelike, NaiveDate};
+
+use std::f64::consts::PI;
+
+/// epoch of the coefficients below
+const EPOCH: f64 = 2025.0;
+
+/// the model is only valid for this many years after the epoch
+const VALID_YEARS: f64 = 5.0;
+
+/// maximum degree of the model
+const MAX_DEGREE: usize = 12;
+
+/// geomagnetic reference radius in km
+const REFERENCE_RADIUS: f64 = 6371.2;
+
+/// WGS84 semi-major axis in km
+const WGS84_A: f64 = 6378.137;
+
+/// WGS84 flattening
+const WGS84_F: f64 = 1_f64 / 298.257223563;
+
+/// World Magnetic Model 2025 coefficients (n, m, g, h in nT, and their secular variation
+/// in nT/year), valid for 2025.0 to 2030.0
+const COEFFICIENTS: [(usize, usize, f64, f64, f64, f64); 90] = [
+    ( 1,  0,  -29351.8,      0.0,   12.0,    0.0),
+    ( 1,  1,   -1410.8,   4545.4,    9.7,  -21.5),
+    ( 2,  0,   -2556.6,      0.0,  -11.6,    0.0),
+    ( 2,  1,    2951.1,  -3133.6,   -5.2,  -27.7),
+    ( 2,  2,    1649.3,   -815.1,   -8.0,  -12.1),
+    ( 3,  0,    1361.0,      0.0,   -1.3,    0.0),
+    ( 3,  1,   -2404.1,    -56.6,   -4.2,    4.0),
+    ( 3,  2,    1243.8,    237.5,    0.4,   -0.3),
+    ( 3,  3,     453.6,   -549.5,  -15.6,   -4.1),
+    ( 4,  0,     895.0,      0.0,   -1.6,    0.0),
+    ( 4,  1,     799.5,    278.6,   -2.4,   -1.1),
+    ( 4,  2,      55.7,   -133.9,   -6.0,    4.1),
+    ( 4,  3,    -281.1,    212.0,    5.6,    1.6),
+    ( 4,  4,      12.1,   -375.6,   -7.0,   -4.4),
+    ( 5,  0,    -233.2,      0.0,    0.6,    0.0),
+    ( 5,  1,     368.9,     45.4,    1.4,   -0.5),
+    ( 5,  2,     187.2,    220.2,    0.0,    2.2),
+    ( 5,  3,    -138.7,   -122.9,    0.6,    0.4),
+    ( 5,  4,    -142.0,     43.0,    2.2,    1.7),
+    ( 5,  5,      20.9,    106.1,    0.9,    1.9),
+    ( 6,  0,      64.4,      0.0,   -0.2,    0.0),
+    ( 6,  1,      63.8,    -18.4,   -0.4,    0.3),
+    ( 6,  2,      76.9,     16.8,    0.9,   -1.6),
+    ( 6,  3,    -115.7,     48.8,    1.2,   -0.4),
+    ( 6,  4,     -40.9,    -59.8,   -0.9,    0.9),
+    ( 6,  5,      14.9,     10.9,    0.3,    0.7),
+    ( 6,  6,     -60.7,     72.7,    0.9,    0.9),
+    ( 7,  0,      79.5,      0.0,   -0.0,    0.0),
+    ( 7,  1,     -77.0,    -48.9,   -0.1,    0.6),
+    ( 7,  2,      -8.8,    -14.4,   -0.1,    0.5),
+    ( 7,  3,      59.3,     -1.0,    0.5,   -0.8),
+    ( 7,  4,      15.8,     23.4,   -0.1,    0.0),
+    ( 7,  5,       2.5,     -7.4,   -0.8,   -1.0),
+    ( 7,  6,     -11.1,    -25.1,   -0.8,    0.6),
+    ( 7,  7,      14.2,     -2.3,    0.8,   -0.2),
+    ( 8,  0,      23.2,      0.0,   -0.1,    0.0),
+    ( 8,  1,      10.8,      7.1,    0.2,   -0.2),
+    ( 8,  2,     -17.5,    -12.6,    0.0,    0.5),
+    ( 8,  3,       2.0,     11.4,    0.5,   -0.4),
+    ( 8,  4,     -21.7,     -9.7,   -0.1,    0.4),
+    ( 8,  5,      16.9,     12.7,    0.3,   -0.5),
+    ( 8,  6,      15.0,      0.7,    0.2,   -0.6),
+    ( 8,  7,     -16.8,     -5.2,   -0.0,    0.3),
+    ( 8,  8,       0.9,      3.9,    0.2,    0.2),
+    ( 9,  0,       4.6,      0.0,   -0.0,    0.0),
+    ( 9,  1,       7.8,    -24.8,   -0.1,   -0.3),
+    ( 9,  2,       3.0,     12.2,    0.1,    0.3),
+    ( 9,  3,      -0.2,      8.3,    0.3,   -0.3),
+    ( 9,  4,      -2.5,     -3.3,   -0.3,    0.3),
+    ( 9,  5,     -13.1,     -5.2,    0.0,    0.2),
+    ( 9,  6,       2.4,      7.2,    0.3,   -0.1),
+    ( 9,  7,       8.6,     -0.6,   -0.1,   -0.2),
+    ( 9,  8,      -8.7,      0.8,    0.1,    0.4),
+    ( 9,  9,     -12.9,     10.0,   -0.1,    0.1),
+    (10,  0,      -1.3,      0.0,    0.1,    0.0),
+    (10,  1,      -6.4,      0.2,    0.0,    0.0),
+    (10,  2,       0.2,     -0.9,   -0.0,    0.1),
+    (10,  3,       2.0,      1.8,    0.0,   -0.1),
+    (10,  4,      -1.0,      4.4,   -0.1,    0.0),
+    (10,  5,      -0.6,     -7.9,   -0.1,   -0.1),
+    (10,  6,      -0.9,     -0.6,   -0.0,    0.1),
+    (10,  7,       1.5,     -4.2,   -0.1,    0.0),
+    (10,  8,       0.9,     -2.8,   -0.0,   -0.1),
+    (10,  9,      -2.7,     -1.1,   -0.1,    0.1),
+    (10, 10,      -3.9,     -9.3,   -0.0,   -0.0),
+    (11,  0,       2.9,      0.0,    0.0,    0.0),
+    (11,  1,      -1.5,      0.0,   -0.0,   -0.0),
+    (11,  2,      -2.5,      2.9,    0.0,    0.1),
+    (11,  3,       2.4,     -0.6,    0.0,   -0.0),
+    (11,  4,      -0.6,      0.2,    0.0,    0.1),
+    (11,  5,      -0.1,      0.5,   -0.1,   -0.0),
+    (11,  6,      -0.6,     -0.3,    0.0,   -0.0),
+    (11,  7,      -0.1,     -1.2,   -0.0,    0.1),
+    (11,  8,       1.1,     -1.7,   -0.1,   -0.0),
+    (11,  9,      -1.0,     -2.9,   -0.1,    0.0),
+    (11, 10,      -0.2,     -1.8,   -0.1,    0.0),
+    (11, 11,       2.6,     -2.3,   -0.1,    0.0),
+    (12,  0,      -2.0,      0.0,    0.0,    0.0),
+    (12,  1,      -0.2,     -1.3,    0.0,   -0.0),
+    (12,  2,       0.3,      0.7,   -0.0,    0.0),
+    (12,  3,       1.2,      1.0,   -0.0,   -0.1),
+    (12,  4,      -1.3,     -1.4,   -0.0,    0.1),
+    (12,  5,       0.6,     -0.0,   -0.0,   -0.0),
+    (12,  6,       0.6,      0.6,    0.1,   -0.0),
+    (12,  7,       0.5,     -0.1,   -0.0,   -0.0),
+    (12,  8,      -0.1,      0.8,    0.0,    0.0),
+    (12,  9,      -0.4,      0.1,    0.0,   -0.0),
+    (12, 10,      -0.2,     -1.0,   -0.1,   -0.0),
+    (12, 11,      -1.3,      0.1,   -0.0,    0.0),
+    (12, 12,      -0.7,      0.2,   -0.1,   -0.1),
+];
+
+/// Magnetic declination in degrees (positive east) at the given geodetic position, altitude
+/// above sea level in metres and decimal year, e.g. 2025.5
+pub fn declination(lat: f64, lon: f64, altitude: f64, year: f64) -> f64 {
+    let dt = year - EPOCH;
+
+    // geodetic to geocentric spherical coordinates
+    let phi = lat * PI / 180_f64;
+    let lambda = lon * PI / 180_f64;
+    let alt = altitude / 1000_f64;
+    let e2 = WGS84_F * (2_f64 - WGS84_F);
+    let rc = WGS84_A / (1_f64 - e2 * phi.sin() * phi.sin()).sqrt();
+    let p = (rc + alt) * phi.cos();
+    let q = (rc * (1_f64 - e2) + alt) * phi.sin();
+    let r = (p * p + q * q).sqrt();
+    let phi_c = (q / r).asin();
+
+    // Schmidt semi-normalized associated Legendre functions and their derivatives with
+    // respect to the geocentric latitude
+    let (s, c) = (phi_c.sin(), phi_c.cos());
+    let mut pnm = [[0_f64; MAX_DEGREE + 1]; MAX_DEGREE + 1];
+    let mut dpnm = [[0_f64; MAX_DEGREE + 1]; MAX_DEGREE + 1];
+    pnm[0][0] = 1_f64;
+    for n in 1..MAX_DEGREE + 1 {
+        for m in 0..n + 1 {
+            if n == m {
+                let k = if n == 1 { 1_f64 } else { ((2 * n - 1) as f64 / (2 * n) as f64).sqrt() };
+                pnm[n][m] = k * c * pnm[n - 1][m - 1];
+                dpnm[n][m] = k * (c * dpnm[n - 1][m - 1] - s * pnm[n - 1][m - 1]);
+            } else {
+                let nn = (n * n - m * m) as f64;
+                let k1 = (2 * n - 1) as f64 / nn.sqrt();
+                pnm[n][m] = k1 * s * pnm[n - 1][m];
+                dpnm[n][m] = k1 * (s * dpnm[n - 1][m] + c * pnm[n - 1][m]);
+                if n >= 2 {
+                    let k2 = (((n - 1) * (n - 1) - m * m) as f64 / nn).sqrt();
+                    pnm[n][m] -= k2 * pnm[n - 2][m];
+                    dpnm[n][m] -= k2 * dpnm[n - 2][m];
+                }
+            }
+        }
+    }
+
+    // field components in the geocentric frame: x north, y east, z down
+    let (mut x, mut y, mut z) = (0_f64, 0_f64, 0_f64);
+    for &(n, m, g, h, g_dot, h_dot) in COEFFICIENTS.iter() {
+        let g = g + dt * g_dot;
+        let h = h + dt * h_dot;
+        let ratio = (REFERENCE_RADIUS / r).powi(n as i32 + 2);
+        let (cos_ml, sin_ml) = ((m as f64 * lambda).cos(), (m as f64 * lambda).sin());
+        x -= ratio * (g * cos_ml + h * sin_ml) * dpnm[n][m];
+        y += ratio * m as f64 * (g * sin_ml - h * cos_ml) * pnm[n][m] / c;
+        z -= ratio * (n + 1) as f64 * (g * cos_ml + h * sin_ml) * pnm[n][m];
+    }
+
+    // rotate the north component back to the geodetic frame
+    let psi = phi_c - phi;
+    let x = x * psi.cos() - z * psi.sin();
+
+    y.atan2(x) * 180_f64 / PI
+}
+
+/// is the model valid for the decimal year?
+pub fn is_valid(year: f64) -> bool {
+    (EPOCH..EPOCH + VALID_YEARS).contains(&year)
+}
+
+/// the first and last decimal years that the model is valid for
+pub fn valid_years() -> (f64, f64) {
+    (EPOCH, EPOCH + VALID_YEARS)
+}
+
+/// the date as a decimal year, e.g. 2020-07-02 is roughly 2020.5
+pub fn decimal_year(date: NaiveDate) -> f64 {
+    date.year() as f64 + date.ordinal0() as f64 / 365.25
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// WMM2025 test values (decimal year, altitude in km, lat, lon, declination)
+    const TEST_VALUES: [(f64, f64, f64, f64, f64); 6] = [
+        (2025.0,   0_f64,  80_f64,   0_f64,  1.47),
+        (2025.0,   0_f64,   0_f64, 120_f64, -0.16),
+        (2025.0,   0_f64, -80_f64, 240_f64, 68.77),
+        (2027.5, 100_f64,  80_f64,   0_f64,  2.31),
+        (2027.5, 100_f64,   0_f64, 120_f64, -0.22),
+        (2027.5, 100_f64, -80_f64, 240_f64, 67.93),
+    ];
+
+    #[test]
+    fn matches_test_values() {
+        for &(year, altitude, lat, lon, expected) in TEST_VALUES.iter() {
+            let d = declination(lat, lon, altitude * 1000_f64, year);
+            assert!((d - expected).abs() < 0.01,
+                    "declination at {}, {} in {} was {}, expected {}", lat, lon, year, d, expected);
+        }
+    }
+
+    #[test]
+    fn valid_for_five_years() {
+        assert!(is_valid(2025.0));
+        assert!(is_valid(2029.9));
+        assert!(!is_valid(2024.9));
+        assert!(!is_valid(2030.0));
+    }
+}